@@ -0,0 +1,532 @@
+//! Gameplay tuning values, read from a RON file and `--set` overrides.
+//!
+//! Every field is optional in the file and falls back to its default, so a
+//! config only needs the values it changes:
+//!
+//! ```ron
+//! (
+//!     player: (speed: 90.0, bomb_capacity: 2),
+//!     bomb: (detonation_period: 1.5),
+//! )
+//! ```
+//!
+//! A single value is overridden on the command line by its dotted path, such
+//! as `--set player.speed=90` or `--set bomb.size=(38.0,34.0)`.
+
+use std::fmt;
+use std::fs;
+use std::path::Path;
+use std::time::Duration;
+
+use bevy::prelude::*;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+
+use super::world::map;
+
+#[derive(Resource, Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct GameConfig {
+    // Fixed steps simulated per second
+    pub fps: f32,
+    pub player: PlayerConfig,
+    pub bomb: BombConfig,
+    pub explosion: ExplosionConfig,
+    pub powerup: PowerupConfig,
+    pub block: BlockConfig,
+    pub brick: BrickConfig,
+    pub border: BorderConfig,
+    pub disease: DiseaseConfig,
+    pub round: RoundConfig,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PlayerConfig {
+    pub speed: f32,
+    pub speed_step: f32,
+    pub max_speed: f32,
+    pub fire_range: u8,
+    pub max_fire_range: u8,
+    pub bomb_capacity: u8,
+    pub size: (f32, f32),
+    pub mass: f32,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct BombConfig {
+    pub detonation_period: f32,
+    pub size: (f32, f32),
+    pub mass: f32,
+    // Pixels per second a kicked bomb travels
+    pub slide_speed: f32,
+    // Pixels per second a punched or thrown bomb travels
+    pub flight_speed: f32,
+    // Cells a punched and a thrown bomb fly before they come down
+    pub punch_distance: u8,
+    pub throw_distance: u8,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ExplosionConfig {
+    pub period: f32,
+    pub size: (f32, f32),
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PowerupConfig {
+    pub size: (f32, f32),
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct BlockConfig {
+    // Blocks are round, so players slide off their corners
+    pub radius: f32,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct BrickConfig {
+    pub size: (f32, f32),
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct BorderConfig {
+    pub thickness: f32,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DiseaseConfig {
+    // Seconds a curse lasts
+    pub duration: f32,
+    pub molasses_speed: f32,
+    pub crack_speed: f32,
+    pub short_fuse: f32,
+    pub long_fuse: f32,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RoundConfig {
+    pub start_delay: f32,
+    pub over_delay: f32,
+    // Seconds into a round before the arena starts shrinking, 0 never shrinks it
+    pub sudden_death_after: f32,
+    // Seconds between two blocks dropping in sudden death
+    pub block_drop_period: f32,
+}
+
+impl std::default::Default for GameConfig {
+    fn default() -> Self {
+        GameConfig {
+            fps: 40.0,
+            player: PlayerConfig::default(),
+            bomb: BombConfig::default(),
+            explosion: ExplosionConfig::default(),
+            powerup: PowerupConfig::default(),
+            block: BlockConfig::default(),
+            brick: BrickConfig::default(),
+            border: BorderConfig::default(),
+            disease: DiseaseConfig::default(),
+            round: RoundConfig::default(),
+        }
+    }
+}
+
+impl std::default::Default for PlayerConfig {
+    fn default() -> Self {
+        PlayerConfig {
+            speed: 70.0,
+            speed_step: 10.0,
+            max_speed: 150.0,
+            fire_range: 2,
+            max_fire_range: 10,
+            bomb_capacity: 1,
+            size: (27.0, 27.0),
+            mass: 100.0,
+        }
+    }
+}
+
+impl std::default::Default for BombConfig {
+    fn default() -> Self {
+        BombConfig {
+            detonation_period: 2.0,
+            size: (40.0, 36.0),
+            mass: 100.0,
+            slide_speed: 200.0,
+            flight_speed: 300.0,
+            punch_distance: 3,
+            throw_distance: 3,
+        }
+    }
+}
+
+impl std::default::Default for ExplosionConfig {
+    fn default() -> Self {
+        ExplosionConfig {
+            period: 0.8,
+            size: (36.0, 32.0),
+        }
+    }
+}
+
+impl std::default::Default for PowerupConfig {
+    fn default() -> Self {
+        PowerupConfig { size: (24.0, 22.0) }
+    }
+}
+
+impl std::default::Default for BlockConfig {
+    fn default() -> Self {
+        BlockConfig { radius: 18.0 }
+    }
+}
+
+impl std::default::Default for BrickConfig {
+    fn default() -> Self {
+        BrickConfig { size: (40.0, 36.0) }
+    }
+}
+
+impl std::default::Default for BorderConfig {
+    fn default() -> Self {
+        BorderConfig { thickness: 4.0 }
+    }
+}
+
+impl std::default::Default for DiseaseConfig {
+    fn default() -> Self {
+        DiseaseConfig {
+            duration: 15.0,
+            molasses_speed: 30.0,
+            crack_speed: 250.0,
+            short_fuse: 0.8,
+            long_fuse: 4.0,
+        }
+    }
+}
+
+impl std::default::Default for RoundConfig {
+    fn default() -> Self {
+        RoundConfig {
+            start_delay: 1.5,
+            // Lets the last flames still catch the survivor, which turns the round into a draw
+            over_delay: 2.0,
+            sudden_death_after: 120.0,
+            block_drop_period: 0.15,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum ConfigError {
+    Io(std::io::Error),
+    Syntax(ron::error::SpannedError),
+    UnknownKey { key: String },
+    InvalidValue { key: String, value: String, reason: String },
+    MissingValue { setting: String },
+    OutOfRange { key: &'static str, reason: &'static str },
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::Io(err) => write!(f, "failed to read config: {}", err),
+            ConfigError::Syntax(err) => write!(f, "{}", err),
+            ConfigError::UnknownKey { key } => write!(f, "unknown setting `{}`", key),
+            ConfigError::InvalidValue { key, value, reason } => {
+                write!(f, "`{}` is not a valid value for `{}`: {}", value, key, reason)
+            }
+            ConfigError::MissingValue { setting } => {
+                write!(f, "expected `key=value`, found `{}`", setting)
+            }
+            ConfigError::OutOfRange { key, reason } => write!(f, "`{}` {}", key, reason),
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+impl From<std::io::Error> for ConfigError {
+    fn from(err: std::io::Error) -> Self {
+        ConfigError::Io(err)
+    }
+}
+
+impl From<ron::error::SpannedError> for ConfigError {
+    fn from(err: ron::error::SpannedError) -> Self {
+        ConfigError::Syntax(err)
+    }
+}
+
+pub fn load<P: AsRef<Path>>(path: P) -> Result<GameConfig, ConfigError> {
+    parse(&fs::read_to_string(path)?)
+}
+
+/// Reads a config without validating it, overrides may still fix or break
+/// its values. `GameConfig::validate` checks the result once they are applied.
+pub fn parse(source: &str) -> Result<GameConfig, ConfigError> {
+    Ok(ron::from_str(source)?)
+}
+
+impl GameConfig {
+    /// Length of one fixed step of the simulation.
+    pub fn tick_duration(&self) -> Duration {
+        Duration::from_secs_f64(1.0 / self.fps as f64)
+    }
+
+    /// Number of whole ticks closest to `secs` seconds, at least one unless
+    /// `secs` is zero, so that a short period never passes in no time.
+    pub fn ticks(&self, secs: f32) -> u64 {
+        if secs > 0.0 {
+            ((secs * self.fps).round() as u64).max(1)
+        } else {
+            0
+        }
+    }
+
+    /// Applies a `key=value` override, the value written as in a config file.
+    pub fn set(&mut self, setting: &str) -> Result<(), ConfigError> {
+        let Some((key, value)) = setting.split_once('=') else {
+            return Err(ConfigError::MissingValue {
+                setting: setting.to_string(),
+            });
+        };
+
+        let (key, value) = (key.trim(), value.trim());
+
+        match key {
+            "fps" => self.fps = parse_value(key, value)?,
+            "player.speed" => self.player.speed = parse_value(key, value)?,
+            "player.speed_step" => self.player.speed_step = parse_value(key, value)?,
+            "player.max_speed" => self.player.max_speed = parse_value(key, value)?,
+            "player.fire_range" => self.player.fire_range = parse_value(key, value)?,
+            "player.max_fire_range" => self.player.max_fire_range = parse_value(key, value)?,
+            "player.bomb_capacity" => self.player.bomb_capacity = parse_value(key, value)?,
+            "player.size" => self.player.size = parse_value(key, value)?,
+            "player.mass" => self.player.mass = parse_value(key, value)?,
+            "bomb.detonation_period" => self.bomb.detonation_period = parse_value(key, value)?,
+            "bomb.size" => self.bomb.size = parse_value(key, value)?,
+            "bomb.mass" => self.bomb.mass = parse_value(key, value)?,
+            "bomb.slide_speed" => self.bomb.slide_speed = parse_value(key, value)?,
+            "bomb.flight_speed" => self.bomb.flight_speed = parse_value(key, value)?,
+            "bomb.punch_distance" => self.bomb.punch_distance = parse_value(key, value)?,
+            "bomb.throw_distance" => self.bomb.throw_distance = parse_value(key, value)?,
+            "explosion.period" => self.explosion.period = parse_value(key, value)?,
+            "explosion.size" => self.explosion.size = parse_value(key, value)?,
+            "powerup.size" => self.powerup.size = parse_value(key, value)?,
+            "block.radius" => self.block.radius = parse_value(key, value)?,
+            "brick.size" => self.brick.size = parse_value(key, value)?,
+            "border.thickness" => self.border.thickness = parse_value(key, value)?,
+            "disease.duration" => self.disease.duration = parse_value(key, value)?,
+            "disease.molasses_speed" => self.disease.molasses_speed = parse_value(key, value)?,
+            "disease.crack_speed" => self.disease.crack_speed = parse_value(key, value)?,
+            "disease.short_fuse" => self.disease.short_fuse = parse_value(key, value)?,
+            "disease.long_fuse" => self.disease.long_fuse = parse_value(key, value)?,
+            "round.start_delay" => self.round.start_delay = parse_value(key, value)?,
+            "round.over_delay" => self.round.over_delay = parse_value(key, value)?,
+            "round.sudden_death_after" => {
+                self.round.sudden_death_after = parse_value(key, value)?
+            }
+            "round.block_drop_period" => self.round.block_drop_period = parse_value(key, value)?,
+            _ => {
+                return Err(ConfigError::UnknownKey {
+                    key: key.to_string(),
+                })
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        let positive = [
+            ("fps", self.fps),
+            ("player.speed", self.player.speed),
+            ("player.max_speed", self.player.max_speed),
+            ("player.mass", self.player.mass),
+            ("bomb.detonation_period", self.bomb.detonation_period),
+            ("bomb.mass", self.bomb.mass),
+            ("bomb.slide_speed", self.bomb.slide_speed),
+            ("bomb.flight_speed", self.bomb.flight_speed),
+            ("explosion.period", self.explosion.period),
+            ("block.radius", self.block.radius),
+            ("border.thickness", self.border.thickness),
+            ("disease.duration", self.disease.duration),
+            ("disease.molasses_speed", self.disease.molasses_speed),
+            ("disease.crack_speed", self.disease.crack_speed),
+            ("disease.short_fuse", self.disease.short_fuse),
+            ("disease.long_fuse", self.disease.long_fuse),
+            ("round.block_drop_period", self.round.block_drop_period),
+        ];
+
+        for (key, value) in positive {
+            check(key, value.is_finite() && value > 0.0, "must be a positive number")?;
+        }
+
+        let non_negative = [
+            ("player.speed_step", self.player.speed_step),
+            ("round.start_delay", self.round.start_delay),
+            ("round.over_delay", self.round.over_delay),
+            ("round.sudden_death_after", self.round.sudden_death_after),
+        ];
+
+        for (key, value) in non_negative {
+            check(key, value.is_finite() && value >= 0.0, "must not be negative")?;
+        }
+
+        let sizes = [
+            ("player.size", self.player.size),
+            ("bomb.size", self.bomb.size),
+            ("explosion.size", self.explosion.size),
+            ("powerup.size", self.powerup.size),
+            ("brick.size", self.brick.size),
+        ];
+
+        for (key, (x, y)) in sizes {
+            check(
+                key,
+                x > 0.0 && y > 0.0 && x <= map::CELL_SIZE.x && y <= map::CELL_SIZE.y,
+                "must fit in a cell",
+            )?;
+        }
+
+        check(
+            "block.radius",
+            2.0 * self.block.radius <= map::CELL_SIZE.min_element(),
+            "must fit in a cell",
+        )?;
+
+        // A player has to squeeze between two blocks
+        check(
+            "player.size",
+            self.player.size.0 < map::CELL_SIZE.x && self.player.size.1 < map::CELL_SIZE.y,
+            "must be smaller than a cell",
+        )?;
+        check(
+            "player.max_speed",
+            self.player.max_speed >= self.player.speed,
+            "must not be below `player.speed`",
+        )?;
+        check("player.fire_range", self.player.fire_range > 0, "must be at least 1")?;
+        check(
+            "player.max_fire_range",
+            self.player.max_fire_range >= self.player.fire_range,
+            "must not be below `player.fire_range`",
+        )?;
+        check("player.bomb_capacity", self.player.bomb_capacity > 0, "must be at least 1")?;
+        check("bomb.punch_distance", self.bomb.punch_distance > 0, "must be at least 1")?;
+        check("bomb.throw_distance", self.bomb.throw_distance > 0, "must be at least 1")?;
+
+        Ok(())
+    }
+}
+
+fn check(key: &'static str, holds: bool, reason: &'static str) -> Result<(), ConfigError> {
+    if holds {
+        Ok(())
+    } else {
+        Err(ConfigError::OutOfRange { key, reason })
+    }
+}
+
+fn parse_value<T: DeserializeOwned>(key: &str, value: &str) -> Result<T, ConfigError> {
+    ron::from_str(value).map_err(|err| ConfigError::InvalidValue {
+        key: key.to_string(),
+        value: value.to_string(),
+        reason: err.code.to_string(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn short_periods_last_a_tick() {
+        let config = GameConfig::default();
+
+        assert_eq!(config.ticks(0.0), 0);
+        assert_eq!(config.ticks(0.1 / config.fps), 1);
+        assert_eq!(config.ticks(1.0), config.fps.round() as u64);
+    }
+
+    #[test]
+    fn loads_only_the_values_given() {
+        let source = "(player: (speed: 90.0, bomb_capacity: 2), bomb: (detonation_period: 1.5))";
+        let config = parse(source).unwrap();
+
+        let mut expected = GameConfig::default();
+        expected.player.speed = 90.0;
+        expected.player.bomb_capacity = 2;
+        expected.bomb.detonation_period = 1.5;
+        assert_eq!(config, expected);
+
+        let path = std::env::temp_dir().join(format!("abtestbed-{}.ron", std::process::id()));
+        fs::write(&path, "(fps: 30.0)").unwrap();
+        let loaded = load(&path);
+        fs::remove_file(&path).unwrap();
+        assert_eq!(loaded.unwrap().fps, 30.0);
+
+        let unknown = parse("(player: (sped: 90.0))");
+        assert!(matches!(unknown, Err(ConfigError::Syntax(_))));
+        let missing = load("no/such/config.ron");
+        assert!(matches!(missing, Err(ConfigError::Io(_))));
+    }
+
+    #[test]
+    fn overrides_single_values() {
+        let mut config = GameConfig::default();
+
+        config.set("player.speed=90").unwrap();
+        config.set(" bomb.size = (38.0, 34.0) ").unwrap();
+        config.set("round.sudden_death_after=10").unwrap();
+        assert_eq!(config.player.speed, 90.0);
+        assert_eq!(config.bomb.size, (38.0, 34.0));
+        assert_eq!(config.round.sudden_death_after, 10.0);
+
+        assert!(matches!(
+            config.set("player.sped=90"),
+            Err(ConfigError::UnknownKey { key }) if key == "player.sped"
+        ));
+        assert!(matches!(
+            config.set("player.fire_range=far"),
+            Err(ConfigError::InvalidValue { key, .. }) if key == "player.fire_range"
+        ));
+        assert!(matches!(
+            config.set("player.speed"),
+            Err(ConfigError::MissingValue { .. })
+        ));
+    }
+
+    #[test]
+    fn validates_the_values() {
+        assert!(GameConfig::default().validate().is_ok());
+
+        let broken = [
+            ("fps=0", "fps"),
+            ("bomb.detonation_period=-1", "bomb.detonation_period"),
+            ("round.start_delay=-0.5", "round.start_delay"),
+            ("bomb.size=(38.0, 90.0)", "bomb.size"),
+            ("player.max_speed=1", "player.max_speed"),
+            ("player.bomb_capacity=0", "player.bomb_capacity"),
+        ];
+
+        for (setting, broken_key) in broken {
+            let mut config = GameConfig::default();
+            config.set(setting).unwrap();
+
+            match config.validate() {
+                Err(ConfigError::OutOfRange { key, .. }) => assert_eq!(key, broken_key),
+                _ => panic!("`{}` was accepted", setting),
+            }
+        }
+    }
+}
@@ -0,0 +1,251 @@
+use bevy::prelude::*;
+
+use super::world::controls::{Action, Binding, Bindings, BindingsPath, ControlSet, STICK_DEADZONE};
+use super::world::round::GameState;
+
+pub struct ControlsScreenPlugin;
+
+impl Plugin for ControlsScreenPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<Cursor>()
+            .add_systems(OnEnter(GameState::Lobby), show_lobby_hint)
+            .add_systems(OnEnter(GameState::ControlsScreen), spawn_screen)
+            .add_systems(
+                OnExit(GameState::ControlsScreen),
+                (despawn_screen, save_bindings),
+            )
+            .add_systems(
+                Update,
+                (
+                    open_screen.run_if(in_state(GameState::Lobby)),
+                    (navigate, capture_binding, render_screen)
+                        .chain()
+                        .run_if(in_state(GameState::ControlsScreen)),
+                ),
+            );
+    }
+}
+
+/// The action being looked at, one row per action of every control set with
+/// the gamepad set last.
+#[derive(Resource, Default)]
+struct Cursor {
+    row: usize,
+    // Waiting for the key or button to bind to the selected action
+    capturing: bool,
+}
+
+impl Cursor {
+    fn selection(&self) -> (usize, Action) {
+        (
+            self.row / Action::ALL.len(),
+            Action::ALL[self.row % Action::ALL.len()],
+        )
+    }
+}
+
+#[derive(Component)]
+struct ControlsScreen;
+
+#[derive(Component)]
+struct ControlsText;
+
+fn show_lobby_hint() {
+    info!("Press Enter to start the match, F1 to change the controls");
+}
+
+fn open_screen(kbd_input: Res<ButtonInput<KeyCode>>, mut next_state: ResMut<NextState<GameState>>) {
+    if kbd_input.just_pressed(KeyCode::F1) {
+        next_state.set(GameState::ControlsScreen);
+    }
+}
+
+fn spawn_screen(mut commands: Commands, mut cursor: ResMut<Cursor>) {
+    *cursor = Cursor::default();
+
+    commands
+        .spawn((
+            ControlsScreen,
+            Node {
+                width: Val::Percent(100.0),
+                height: Val::Percent(100.0),
+                padding: UiRect::all(Val::Px(24.0)),
+                ..default()
+            },
+            BackgroundColor(Color::srgba(0.0, 0.0, 0.0, 0.85)),
+        ))
+        .with_children(|parent| {
+            parent.spawn((
+                ControlsText,
+                Text::default(),
+                TextFont {
+                    font_size: 18.0,
+                    ..default()
+                },
+            ));
+        });
+}
+
+fn despawn_screen(mut commands: Commands, query: Query<Entity, With<ControlsScreen>>) {
+    for entity in &query {
+        commands.entity(entity).despawn_recursive();
+    }
+}
+
+fn save_bindings(bindings: Res<Bindings>, path: Res<BindingsPath>) {
+    match bindings.save(&path.0) {
+        Ok(()) => info!("Saved the controls to {}", path.0.display()),
+        Err(err) => warn!("{}: {}", path.0.display(), err),
+    }
+}
+
+fn navigate(
+    kbd_input: Res<ButtonInput<KeyCode>>,
+    mut cursor: ResMut<Cursor>,
+    mut bindings: ResMut<Bindings>,
+    mut next_state: ResMut<NextState<GameState>>,
+) {
+    if cursor.capturing {
+        return;
+    }
+
+    let rows = (bindings.keyboard.len() + 1) * Action::ALL.len();
+
+    if kbd_input.just_pressed(KeyCode::ArrowDown) {
+        cursor.row = (cursor.row + 1) % rows;
+    } else if kbd_input.just_pressed(KeyCode::ArrowUp) {
+        cursor.row = (cursor.row + rows - 1) % rows;
+    } else if kbd_input.just_pressed(KeyCode::Enter) {
+        cursor.capturing = true;
+    } else if kbd_input.just_pressed(KeyCode::Backspace) {
+        let (set, action) = cursor.selection();
+        control_set(&mut bindings, set).get_mut(action).clear();
+    } else if kbd_input.just_pressed(KeyCode::Escape) {
+        next_state.set(GameState::Lobby);
+    }
+}
+
+fn capture_binding(
+    kbd_input: Res<ButtonInput<KeyCode>>,
+    gamepads: Query<&Gamepad>,
+    mut cursor: ResMut<Cursor>,
+    mut bindings: ResMut<Bindings>,
+) {
+    // The Enter that started the capture is not a binding
+    if !cursor.capturing || kbd_input.just_pressed(KeyCode::Enter) {
+        return;
+    }
+
+    if kbd_input.just_pressed(KeyCode::Escape) {
+        cursor.capturing = false;
+        return;
+    }
+
+    let (set, action) = cursor.selection();
+    let is_gamepad_set = set == bindings.keyboard.len();
+
+    let captured = if is_gamepad_set {
+        gamepads
+            .iter()
+            .find_map(|gamepad| captured_gamepad_input(gamepad, action))
+    } else {
+        kbd_input
+            .get_just_pressed()
+            .next()
+            .map(|key| Binding::Key(*key))
+    };
+
+    let Some(binding) = captured else {
+        return;
+    };
+
+    let action_bindings = control_set(&mut bindings, set).get_mut(action);
+    if !action_bindings.contains(&binding) {
+        action_bindings.push(binding);
+    }
+
+    cursor.capturing = false;
+}
+
+// Sticks only steer, bombs need a button that goes down
+fn captured_gamepad_input(gamepad: &Gamepad, action: Action) -> Option<Binding> {
+    if let Some(button) = gamepad.get_just_pressed().next() {
+        return Some(Binding::Button(*button));
+    }
+
+    if matches!(action, Action::Bomb | Action::Detonate) {
+        return None;
+    }
+
+    GamepadAxis::all().into_iter().find_map(|axis| {
+        let value = gamepad.get(axis).unwrap_or_default();
+
+        (value.abs() >= STICK_DEADZONE).then_some(Binding::Axis {
+            axis,
+            positive: value > 0.0,
+        })
+    })
+}
+
+fn control_set(bindings: &mut Bindings, set: usize) -> &mut ControlSet {
+    if set < bindings.keyboard.len() {
+        &mut bindings.keyboard[set]
+    } else {
+        &mut bindings.gamepad
+    }
+}
+
+fn render_screen(
+    cursor: Res<Cursor>,
+    bindings: Res<Bindings>,
+    mut texts: Query<&mut Text, With<ControlsText>>,
+) {
+    if !cursor.is_changed() && !bindings.is_changed() {
+        return;
+    }
+
+    let mut lines = vec![
+        "CONTROLS".to_string(),
+        "Up/Down select, Enter adds a binding, Backspace clears, Escape saves and leaves"
+            .to_string(),
+    ];
+
+    let set_names = (1..=bindings.keyboard.len())
+        .map(|layout| format!("Keyboard {}", layout))
+        .chain(std::iter::once("Gamepads".to_string()));
+
+    for (set, name) in set_names.enumerate() {
+        lines.push(String::new());
+        lines.push(name);
+
+        let control_set = if set < bindings.keyboard.len() {
+            &bindings.keyboard[set]
+        } else {
+            &bindings.gamepad
+        };
+
+        for (index, action) in Action::ALL.into_iter().enumerate() {
+            let row = set * Action::ALL.len() + index;
+            let marker = if row != cursor.row {
+                "  "
+            } else if cursor.capturing {
+                "? "
+            } else {
+                "> "
+            };
+
+            let bound: Vec<String> = control_set
+                .get(action)
+                .iter()
+                .map(Binding::to_string)
+                .collect();
+
+            let name = format!("{:?}", action);
+            lines.push(format!("{}{:<8} {}", marker, name, bound.join(", ")));
+        }
+    }
+
+    for mut text in &mut texts {
+        text.0 = lines.join("\n");
+    }
+}
@@ -0,0 +1,541 @@
+//! Reinforcement learning environment in the style of Gym: agents play single
+//! rounds of the headless world against each other and any number of bots.
+//!
+//! ```no_run
+//! use abtestbed::gym::{Action, Env, EnvSettings};
+//!
+//! let mut env = Env::new(EnvSettings::default()).unwrap();
+//! let _observation = env.reset(7);
+//!
+//! loop {
+//!     let actions = vec![Action::Bomb; env.agents()];
+//!     let (_observation, _rewards, done, _info) = env.step(&actions);
+//!     if done {
+//!         break;
+//!     }
+//! }
+//! ```
+//!
+//! Every episode is built afresh from its seed, so the same seed and actions
+//! always play out the same way.
+
+use std::collections::HashSet;
+use std::fmt;
+
+use bevy::prelude::*;
+use uuid::Uuid;
+
+use super::setup;
+use super::world::bomb::Bomb;
+use super::world::explosion::Explosion;
+use super::world::map::{self, legend};
+use super::world::player::{InputState, PendingInputs, Player, Roster, RosterSettings, SlotKind};
+use super::world::powerup::Powerup;
+use super::world::round::{GameState, MatchSettings};
+use super::world::{self as game_world, controls};
+
+pub use super::config::GameConfig;
+pub use super::snapshot::{SnapshotError, WorldSnapshot};
+pub use super::world::ai::Difficulty;
+pub use super::world::map::scheme::load as load_scheme;
+pub use super::world::map::MapState;
+pub use super::world::player::RosterError;
+
+const MIN_PLAYERS: usize = 2;
+const WIN_REWARD: f32 = 1.0;
+const DEATH_REWARD: f32 = -1.0;
+
+/// Planes of an `Observation`. The player planes come last, one per player in
+/// the order of `Env::players`.
+pub mod channel {
+    pub const BLOCK: usize = 0;
+    pub const BRICK: usize = 1;
+    pub const POWERUP: usize = 2;
+    pub const BOMB: usize = 3;
+    // Share of the default fuse left, 1 for trigger bombs that wait
+    pub const FUSE: usize = 4;
+    pub const EXPLOSION: usize = 5;
+    pub const PLAYERS: usize = 6;
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Action {
+    Idle,
+    North,
+    South,
+    West,
+    East,
+    Bomb,
+    Detonate,
+}
+
+impl Action {
+    pub const ALL: [Action; 7] = [
+        Action::Idle,
+        Action::North,
+        Action::South,
+        Action::West,
+        Action::East,
+        Action::Bomb,
+        Action::Detonate,
+    ];
+
+    fn inputs(self) -> InputState {
+        let (horizontal_direction, vertical_direction) = match self {
+            Action::North => (0, 1),
+            Action::South => (0, -1),
+            Action::West => (-1, 0),
+            Action::East => (1, 0),
+            Action::Idle | Action::Bomb | Action::Detonate => (0, 0),
+        };
+
+        InputState {
+            horizontal_direction,
+            vertical_direction,
+            plant_bomb: self == Action::Bomb,
+            detonate: self == Action::Detonate,
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct EnvSettings {
+    pub config: GameConfig,
+    // The default arena when unset
+    pub map: Option<MapState>,
+    pub agents: usize,
+    pub bots: Vec<Difficulty>,
+    // World ticks played out per step, the action holds for all of them
+    pub ticks_per_step: u32,
+}
+
+impl std::default::Default for EnvSettings {
+    fn default() -> Self {
+        EnvSettings {
+            config: GameConfig::default(),
+            map: None,
+            agents: 1,
+            bots: vec![Difficulty::Normal],
+            ticks_per_step: 4,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum EnvError {
+    TooFewPlayers { count: usize },
+    Roster(RosterError),
+}
+
+impl fmt::Display for EnvError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            EnvError::TooFewPlayers { count } => write!(
+                f,
+                "{} players cannot play a round, at least {} are needed",
+                count, MIN_PLAYERS
+            ),
+            EnvError::Roster(err) => write!(f, "{}", err),
+        }
+    }
+}
+
+impl std::error::Error for EnvError {}
+
+impl From<RosterError> for EnvError {
+    fn from(err: RosterError) -> Self {
+        EnvError::Roster(err)
+    }
+}
+
+/// The arena as planes of `width * height` values, stored plane by plane and
+/// row by row from the top left cell.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Observation {
+    pub width: usize,
+    pub height: usize,
+    pub channels: usize,
+    pub data: Vec<f32>,
+}
+
+impl Observation {
+    pub fn get(&self, channel: usize, x: usize, y: usize) -> f32 {
+        self.data[self.index(channel, x, y)]
+    }
+
+    fn index(&self, channel: usize, x: usize, y: usize) -> usize {
+        (channel * self.height + y) * self.width + x
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct StepInfo {
+    // World ticks since the round started
+    pub tick: u64,
+    // Per agent
+    pub alive: Vec<bool>,
+}
+
+pub struct Env {
+    settings: EnvSettings,
+    // Unset until the first reset
+    app: Option<App>,
+    agents: Vec<Uuid>,
+    players: Vec<Uuid>,
+    alive: Vec<bool>,
+    done: bool,
+    // World tick the round started at
+    start: u64,
+}
+
+impl Env {
+    pub fn new(settings: EnvSettings) -> Result<Self, EnvError> {
+        let count = settings.agents + settings.bots.len();
+        if count < MIN_PLAYERS {
+            return Err(EnvError::TooFewPlayers { count });
+        }
+
+        roster_settings(&settings).validate(&controls::Bindings::default())?;
+
+        Ok(Env {
+            settings,
+            app: None,
+            agents: Vec::new(),
+            players: Vec::new(),
+            alive: Vec::new(),
+            done: true,
+            start: 0,
+        })
+    }
+
+    pub fn agents(&self) -> usize {
+        self.settings.agents
+    }
+
+    /// Every player of the round, agents first, in the order of the player
+    /// planes of the observations.
+    pub fn players(&self) -> &[Uuid] {
+        &self.players
+    }
+
+    /// Starts a new round from `seed` and plays it up to the moment the
+    /// players can move.
+    pub fn reset(&mut self, seed: u64) -> Observation {
+        let mut app = App::new();
+
+        app.insert_resource(self.settings.config.clone())
+            .insert_resource(setup::GameRng::from_seed(seed))
+            .insert_resource(roster_settings(&self.settings))
+            .insert_resource(MatchSettings {
+                auto_start: true,
+                ..default()
+            });
+
+        if let Some(map_state) = &self.settings.map {
+            app.insert_resource(map_state.clone());
+        }
+
+        app.add_plugins(setup::HeadlessSetupPlugin)
+            .add_plugins(game_world::WorldPlugin);
+
+        // Done by `App::run` otherwise, which would never hand control back
+        app.finish();
+        app.cleanup();
+
+        while *app.world().resource::<State<GameState>>().get() != GameState::InRound {
+            app.update();
+        }
+
+        self.players = app
+            .world()
+            .resource::<Roster>()
+            .slots
+            .iter()
+            .map(|slot| slot.id)
+            .collect();
+        self.agents = self.players[..self.settings.agents].to_vec();
+        self.alive = vec![true; self.agents.len()];
+        self.done = false;
+        self.start = app.world().resource::<setup::SimTick>().0;
+        self.app = Some(app);
+
+        self.observe()
+    }
+
+    /// Plays one action per agent for `ticks_per_step` ticks. Agents earn
+    /// `DEATH_REWARD` when they die and `WIN_REWARD` for being the last one
+    /// standing. Once the round is over the world stands still.
+    pub fn step(&mut self, actions: &[Action]) -> (Observation, Vec<f32>, bool, StepInfo) {
+        assert_eq!(
+            actions.len(),
+            self.agents.len(),
+            "one action is needed per agent"
+        );
+
+        let mut rewards = vec![0.0; self.agents.len()];
+
+        if !self.done {
+            let app = self.app.as_mut().expect("the environment was never reset");
+
+            // Taken in on the next tick, directions then hold while the
+            // bomb and trigger go off once
+            let mut pending_inputs = app.world_mut().resource_mut::<PendingInputs>();
+            for (id, action) in self.agents.iter().zip(actions) {
+                pending_inputs.by_id.insert(*id, action.inputs());
+            }
+
+            for _ in 0..self.settings.ticks_per_step {
+                app.update();
+
+                if *app.world().resource::<State<GameState>>().get() != GameState::InRound {
+                    self.done = true;
+                    break;
+                }
+            }
+
+            let living: HashSet<Uuid> = app
+                .world_mut()
+                .query::<&Player>()
+                .iter(app.world())
+                .map(|player| player.id)
+                .collect();
+
+            for (index, id) in self.agents.iter().enumerate() {
+                if self.alive[index] && !living.contains(id) {
+                    self.alive[index] = false;
+                    rewards[index] += DEATH_REWARD;
+                }
+            }
+
+            if self.done && living.len() == 1 {
+                if let Some(winner) = self.agents.iter().position(|id| living.contains(id)) {
+                    rewards[winner] += WIN_REWARD;
+                }
+            }
+
+            // Bots alone have nothing left to teach
+            self.done |= !self.alive.contains(&true);
+        }
+
+        let app = self.app.as_ref().expect("the environment was never reset");
+        let info = StepInfo {
+            tick: app.world().resource::<setup::SimTick>().0.saturating_sub(self.start),
+            alive: self.alive.clone(),
+        };
+
+        (self.observe(), rewards, self.done, info)
+    }
+
+    /// Captures the world as it stands between two steps.
+    pub fn snapshot(&mut self) -> WorldSnapshot {
+        let app = self.app.as_mut().expect("the environment was never reset");
+        WorldSnapshot::capture(app.world_mut())
+    }
+
+    /// Puts the world of the current episode back as it was in `snapshot`,
+    /// which must come from an episode with the same players.
+    pub fn restore(&mut self, snapshot: &WorldSnapshot) -> Observation {
+        let app = self.app.as_mut().expect("the environment was never reset");
+        snapshot.restore(app.world_mut());
+
+        let living: HashSet<Uuid> = snapshot.players.iter().map(|p| p.player.id).collect();
+        self.alive = self.agents.iter().map(|id| living.contains(id)).collect();
+        self.done = snapshot.state != GameState::InRound || !self.alive.contains(&true);
+
+        self.observe()
+    }
+
+    fn observe(&mut self) -> Observation {
+        let app = self.app.as_mut().expect("the environment was never reset");
+        let world = app.world_mut();
+
+        let grid = world.resource::<MapState>().scheme.clone();
+        let now = world.resource::<setup::SimTick>().0;
+        let config = &self.settings.config;
+        let fuse = config.ticks(config.bomb.detonation_period).max(1) as f32;
+
+        let mut observation = Observation {
+            width: grid.width() as usize,
+            height: grid.height() as usize,
+            channels: channel::PLAYERS + self.players.len(),
+            data: Vec::new(),
+        };
+        observation.data = vec![0.0; observation.channels * observation.height * observation.width];
+
+        let mut set = |channel: usize, cell: map::Cell, value: f32| {
+            let index = observation.index(channel, cell.0 as usize, cell.1 as usize);
+            observation.data[index] = value;
+        };
+
+        for (cell, tile) in grid.cells() {
+            match tile {
+                legend::BLOCK => set(channel::BLOCK, cell, 1.0),
+                legend::BRICK => set(channel::BRICK, cell, 1.0),
+                _ => {}
+            }
+        }
+
+        for powerup in world.query::<&Powerup>().iter(world) {
+            set(channel::POWERUP, powerup.cell, 1.0);
+        }
+
+        for bomb in world.query::<&Bomb>().iter(world) {
+            let left = bomb.explode_at().map_or(1.0, |at| {
+                (at.saturating_sub(now) as f32 / fuse).min(1.0)
+            });
+
+            set(channel::BOMB, bomb.cell, 1.0);
+            set(channel::FUSE, bomb.cell, left);
+        }
+
+        for explosion in world.query::<&Explosion>().iter(world) {
+            set(channel::EXPLOSION, explosion.cell, 1.0);
+        }
+
+        for (player, transform) in world.query::<(&Player, &Transform)>().iter(world) {
+            if let Some(index) = self.players.iter().position(|id| *id == player.id) {
+                let cell = map::Cell::from_transform(&grid, transform);
+                set(channel::PLAYERS + index, cell, 1.0);
+            }
+        }
+
+        observation
+    }
+}
+
+fn roster_settings(settings: &EnvSettings) -> RosterSettings {
+    let slots = std::iter::repeat_n(SlotKind::Remote, settings.agents)
+        .chain(settings.bots.iter().copied().map(SlotKind::Bot))
+        .collect();
+
+    RosterSettings { slots }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MAX_STEPS: usize = 500;
+
+    fn env(agents: usize, bots: Vec<Difficulty>) -> Env {
+        Env::new(EnvSettings {
+            agents,
+            bots,
+            ..default()
+        })
+        .unwrap()
+    }
+
+    // Walks about and plants a bomb now and then
+    fn wander(step: usize, agent: usize) -> Action {
+        if step.is_multiple_of(15) {
+            Action::Bomb
+        } else {
+            Action::ALL[1 + (step / 5 + agent) % 4]
+        }
+    }
+
+    #[test]
+    fn rejects_too_few_players() {
+        assert!(matches!(
+            Env::new(EnvSettings {
+                agents: 1,
+                bots: Vec::new(),
+                ..default()
+            }),
+            Err(EnvError::TooFewPlayers { count: 1 })
+        ));
+    }
+
+    #[test]
+    fn reset_is_reproducible() {
+        let mut env = env(2, vec![Difficulty::Hard]);
+
+        let first = env.reset(7);
+        let players = env.players().to_vec();
+        let second = env.reset(7);
+
+        assert_eq!(first, second);
+        assert_eq!(env.players(), players);
+        assert_eq!(env.players().len(), 3);
+    }
+
+    #[test]
+    fn steps_until_the_last_player_stands() {
+        let mut env = env(2, Vec::new());
+        let observation = env.reset(3);
+
+        let grid = &MapState::default().scheme;
+        assert_eq!(observation.width, grid.width() as usize);
+        assert_eq!(observation.height, grid.height() as usize);
+        assert_eq!(observation.channels, channel::PLAYERS + 2);
+        assert_eq!(
+            observation.data.len(),
+            observation.channels * observation.width * observation.height
+        );
+        for (index, id) in env.players().iter().enumerate() {
+            let on_map = (0..observation.height)
+                .flat_map(|y| (0..observation.width).map(move |x| (x, y)))
+                .filter(|(x, y)| observation.get(channel::PLAYERS + index, *x, *y) == 1.0)
+                .count();
+            assert_eq!(on_map, 1, "player {} is not on the map once", id);
+        }
+
+        // The first agent stays put, the second one stands on its own bombs
+        let mut totals = [0.0; 2];
+        let mut done = false;
+        let mut last_tick = 0;
+
+        for _ in 0..MAX_STEPS {
+            let (observation, rewards, step_done, info) = env.step(&[Action::Idle, Action::Bomb]);
+
+            let cells = grid.width() as usize * grid.height() as usize;
+            assert_eq!(observation.data.len(), observation.channels * cells);
+            assert_eq!(rewards.len(), 2);
+            assert!(info.tick > last_tick);
+            last_tick = info.tick;
+            totals[0] += rewards[0];
+            totals[1] += rewards[1];
+
+            if step_done {
+                assert_eq!(info.alive, vec![true, false]);
+                done = true;
+                break;
+            }
+        }
+
+        assert!(done, "the round never ended");
+        assert_eq!(totals, [WIN_REWARD, DEATH_REWARD]);
+
+        // Over is over
+        let (_, rewards, done, info) = env.step(&[Action::East, Action::East]);
+        assert_eq!(rewards, vec![0.0, 0.0]);
+        assert!(done);
+        assert_eq!(info.tick, last_tick);
+    }
+
+    #[test]
+    fn restore_replays_the_same_steps() {
+        let mut env = env(2, vec![Difficulty::Hard, Difficulty::Normal]);
+        env.reset(11);
+
+        for step in 0..20 {
+            env.step(&[wander(step, 0), wander(step, 1)]);
+        }
+
+        let snapshot = env.snapshot();
+        let play = |env: &mut Env| -> Vec<(Observation, Vec<f32>, bool, StepInfo)> {
+            (20..80)
+                .map(|step| env.step(&[wander(step, 0), wander(step, 1)]))
+                .collect()
+        };
+
+        let before = env.observe();
+        let first = play(&mut env);
+
+        assert_eq!(env.restore(&snapshot), before);
+        let second = play(&mut env);
+
+        assert!(first == second, "the restored world played out differently");
+    }
+}
@@ -0,0 +1,71 @@
+use bevy::prelude::*;
+
+use super::setup::SimTick;
+use super::world::player::Roster;
+use super::world::round::{GameState, MatchScore, MatchSettings};
+
+/// Plays a match without waiting for anybody and ends the run once it is
+/// decided or the tick limit is reached, printing the outcome.
+pub struct HeadlessPlugin {
+    pub max_ticks: u64,
+}
+
+impl Plugin for HeadlessPlugin {
+    fn build(&self, app: &mut App) {
+        // Keeps the number of wins the match was set up with
+        app.world_mut()
+            .get_resource_or_insert_with(MatchSettings::default)
+            .auto_start = true;
+
+        app.insert_resource(TickLimit(self.max_ticks))
+            .add_systems(OnEnter(GameState::MatchOver), report_match_over)
+            .add_systems(Last, watch_tick_limit);
+    }
+}
+
+#[derive(Resource)]
+pub struct TickLimit(u64);
+
+pub fn watch_tick_limit(
+    tick: Res<SimTick>,
+    tick_limit: Res<TickLimit>,
+    score: Res<MatchScore>,
+    roster: Res<Roster>,
+    mut exit: EventWriter<AppExit>,
+) {
+    if tick.0 < tick_limit.0 {
+        return;
+    }
+
+    println!("Match stopped after {} ticks in round {}", tick.0, score.round);
+    print_score(&score, &roster);
+
+    exit.send(AppExit::Success);
+}
+
+fn report_match_over(
+    score: Res<MatchScore>,
+    roster: Res<Roster>,
+    mut exit: EventWriter<AppExit>,
+) {
+    print_champion(&score, &roster);
+    print_score(&score, &roster);
+
+    exit.send(AppExit::Success);
+}
+
+pub fn print_champion(score: &MatchScore, roster: &Roster) {
+    if let Some(champion) = roster.slots.iter().find(|slot| Some(slot.id) == score.champion) {
+        println!(
+            "Match finished after {} rounds: {:?} ({}) wins",
+            score.round, champion.color, champion.id
+        );
+    }
+}
+
+pub fn print_score(score: &MatchScore, roster: &Roster) {
+    for slot in &roster.slots {
+        let wins = score.wins.get(&slot.id).copied().unwrap_or_default();
+        println!("  {:?} ({}): {} wins", slot.color, slot.id, wins);
+    }
+}
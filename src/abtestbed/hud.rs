@@ -0,0 +1,78 @@
+use bevy::prelude::*;
+
+use super::config::GameConfig;
+use super::world::round::{GameState, MatchScore};
+use super::world::sudden_death::SuddenDeath;
+
+const TEXT_COLOR: Color = Color::WHITE;
+const SUDDEN_DEATH_COLOR: Color = Color::srgb(0.9, 0.1, 0.1);
+
+/// A line over the arena with the round and the time left before sudden death.
+pub struct HudPlugin;
+
+impl Plugin for HudPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_systems(Startup, spawn_hud)
+            .add_systems(Update, render_hud);
+    }
+}
+
+#[derive(Component)]
+struct HudText;
+
+fn spawn_hud(mut commands: Commands) {
+    commands
+        .spawn(Node {
+            width: Val::Percent(100.0),
+            position_type: PositionType::Absolute,
+            top: Val::Px(8.0),
+            justify_content: JustifyContent::Center,
+            ..default()
+        })
+        .with_children(|parent| {
+            parent.spawn((
+                HudText,
+                Text::default(),
+                TextFont {
+                    font_size: 22.0,
+                    ..default()
+                },
+                TextColor(TEXT_COLOR),
+            ));
+        });
+}
+
+fn render_hud(
+    state: Res<State<GameState>>,
+    score: Res<MatchScore>,
+    sudden_death: Res<SuddenDeath>,
+    config: Res<GameConfig>,
+    mut texts: Query<(&mut Text, &mut TextColor), With<HudText>>,
+) {
+    let in_round = matches!(
+        state.get(),
+        GameState::RoundStarting | GameState::InRound | GameState::RoundOver
+    );
+
+    let (line, color) = if !in_round {
+        (String::new(), TEXT_COLOR)
+    } else if sudden_death.started() {
+        (
+            format!("Round {}   SUDDEN DEATH", score.round),
+            SUDDEN_DEATH_COLOR,
+        )
+    } else if let Some(remaining) = sudden_death.remaining() {
+        let secs = (remaining as f32 / config.fps).ceil() as u32;
+        let line = format!("Round {}   {}:{:02}", score.round, secs / 60, secs % 60);
+        (line, TEXT_COLOR)
+    } else {
+        (format!("Round {}", score.round), TEXT_COLOR)
+    };
+
+    for (mut text, mut text_color) in &mut texts {
+        if text.0 != line {
+            text.0.clone_from(&line);
+            text_color.0 = color;
+        }
+    }
+}
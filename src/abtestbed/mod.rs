@@ -1,11 +1,568 @@
 use bevy::prelude::*;
 
+mod config;
+mod controls_screen;
+pub mod gym;
+mod headless;
+mod hud;
+mod net;
+mod replay;
 mod setup;
+mod snapshot;
 mod world;
 
+// Three minutes of play at the fixed step rate
+const DEFAULT_HEADLESS_SECS: f32 = 180.0;
+const DEFAULT_CONTROLS_PATH: &str = "controls.ron";
+
 pub fn main() {
-    App::new()
-        .add_plugins(setup::SetupPlugin)
-        .add_plugins(world::WorldPlugin)
-        .run();
+    let mut app = App::new();
+
+    if let Some(path) = arg_value("--replay") {
+        let replay = match replay::Replay::load(&path) {
+            Ok(replay) => replay,
+            Err(err) => {
+                eprintln!("{}: {}", path, err);
+                std::process::exit(1);
+            }
+        };
+
+        let exit = app
+            .insert_resource(replay.config.clone())
+            .add_plugins(setup::HeadlessSetupPlugin)
+            .add_plugins(replay::PlaybackPlugin { replay })
+            .add_plugins(world::WorldPlugin)
+            .run();
+
+        if exit.is_error() {
+            std::process::exit(1);
+        }
+        return;
+    }
+
+    let mut game_config = match arg_value("--config") {
+        Some(path) => match config::load(&path) {
+            Ok(game_config) => game_config,
+            Err(err) => {
+                eprintln!("{}: {}", path, err);
+                std::process::exit(1);
+            }
+        },
+        None => config::GameConfig::default(),
+    };
+
+    for setting in arg_values("--set") {
+        if let Err(err) = game_config.set(&setting) {
+            eprintln!("--set {}: {}", setting, err);
+            std::process::exit(1);
+        }
+    }
+
+    if let Err(err) = game_config.validate() {
+        eprintln!("invalid config: {}", err);
+        std::process::exit(1);
+    }
+
+    // Plugins read the config while they are built
+    let fps = game_config.fps;
+    app.insert_resource(game_config);
+
+    if let Some(path) = arg_value("--watch") {
+        let recording = match net::stream::Recording::load(&path) {
+            Ok(recording) => recording,
+            Err(err) => {
+                eprintln!("{}: {}", path, err);
+                std::process::exit(1);
+            }
+        };
+
+        app.add_plugins(setup::SetupPlugin)
+            .add_plugins(net::stream::ViewerPlugin { recording })
+            .run();
+        return;
+    }
+
+    if let Some(path) = arg_value("--scheme") {
+        match world::map::scheme::load(&path) {
+            Ok(map_state) => {
+                app.insert_resource(map_state);
+            }
+            Err(err) => {
+                eprintln!("{}: {}", path, err);
+                std::process::exit(1);
+            }
+        }
+    }
+
+    let seed = arg_value("--seed").map(|seed| match seed.parse() {
+        Ok(seed) => seed,
+        Err(err) => {
+            eprintln!("--seed {}: {}", seed, err);
+            std::process::exit(1);
+        }
+    });
+
+    if let Some(seed) = seed {
+        app.insert_resource(setup::GameRng::from_seed(seed));
+    }
+
+    if let Some(spec) = arg_value("--generate") {
+        if app.world().contains_resource::<world::map::MapState>() {
+            eprintln!("--generate cannot be combined with --scheme");
+            std::process::exit(1);
+        }
+
+        let map_seed = seed.unwrap_or_else(rand::random);
+        let generated = spec
+            .parse()
+            .and_then(|params| world::map::generate(&params, map_seed));
+
+        match generated {
+            Ok(map_state) => {
+                println!("Generated map from seed {}", map_seed);
+                app.insert_resource(map_state);
+            }
+            Err(err) => {
+                eprintln!("--generate {}: {}", spec, err);
+                std::process::exit(1);
+            }
+        }
+    }
+
+    // Bindings are saved back to the file they came from, which may not exist yet
+    let bindings_path = arg_value("--controls").unwrap_or_else(|| DEFAULT_CONTROLS_PATH.into());
+    let bindings = if std::path::Path::new(&bindings_path).exists() {
+        match world::controls::Bindings::load(&bindings_path) {
+            Ok(bindings) => bindings,
+            Err(err) => {
+                eprintln!("{}: {}", bindings_path, err);
+                std::process::exit(1);
+            }
+        }
+    } else {
+        world::controls::Bindings::default()
+    };
+
+    app.insert_resource(bindings.clone())
+        .insert_resource(world::controls::BindingsPath(bindings_path.into()));
+
+    // Every peer of a rollback session is given the same addresses, in the
+    // same order, and tells which of them it is with `--handle`
+    let peer_addrs = arg_value("--peers").map(|peers| {
+        let addrs: Result<Vec<std::net::SocketAddr>, _> = peers.split(',').map(str::parse).collect();
+        match addrs {
+            Ok(addrs) => addrs,
+            Err(err) => {
+                eprintln!("--peers {}: {}", peers, err);
+                std::process::exit(1);
+            }
+        }
+    });
+
+    let loopback_peers = arg_value("--loopback").map(|peers| match peers.parse() {
+        Ok(peers) => peers,
+        Err(err) => {
+            eprintln!("--loopback {}: {}", peers, err);
+            std::process::exit(1);
+        }
+    });
+
+    let rollback_peers = match (&peer_addrs, loopback_peers) {
+        (Some(_), Some(_)) => {
+            eprintln!("--peers cannot be combined with --loopback");
+            std::process::exit(1);
+        }
+        (Some(addrs), None) => Some(addrs.len()),
+        (None, peers) => peers,
+    };
+
+    if let Some(peers) = rollback_peers {
+        if peers < 2 {
+            eprintln!("a rollback session needs at least two peers");
+            std::process::exit(1);
+        }
+
+        for flag in [
+            "--connect",
+            "--spectate",
+            "--server",
+            "--players",
+            "--external",
+            "--record",
+            "--record-stream",
+        ] {
+            if arg_value(flag).is_some() {
+                eprintln!("{} cannot be combined with a rollback session", flag);
+                std::process::exit(1);
+            }
+        }
+    }
+
+    // Spectators connect like players, but only watch the match
+    let remote = match (arg_value("--connect"), arg_value("--spectate")) {
+        (Some(_), Some(_)) => {
+            eprintln!("--connect cannot be combined with --spectate");
+            std::process::exit(1);
+        }
+        (Some(addr), None) => Some(("--connect", addr)),
+        (None, Some(addr)) => Some(("--spectate", addr)),
+        (None, None) => None,
+    };
+
+    if let Some((flag, addr)) = remote {
+        let client = match addr.parse().map_err(|err| format!("{}", err)) {
+            Ok(addr) if flag == "--spectate" => {
+                net::client::Client::spectate(addr).map_err(|err| format!("{}", err))
+            }
+            Ok(addr) => net::client::Client::connect(addr).map_err(|err| format!("{}", err)),
+            Err(err) => Err(err),
+        };
+
+        match client {
+            Ok(client) => {
+                app.insert_resource(client);
+            }
+            Err(err) => {
+                eprintln!("{} {}: {}", flag, addr, err);
+                std::process::exit(1);
+            }
+        }
+
+        app.add_plugins(setup::SetupPlugin)
+            .add_plugins(net::client::ClientPlugin)
+            .run();
+        return;
+    }
+
+    if let Some(addr) = arg_value("--server") {
+        let server = match addr.parse().map_err(|err| format!("{}", err)) {
+            Ok(addr) => net::server::Server::bind(addr).map_err(|err| format!("{}", err)),
+            Err(err) => Err(err),
+        };
+
+        match server {
+            Ok(server) => {
+                if let Ok(local_addr) = server.local_addr() {
+                    println!("Listening on {}", local_addr);
+                }
+
+                app.insert_resource(server);
+            }
+            Err(err) => {
+                eprintln!("--server {}: {}", addr, err);
+                std::process::exit(1);
+            }
+        }
+
+        app.add_plugins(net::server::ServerPlugin);
+    }
+
+    if let Some(wins) = arg_value("--wins") {
+        match wins.parse() {
+            Ok(0) => {
+                eprintln!("--wins {}: a match takes at least one win", wins);
+                std::process::exit(1);
+            }
+            Ok(wins_to_win) => {
+                app.insert_resource(world::round::MatchSettings {
+                    wins_to_win,
+                    ..default()
+                });
+            }
+            Err(err) => {
+                eprintln!("--wins {}: {}", wins, err);
+                std::process::exit(1);
+            }
+        }
+    }
+
+    let mut roster_settings = match (arg_value("--players"), arg_value("--bots")) {
+        (Some(_), Some(_)) => {
+            eprintln!("--players cannot be combined with --bots");
+            std::process::exit(1);
+        }
+        (Some(players), None) => match players.split(',').map(str::parse).collect() {
+            Ok(slots) => Some(world::player::RosterSettings { slots }),
+            Err(err) => {
+                eprintln!("--players {}: {}", players, err);
+                std::process::exit(1);
+            }
+        },
+        (None, Some(bots)) => {
+            let difficulties: Result<Vec<world::ai::Difficulty>, _> =
+                bots.split(',').map(str::parse).collect();
+
+            match difficulties {
+                Ok(difficulties) => {
+                    // Bots take the place of the second keyboard player, or of both
+                    // when nobody is at the keyboard, and join the peers of a
+                    // rollback session
+                    let (kind, count) = match rollback_peers {
+                        Some(peers) => (world::player::SlotKind::Remote, peers),
+                        None if has_flag("--headless") => (world::player::SlotKind::Keyboard, 0),
+                        None => (world::player::SlotKind::Keyboard, 1),
+                    };
+                    let slots = std::iter::repeat_n(kind, count)
+                        .chain(difficulties.into_iter().map(world::player::SlotKind::Bot))
+                        .collect();
+
+                    Some(world::player::RosterSettings { slots })
+                }
+                Err(err) => {
+                    eprintln!("--bots {}: {}", bots, err);
+                    std::process::exit(1);
+                }
+            }
+        }
+        (None, None) => rollback_peers.map(|peers| world::player::RosterSettings {
+            slots: vec![world::player::SlotKind::Remote; peers],
+        }),
+    };
+
+    // Without `--players` naming their slots, every external bot takes a new one
+    let external_commands: Vec<String> = arg_values("--external").collect();
+    if arg_value("--players").is_none() && !external_commands.is_empty() {
+        let external_slots =
+            std::iter::repeat_n(world::player::SlotKind::External, external_commands.len());
+        roster_settings.get_or_insert_with(default).slots.extend(external_slots);
+    }
+
+    let external_slots = roster_settings.as_ref().map_or(0, |settings| {
+        settings
+            .slots
+            .iter()
+            .filter(|kind| **kind == world::player::SlotKind::External)
+            .count()
+    });
+
+    if external_slots != external_commands.len() {
+        eprintln!(
+            "--players names {} external bots, but {} --external commands were given",
+            external_slots,
+            external_commands.len()
+        );
+        std::process::exit(1);
+    }
+
+    let external_timeout = match arg_value("--external-timeout").map(|ms| ms.parse()) {
+        None => world::external::DEFAULT_TIMEOUT,
+        Some(Ok(ms)) => std::time::Duration::from_millis(ms),
+        Some(Err(err)) => {
+            eprintln!("--external-timeout: {}", err);
+            std::process::exit(1);
+        }
+    };
+
+    app.insert_resource(world::external::ExternalSettings {
+        commands: external_commands,
+        timeout: external_timeout,
+    });
+
+    if let Some(roster_settings) = roster_settings {
+        if let Err(err) = roster_settings.validate(&bindings) {
+            eprintln!("invalid players: {}", err);
+            std::process::exit(1);
+        }
+
+        app.insert_resource(roster_settings);
+    }
+
+    let max_ticks = match arg_value("--ticks").map(|ticks| ticks.parse()) {
+        None => (DEFAULT_HEADLESS_SECS * fps) as u64,
+        Some(Ok(ticks)) => ticks,
+        Some(Err(err)) => {
+            eprintln!("--ticks: {}", err);
+            std::process::exit(1);
+        }
+    };
+
+    if let Some(peers) = loopback_peers {
+        let latency = match arg_value("--latency").map(|ms| ms.parse()) {
+            None => std::time::Duration::ZERO,
+            Some(Ok(ms)) => std::time::Duration::from_millis(ms),
+            Some(Err(err)) => {
+                eprintln!("--latency: {}", err);
+                std::process::exit(1);
+            }
+        };
+
+        let loss = match arg_value("--loss").map(|percent| percent.parse::<f32>()) {
+            None => 0.0,
+            Some(Ok(percent)) if (0.0..=100.0).contains(&percent) => percent / 100.0,
+            Some(Ok(percent)) => {
+                eprintln!("--loss {}: not a percentage", percent);
+                std::process::exit(1);
+            }
+            Some(Err(err)) => {
+                eprintln!("--loss: {}", err);
+                std::process::exit(1);
+            }
+        };
+
+        let seed = seed.unwrap_or_else(rand::random);
+        println!("Loopback session of {} peers from seed {}", peers, seed);
+
+        let network =
+            net::loopback::LoopbackNetwork::new(net::loopback::LoopbackSettings { latency, loss }, seed);
+
+        // Every peer gets a world of its own, set up like this one
+        let template = app.world();
+        let game_config = template.resource::<config::GameConfig>().clone();
+        let map_state = template.get_resource::<world::map::MapState>().cloned();
+        let wins_to_win = template
+            .get_resource::<world::round::MatchSettings>()
+            .map(|settings| settings.wins_to_win);
+        let slots = template.resource::<world::player::RosterSettings>().slots.clone();
+
+        let mut peer_apps: Vec<App> = (0..peers)
+            .map(|handle| {
+                let session = net::rollback::Session::new(
+                    net::rollback::SessionSettings {
+                        handle,
+                        peers,
+                        local_inputs: net::rollback::LocalInputs::random(seed + handle as u64),
+                        max_ticks: Some(max_ticks),
+                        exit_when_over: true,
+                    },
+                    Box::new(network.endpoint(handle)),
+                );
+
+                let mut peer = App::new();
+                peer.insert_resource(game_config.clone())
+                    .insert_resource(setup::GameRng::from_seed(seed))
+                    .insert_resource(world::player::RosterSettings { slots: slots.clone() })
+                    .insert_resource(session);
+
+                if let Some(map_state) = &map_state {
+                    peer.insert_resource(map_state.clone());
+                }
+                if let Some(wins_to_win) = wins_to_win {
+                    peer.insert_resource(world::round::MatchSettings {
+                        wins_to_win,
+                        ..default()
+                    });
+                }
+
+                peer.add_plugins(setup::HeadlessSetupPlugin)
+                    .add_plugins(net::rollback::RollbackPlugin)
+                    .add_plugins(world::WorldPlugin);
+                peer
+            })
+            .collect();
+
+        let exit = net::loopback::run(&mut peer_apps, &network, game_config.tick_duration());
+        if exit.is_error() {
+            std::process::exit(1);
+        }
+        return;
+    }
+
+    if let Some(addrs) = peer_addrs {
+        let handle = match arg_value("--handle").map(|handle| handle.parse()) {
+            Some(Ok(handle)) if handle < addrs.len() => handle,
+            Some(Ok(handle)) => {
+                eprintln!("--handle {}: only {} peers were given", handle, addrs.len());
+                std::process::exit(1);
+            }
+            Some(Err(err)) => {
+                eprintln!("--handle: {}", err);
+                std::process::exit(1);
+            }
+            None => {
+                eprintln!("--peers needs --handle");
+                std::process::exit(1);
+            }
+        };
+
+        // Peers generate the same world from the seed, so they must share it
+        let Some(seed) = seed else {
+            eprintln!("--peers needs --seed, the same for every peer");
+            std::process::exit(1);
+        };
+
+        let transport = match net::transport::UdpTransport::bind(addrs.clone(), handle) {
+            Ok(transport) => transport,
+            Err(err) => {
+                eprintln!("--peers {}: {}", addrs[handle], err);
+                std::process::exit(1);
+            }
+        };
+
+        let headless = has_flag("--headless");
+        let local_inputs = if headless {
+            net::rollback::LocalInputs::random(seed + handle as u64)
+        } else {
+            net::rollback::LocalInputs::Keyboard
+        };
+
+        app.insert_resource(net::rollback::Session::new(
+            net::rollback::SessionSettings {
+                handle,
+                peers: addrs.len(),
+                local_inputs,
+                max_ticks: headless.then_some(max_ticks),
+                exit_when_over: headless,
+            },
+            Box::new(transport),
+        ))
+        .add_plugins(net::rollback::RollbackPlugin);
+    }
+
+    // The session of a rollback peer ends the run itself, once every peer
+    // agrees on how the match went
+    if has_flag("--headless") && rollback_peers.is_some() {
+        app.add_plugins(setup::HeadlessSetupPlugin);
+    } else if has_flag("--headless") {
+        app.add_plugins(setup::HeadlessSetupPlugin)
+            .add_plugins(headless::HeadlessPlugin { max_ticks });
+    } else {
+        app.add_plugins(setup::SetupPlugin)
+            .add_plugins(controls_screen::ControlsScreenPlugin)
+            .add_plugins(hud::HudPlugin);
+    }
+
+    if let Some(path) = arg_value("--record") {
+        app.add_plugins(replay::RecordPlugin {
+            seed: seed.unwrap_or_else(rand::random),
+            path: path.into(),
+        });
+    }
+
+    if let Some(path) = arg_value("--record-stream") {
+        match net::stream::StreamWriter::create(&path, fps) {
+            Ok(writer) => {
+                app.insert_resource(writer)
+                    .add_plugins(net::stream::StreamRecordPlugin);
+            }
+            Err(err) => {
+                eprintln!("--record-stream {}: {}", path, err);
+                std::process::exit(1);
+            }
+        }
+    }
+
+    app.add_plugins(world::WorldPlugin).run();
+}
+
+fn has_flag(name: &str) -> bool {
+    std::env::args().skip(1).any(|arg| arg == name)
+}
+
+fn arg_value(name: &str) -> Option<String> {
+    arg_values(name).next()
+}
+
+// Every value given to a flag that may be repeated
+fn arg_values(name: &str) -> impl Iterator<Item = String> + '_ {
+    let mut args = std::env::args().skip(1);
+
+    std::iter::from_fn(move || {
+        while let Some(arg) = args.next() {
+            if arg == name {
+                return args.next();
+            }
+        }
+
+        None
+    })
 }
@@ -0,0 +1,244 @@
+use std::io;
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use bevy::prelude::*;
+use mio::net::TcpStream;
+use mio::{Events, Interest, Poll, Token};
+use uuid::Uuid;
+
+use super::protocol::{ClientMessage, Connection, ServerMessage, Snapshot};
+use super::stream;
+use crate::abtestbed::config::GameConfig;
+use crate::abtestbed::world::controls::Bindings;
+use crate::abtestbed::world::player::InputState;
+use crate::abtestbed::world::{block, brick, map};
+
+const SERVER: Token = Token(0);
+const EVENTS_CAPACITY: usize = 16;
+
+/// Thin client: sends local inputs to the server and draws the snapshots it
+/// receives, without simulating anything itself. A spectator sends nothing
+/// and draws the stream of the match.
+pub struct ClientPlugin;
+
+impl Plugin for ClientPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<Bindings>()
+            .add_systems(Update, (send_client_input, receive_snapshots).chain());
+    }
+}
+
+#[derive(Resource)]
+pub struct Client {
+    poll: Poll,
+    events: Events,
+    connection: Connection,
+    pub player_id: Option<Uuid>,
+    spectator: bool,
+    // Arena as of the last snapshot or frame of the stream
+    view: Option<Snapshot>,
+}
+
+impl Client {
+    pub fn connect(addr: SocketAddr) -> io::Result<Self> {
+        Self::open(addr, false)
+    }
+
+    /// Connects without taking a place in the match, only to watch it.
+    pub fn spectate(addr: SocketAddr) -> io::Result<Self> {
+        Self::open(addr, true)
+    }
+
+    fn open(addr: SocketAddr, spectator: bool) -> io::Result<Self> {
+        let poll = Poll::new()?;
+        let mut connection = Connection::new(TcpStream::connect(addr)?);
+        poll.registry().register(
+            connection.stream_mut(),
+            SERVER,
+            Interest::READABLE | Interest::WRITABLE,
+        )?;
+
+        // Sent once the stream is connected
+        let hello = if spectator {
+            ClientMessage::Spectate
+        } else {
+            ClientMessage::Join
+        };
+        connection.queue(&hello)?;
+
+        Ok(Client {
+            poll,
+            events: Events::with_capacity(EVENTS_CAPACITY),
+            connection,
+            player_id: None,
+            spectator,
+            view: None,
+        })
+    }
+
+    pub fn send(&mut self, message: &ClientMessage) -> io::Result<()> {
+        self.connection.queue(message)?;
+        self.connection.flush()
+    }
+
+    /// Collects the messages received since the last call, without blocking.
+    pub fn poll(&mut self) -> io::Result<Vec<ServerMessage>> {
+        self.poll.poll(&mut self.events, Some(Duration::ZERO))?;
+
+        let mut messages = Vec::new();
+
+        for event in self.events.iter() {
+            if event.is_writable() {
+                self.connection.flush()?;
+            }
+
+            if event.is_readable() {
+                messages.extend(self.connection.receive()?);
+            }
+        }
+
+        for message in &messages {
+            if let ServerMessage::Welcome { player_id } = message {
+                self.player_id = Some(*player_id);
+            }
+        }
+
+        Ok(messages)
+    }
+}
+
+#[derive(Component)]
+pub struct Mirrored;
+
+fn send_client_input(
+    bindings: Res<Bindings>,
+    mut last_inputs: Local<InputState>,
+    kbd_input: Res<ButtonInput<KeyCode>>,
+    mut client: ResMut<Client>,
+) {
+    if client.spectator {
+        return;
+    }
+
+    // The client plays with the first keyboard layout
+    let Some(control_set) = bindings.keyboard.first() else {
+        return;
+    };
+
+    let inputs = control_set.read(&kbd_input, None);
+    if inputs == *last_inputs {
+        return;
+    }
+
+    *last_inputs = inputs;
+
+    if let Err(err) = client.send(&ClientMessage::Input(inputs)) {
+        warn!("Failed to send inputs: {}", err);
+    }
+}
+
+fn receive_snapshots(
+    mut commands: Commands,
+    mut client: ResMut<Client>,
+    mirrored: Query<Entity, With<Mirrored>>,
+    mut projections: Query<&mut OrthographicProjection>,
+    config: Res<GameConfig>,
+    mut exit: EventWriter<AppExit>,
+) {
+    let messages = match client.poll() {
+        Ok(messages) => messages,
+        Err(err) => {
+            error!("Lost connection to the server: {}", err);
+            exit.send(AppExit::error());
+            return;
+        }
+    };
+
+    let mut changed = false;
+
+    for message in messages {
+        changed |= match message {
+            ServerMessage::Snapshot(snapshot) => {
+                client.view = Some(*snapshot);
+                true
+            }
+            ServerMessage::Stream(frame) => stream::apply_frame(&mut client.view, frame),
+            ServerMessage::Welcome { .. } => false,
+        };
+    }
+
+    if !changed {
+        return;
+    }
+
+    if let Some(snapshot) = &client.view {
+        draw_snapshot(&mut commands, &mirrored, &mut projections, &config, snapshot);
+    }
+}
+
+/// Replaces whatever snapshot was drawn before with `snapshot`.
+pub fn draw_snapshot(
+    commands: &mut Commands,
+    mirrored: &Query<Entity, With<Mirrored>>,
+    projections: &mut Query<&mut OrthographicProjection>,
+    config: &GameConfig,
+    snapshot: &Snapshot,
+) {
+    for entity in mirrored {
+        commands.entity(entity).despawn();
+    }
+
+    // The arena may change size from one round to the next
+    for mut projection in projections {
+        map::fit_projection(&snapshot.scheme, config, &mut projection);
+    }
+
+    spawn_snapshot(commands, config, snapshot);
+}
+
+fn spawn_snapshot(commands: &mut Commands, config: &GameConfig, snapshot: &Snapshot) {
+    let mut spawn_sprite = |color: Color, size: Vec2, transform: Transform| {
+        commands.spawn((
+            Mirrored,
+            Sprite {
+                color,
+                custom_size: Some(size),
+                ..default()
+            },
+            transform,
+        ));
+    };
+
+    let grid = &snapshot.scheme;
+
+    for (cell, tile) in grid.cells() {
+        let center = cell.center(grid);
+
+        match tile {
+            map::legend::BLOCK => spawn_sprite(block::MAIN_COLOR, map::CELL_SIZE, center),
+            map::legend::BRICK => spawn_sprite(brick::MAIN_COLOR, config.brick.size.into(), center),
+            _ => {}
+        }
+    }
+
+    for (cell, kind) in &snapshot.powerups {
+        spawn_sprite(kind.to_bevy_color(), config.powerup.size.into(), cell.center(grid));
+    }
+
+    for (cell, color) in &snapshot.bombs {
+        spawn_sprite(color.to_bevy_color(), config.bomb.size.into(), cell.center(grid));
+    }
+
+    for (cell, color) in &snapshot.explosions {
+        spawn_sprite(color.to_bevy_color(), config.explosion.size.into(), cell.center(grid));
+    }
+
+    for player in &snapshot.players {
+        spawn_sprite(
+            player.color.to_bevy_color(),
+            config.player.size.into(),
+            Transform::from_xyz(player.position.0, player.position.1, 1.0),
+        );
+    }
+}
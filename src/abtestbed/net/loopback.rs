@@ -0,0 +1,138 @@
+//! Network inside the process for trying out rollback sessions, with a set
+//! delay and a share of datagrams that never arrive. It keeps a clock of its
+//! own that only moves when told to, so a run with the same seed loses the
+//! same datagrams every time.
+
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use bevy::prelude::*;
+use rand::{Rng, SeedableRng};
+use rand_chacha::ChaCha8Rng;
+
+use super::protocol::PeerMessage;
+use super::transport::Transport;
+
+#[derive(Clone, Copy, Default)]
+pub struct LoopbackSettings {
+    // Time from sending a datagram to its arrival
+    pub latency: Duration,
+    // Share of datagrams dropped, from 0 to 1
+    pub loss: f32,
+}
+
+#[derive(Clone)]
+pub struct LoopbackNetwork(Arc<Mutex<Network>>);
+
+struct Network {
+    settings: LoopbackSettings,
+    rng: ChaCha8Rng,
+    now: Duration,
+    in_flight: Vec<Datagram>,
+}
+
+struct Datagram {
+    arrives_at: Duration,
+    to: usize,
+    from: usize,
+    bytes: Vec<u8>,
+}
+
+impl LoopbackNetwork {
+    pub fn new(settings: LoopbackSettings, seed: u64) -> Self {
+        LoopbackNetwork(Arc::new(Mutex::new(Network {
+            settings,
+            rng: ChaCha8Rng::seed_from_u64(seed),
+            now: Duration::ZERO,
+            in_flight: Vec::new(),
+        })))
+    }
+
+    /// Transport of the peer with the given handle.
+    pub fn endpoint(&self, handle: usize) -> LoopbackTransport {
+        LoopbackTransport {
+            handle,
+            network: self.0.clone(),
+        }
+    }
+
+    pub fn advance(&self, elapsed: Duration) {
+        self.0.lock().unwrap().now += elapsed;
+    }
+}
+
+pub struct LoopbackTransport {
+    handle: usize,
+    network: Arc<Mutex<Network>>,
+}
+
+impl Transport for LoopbackTransport {
+    // Encoded like over a socket, so the messages go through what a real
+    // network would have them go through
+    fn send(&mut self, to: usize, message: &PeerMessage) {
+        let mut network = self.network.lock().unwrap();
+
+        let loss = network.settings.loss;
+        if network.rng.gen::<f32>() < loss {
+            return;
+        }
+
+        if let Ok(bytes) = bincode::serialize(message) {
+            let arrives_at = network.now + network.settings.latency;
+            network.in_flight.push(Datagram {
+                arrives_at,
+                to,
+                from: self.handle,
+                bytes,
+            });
+        }
+    }
+
+    fn receive(&mut self) -> Vec<(usize, PeerMessage)> {
+        let mut network = self.network.lock().unwrap();
+        let now = network.now;
+
+        let (arrived, in_flight) = std::mem::take(&mut network.in_flight)
+            .into_iter()
+            .partition(|datagram| datagram.to == self.handle && datagram.arrives_at <= now);
+        network.in_flight = in_flight;
+
+        arrived
+            .into_iter()
+            .filter_map(|datagram: Datagram| {
+                let message = bincode::deserialize(&datagram.bytes).ok()?;
+                Some((datagram.from, message))
+            })
+            .collect()
+    }
+}
+
+/// Runs the apps of every peer of a session in turns, one update each per
+/// step of the network clock, until all of them exited. Reports an error if
+/// any of them did. The apps are kept as they ended.
+pub fn run(peers: &mut [App], network: &LoopbackNetwork, step: Duration) -> AppExit {
+    // Done by `App::run` otherwise, which would never hand control back
+    for app in peers.iter_mut() {
+        app.finish();
+        app.cleanup();
+    }
+
+    let mut exits: Vec<Option<AppExit>> = vec![None; peers.len()];
+
+    while exits.iter().any(Option::is_none) {
+        for (app, exit) in peers.iter_mut().zip(&mut exits) {
+            if exit.is_none() {
+                app.update();
+                *exit = app.should_exit();
+            }
+        }
+
+        network.advance(step);
+    }
+
+    exits
+        .into_iter()
+        .flatten()
+        .find(AppExit::is_error)
+        .unwrap_or(AppExit::Success)
+}
@@ -0,0 +1,7 @@
+pub mod client;
+pub mod loopback;
+pub mod protocol;
+pub mod rollback;
+pub mod server;
+pub mod stream;
+pub mod transport;
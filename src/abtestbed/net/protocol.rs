@@ -0,0 +1,168 @@
+use std::io::{self, Read, Write};
+
+use mio::net::TcpStream;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use super::stream::StreamFrame;
+use crate::abtestbed::world::map::{Cell, Grid};
+use crate::abtestbed::world::player::{InputState, PlayerColor};
+use crate::abtestbed::world::powerup::PowerupKind;
+
+// Frames are prefixed with their length, anything bigger is a broken peer
+const MAX_FRAME_SIZE: usize = 1 << 20;
+const LENGTH_SIZE: usize = std::mem::size_of::<u32>();
+const READ_CHUNK_SIZE: usize = 4096;
+
+/// The first message of a client tells whether it plays or only watches.
+#[derive(Serialize, Deserialize)]
+pub enum ClientMessage {
+    Join,
+    Spectate,
+    Input(InputState),
+}
+
+#[derive(Serialize, Deserialize)]
+pub enum ServerMessage {
+    Welcome { player_id: Uuid },
+    Snapshot(Box<Snapshot>),
+    // Sent to spectators instead of snapshots
+    Stream(StreamFrame),
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Snapshot {
+    pub tick: u64,
+    pub scheme: Grid,
+    pub players: Vec<PlayerSnapshot>,
+    pub bombs: Vec<(Cell, PlayerColor)>,
+    pub explosions: Vec<(Cell, PlayerColor)>,
+    pub powerups: Vec<(Cell, PowerupKind)>,
+}
+
+/// Datagram between the peers of a rollback session. Inputs are sent again
+/// until the other peer acknowledges them, so a lost datagram costs nothing
+/// but the delay.
+#[derive(Serialize, Deserialize)]
+pub enum PeerMessage {
+    Inputs {
+        // Tick of the first input, the others follow in order
+        first_tick: u64,
+        inputs: Vec<InputState>,
+        // Every input of the receiver up to this tick has arrived
+        ack: u64,
+    },
+    Checksum {
+        tick: u64,
+        checksum: u64,
+    },
+}
+
+#[derive(Clone, PartialEq, Serialize, Deserialize)]
+pub struct PlayerSnapshot {
+    pub id: Uuid,
+    pub color: PlayerColor,
+    pub position: (f32, f32),
+}
+
+/// Non-blocking TCP stream exchanging length prefixed bincode frames.
+pub struct Connection {
+    stream: TcpStream,
+    inbox: Vec<u8>,
+    outbox: Vec<u8>,
+}
+
+impl Connection {
+    pub fn new(stream: TcpStream) -> Self {
+        Connection {
+            stream,
+            inbox: Vec::new(),
+            outbox: Vec::new(),
+        }
+    }
+
+    pub fn stream_mut(&mut self) -> &mut TcpStream {
+        &mut self.stream
+    }
+
+    pub fn queue<T: Serialize>(&mut self, message: &T) -> io::Result<()> {
+        let payload = bincode::serialize(message).map_err(invalid_data)?;
+
+        self.outbox
+            .extend_from_slice(&(payload.len() as u32).to_le_bytes());
+        self.outbox.extend_from_slice(&payload);
+
+        Ok(())
+    }
+
+    /// Writes as much of the queued data as the socket accepts right now.
+    pub fn flush(&mut self) -> io::Result<()> {
+        while !self.outbox.is_empty() {
+            match self.stream.write(&self.outbox) {
+                Ok(0) => return Err(io::ErrorKind::WriteZero.into()),
+                Ok(n) => {
+                    self.outbox.drain(..n);
+                }
+                Err(err) if would_block(&err) => break,
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+                Err(err) => return Err(err),
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Drains the socket and decodes every complete frame. A closed stream is
+    /// reported as `UnexpectedEof`.
+    pub fn receive<T: DeserializeOwned>(&mut self) -> io::Result<Vec<T>> {
+        let mut chunk = [0; READ_CHUNK_SIZE];
+
+        loop {
+            match self.stream.read(&mut chunk) {
+                Ok(0) => return Err(io::ErrorKind::UnexpectedEof.into()),
+                Ok(n) => self.inbox.extend_from_slice(&chunk[..n]),
+                Err(err) if would_block(&err) => break,
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+                Err(err) => return Err(err),
+            }
+        }
+
+        let mut messages = Vec::new();
+
+        while self.inbox.len() >= LENGTH_SIZE {
+            let mut length = [0; LENGTH_SIZE];
+            length.copy_from_slice(&self.inbox[..LENGTH_SIZE]);
+            let length = u32::from_le_bytes(length) as usize;
+
+            if length > MAX_FRAME_SIZE {
+                return Err(invalid_data(format!("frame of {} bytes", length)));
+            }
+
+            if self.inbox.len() < LENGTH_SIZE + length {
+                break;
+            }
+
+            let frame = &self.inbox[LENGTH_SIZE..LENGTH_SIZE + length];
+            messages.push(bincode::deserialize(frame).map_err(invalid_data)?);
+            self.inbox.drain(..LENGTH_SIZE + length);
+        }
+
+        Ok(messages)
+    }
+}
+
+// A stream that is still connecting reports `NotConnected`, it is retried later
+fn would_block(err: &io::Error) -> bool {
+    matches!(
+        err.kind(),
+        io::ErrorKind::WouldBlock | io::ErrorKind::NotConnected
+    )
+}
+
+fn invalid_data<E>(err: E) -> io::Error
+where
+    E: Into<Box<dyn std::error::Error + Send + Sync>>,
+{
+    io::Error::new(io::ErrorKind::InvalidData, err)
+}
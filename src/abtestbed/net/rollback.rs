@@ -0,0 +1,656 @@
+//! Peer-to-peer play with rollback. Every peer plays the whole world and the
+//! peers only tell each other their inputs. Inputs that have not arrived yet
+//! are predicted, and when one turns out different the world goes back to
+//! the snapshot of the tick before it and the ticks since are played again.
+//!
+//! The session steps the world itself rather than leaving it to the fixed
+//! loop of Bevy, and waits for the other peers once it got too far ahead of
+//! their inputs. Checksums of the world at ticks whose inputs every peer has
+//! are exchanged now and then to catch peers that drifted apart.
+
+use std::collections::{BTreeMap, VecDeque};
+use std::fmt;
+use std::time::Duration;
+
+use bevy::app::{FixedMain, RunFixedMainLoop, RunFixedMainLoopSystem};
+use bevy::prelude::*;
+use rand::{Rng, SeedableRng};
+use rand_chacha::ChaCha8Rng;
+use uuid::Uuid;
+
+use super::protocol::PeerMessage;
+use super::transport::Transport;
+use crate::abtestbed::config::GameConfig;
+use crate::abtestbed::headless;
+use crate::abtestbed::setup::SimTick;
+use crate::abtestbed::snapshot::WorldSnapshot;
+use crate::abtestbed::world::controls::Bindings;
+use crate::abtestbed::world::player::{Controls, InputState, PendingInputs, Roster};
+use crate::abtestbed::world::round::{GameState, MatchSettings};
+
+// Ticks played ahead of the inputs of the other peers before waiting for them
+const MAX_PREDICTION: u64 = 12;
+// Keeps the snapshot of the tick before the oldest prediction
+const SNAPSHOTS_KEPT: usize = MAX_PREDICTION as usize + 2;
+const MAX_INPUTS_PER_MESSAGE: usize = 64;
+// Own checksums waiting for those of peers that are behind
+const CHECKSUMS_KEPT: usize = 32;
+const CHECKSUM_PERIOD_SECS: f32 = 1.0;
+const DISCONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+
+// How often a random player changes direction, plants a bomb and sets one off
+const TURN_CHANCE: f64 = 0.1;
+const PLANT_CHANCE: f64 = 0.02;
+const DETONATE_CHANCE: f64 = 0.01;
+
+/// Plays the match with the `Session` resource, which must be inserted
+/// first. The players of the peers take the remote slots of the roster, in
+/// the order of their handles.
+pub struct RollbackPlugin;
+
+impl Plugin for RollbackPlugin {
+    fn build(&self, app: &mut App) {
+        // Peers start together rather than on a key press of each
+        app.world_mut()
+            .get_resource_or_insert_with(MatchSettings::default)
+            .auto_start = true;
+
+        app.add_systems(Startup, pause_fixed_loop)
+            .add_systems(Update, latch_local_input)
+            .add_systems(
+                RunFixedMainLoop,
+                run_session.in_set(RunFixedMainLoopSystem::BeforeFixedMainLoop),
+            );
+    }
+}
+
+pub enum LocalInputs {
+    // First keyboard layout of the bindings
+    Keyboard,
+    // Wanders about and plants bombs now and then, for peers nobody plays at
+    Random(Box<ChaCha8Rng>),
+}
+
+impl LocalInputs {
+    pub fn random(seed: u64) -> Self {
+        LocalInputs::Random(Box::new(ChaCha8Rng::seed_from_u64(seed)))
+    }
+}
+
+pub struct SessionSettings {
+    pub handle: usize,
+    pub peers: usize,
+    pub local_inputs: LocalInputs,
+    // Ends the session once every peer has the inputs of this many ticks
+    pub max_ticks: Option<u64>,
+    // Closes the app at the end, rather than leaving the last tick on screen
+    pub exit_when_over: bool,
+}
+
+#[derive(Resource)]
+pub struct Session {
+    settings: SessionSettings,
+    transport: Box<dyn Transport>,
+    phase: Phase,
+    // Player of every peer by handle, taken from the roster at the start
+    players: Vec<Uuid>,
+    peers: Vec<Peer>,
+    latched: InputState,
+    // One per tick played, the newest at the back
+    snapshots: VecDeque<WorldSnapshot>,
+    // Time owed to the world, played out in ticks
+    lag: Duration,
+    // Earliest tick played with a prediction that turned out wrong
+    rollback_from: Option<u64>,
+    // Every tick up to here has the inputs of all peers and was checked
+    confirmed: u64,
+    checksums: VecDeque<(u64, u64)>,
+    stats: Stats,
+}
+
+enum Phase {
+    Waiting,
+    Running,
+    Over,
+    Failed,
+}
+
+#[derive(Default)]
+struct Peer {
+    // From the first tick on, as far as they arrived without a gap
+    inputs: Vec<InputState>,
+    // Played with in the ticks past the known inputs
+    predicted: BTreeMap<u64, InputState>,
+    // Ticks of local inputs the peer has
+    acked: u64,
+    checksums: BTreeMap<u64, u64>,
+    heard: bool,
+    silence: Duration,
+}
+
+#[derive(Default)]
+struct Stats {
+    rollbacks: u64,
+    resimulated: u64,
+    stalls: u64,
+    checksums: u64,
+}
+
+#[derive(Debug)]
+pub enum SessionError {
+    Desync { handle: usize, tick: u64 },
+    Disconnected { handle: usize },
+}
+
+impl fmt::Display for SessionError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SessionError::Desync { handle, tick } => {
+                write!(f, "the world of peer {} differs at tick {}", handle, tick)
+            }
+            SessionError::Disconnected { handle } => {
+                write!(f, "peer {} stopped answering", handle)
+            }
+        }
+    }
+}
+
+impl std::error::Error for SessionError {}
+
+impl Peer {
+    fn known(&self) -> u64 {
+        self.inputs.len() as u64
+    }
+
+    // Without the input of the tick the player is taken to keep going the same
+    // way, without pressing anything new
+    fn input(&mut self, tick: u64) -> InputState {
+        if let Some(input) = self.inputs.get(tick as usize - 1) {
+            return *input;
+        }
+
+        let predicted = InputState {
+            plant_bomb: false,
+            detonate: false,
+            ..self.inputs.last().copied().unwrap_or_default()
+        };
+        self.predicted.insert(tick, predicted);
+
+        predicted
+    }
+}
+
+impl Session {
+    pub fn new(settings: SessionSettings, transport: Box<dyn Transport>) -> Self {
+        let peers = (0..settings.peers).map(|_| Peer::default()).collect();
+
+        Session {
+            settings,
+            transport,
+            phase: Phase::Waiting,
+            players: Vec::new(),
+            peers,
+            latched: InputState::default(),
+            snapshots: VecDeque::new(),
+            lag: Duration::ZERO,
+            rollback_from: None,
+            confirmed: 0,
+            checksums: VecDeque::new(),
+            stats: Stats::default(),
+        }
+    }
+
+    fn remotes(&self) -> impl Iterator<Item = usize> {
+        let handle = self.settings.handle;
+        (0..self.peers.len()).filter(move |peer| *peer != handle)
+    }
+
+    // Last tick whose inputs every peer has
+    fn known(&self) -> u64 {
+        self.peers.iter().map(Peer::known).min().unwrap_or_default()
+    }
+
+    fn receive(&mut self, elapsed: Duration) {
+        for peer in &mut self.peers {
+            peer.silence += elapsed;
+        }
+
+        for (from, message) in self.transport.receive() {
+            if from == self.settings.handle {
+                continue;
+            }
+            let Some(peer) = self.peers.get_mut(from) else {
+                continue;
+            };
+
+            peer.heard = true;
+            peer.silence = Duration::ZERO;
+
+            match message {
+                PeerMessage::Inputs {
+                    first_tick,
+                    inputs,
+                    ack,
+                } => {
+                    peer.acked = peer.acked.max(ack);
+
+                    for (tick, input) in (first_tick..).zip(inputs) {
+                        // Resent inputs are known already, and none is taken past a gap
+                        if tick <= peer.known() {
+                            continue;
+                        }
+                        if tick > peer.known() + 1 {
+                            break;
+                        }
+
+                        peer.inputs.push(input);
+
+                        let predicted = peer.predicted.remove(&tick);
+                        if predicted.is_some_and(|predicted| predicted != input) {
+                            let from = self.rollback_from.map_or(tick, |from| from.min(tick));
+                            self.rollback_from = Some(from);
+                        }
+                    }
+                }
+                PeerMessage::Checksum { tick, checksum } => {
+                    peer.checksums.insert(tick, checksum);
+                }
+            }
+        }
+    }
+
+    fn send(&mut self) {
+        let local = &self.peers[self.settings.handle];
+        let mut messages = Vec::new();
+
+        for to in self.remotes() {
+            let peer = &self.peers[to];
+            let inputs = local
+                .inputs
+                .iter()
+                .skip(peer.acked as usize)
+                .take(MAX_INPUTS_PER_MESSAGE)
+                .copied()
+                .collect();
+
+            let message = PeerMessage::Inputs {
+                first_tick: peer.acked + 1,
+                inputs,
+                ack: peer.known(),
+            };
+            messages.push((to, message));
+        }
+
+        for (to, message) in messages {
+            self.transport.send(to, &message);
+        }
+    }
+
+    // Once every peer was heard from and the match left the lobby, which
+    // happens in the same tick for all of them
+    fn start(&mut self, world: &mut World) {
+        let heard = self.remotes().all(|handle| self.peers[handle].heard);
+        if !heard || *world.resource::<State<GameState>>().get() == GameState::Lobby {
+            return;
+        }
+
+        self.players = world
+            .resource::<Roster>()
+            .slots
+            .iter()
+            .filter(|slot| matches!(slot.controls, Controls::Remote))
+            .map(|slot| slot.id)
+            .take(self.peers.len())
+            .collect();
+
+        self.snapshots.push_back(WorldSnapshot::capture(world));
+        self.phase = Phase::Running;
+    }
+
+    fn advance(&mut self, world: &mut World, elapsed: Duration) -> Result<(), SessionError> {
+        for handle in self.remotes() {
+            if self.peers[handle].silence >= DISCONNECT_TIMEOUT {
+                return Err(SessionError::Disconnected { handle });
+            }
+        }
+
+        if let Some(from) = self.rollback_from.take() {
+            self.roll_back(world, from);
+        }
+
+        let step = world.resource::<GameConfig>().tick_duration();
+        self.lag += elapsed;
+
+        while self.lag >= step {
+            let tick = world.resource::<SimTick>().0;
+            if tick - self.known() >= MAX_PREDICTION {
+                self.stats.stalls += 1;
+                self.lag = self.lag.min(step);
+                break;
+            }
+
+            let input = self.next_local_input();
+            self.peers[self.settings.handle].inputs.push(input);
+            self.play_tick(world);
+            self.lag -= step;
+        }
+
+        self.confirm(world);
+        self.compare_checksums()
+    }
+
+    fn next_local_input(&mut self) -> InputState {
+        match &mut self.settings.local_inputs {
+            LocalInputs::Keyboard => {
+                let input = self.latched;
+                self.latched.plant_bomb = false;
+                self.latched.detonate = false;
+                input
+            }
+            LocalInputs::Random(rng) => {
+                let last = self.peers[self.settings.handle].inputs.last();
+                wander(rng, last.copied().unwrap_or_default())
+            }
+        }
+    }
+
+    fn play_tick(&mut self, world: &mut World) {
+        let tick = world.resource::<SimTick>().0 + 1;
+        let inputs: Vec<(Uuid, InputState)> = self
+            .players
+            .iter()
+            .zip(&mut self.peers)
+            .map(|(id, peer)| (*id, peer.input(tick)))
+            .collect();
+
+        world.resource_mut::<PendingInputs>().by_id.extend(inputs);
+        world.run_schedule(FixedMain);
+        // Changes of state are applied right after the tick that asked for
+        // them, as when a frame holds a single tick, rather than with the next
+        // frame, whose start differs between the peers
+        let _ = world.try_run_schedule(StateTransition);
+
+        self.snapshots.push_back(WorldSnapshot::capture(world));
+        if self.snapshots.len() > SNAPSHOTS_KEPT {
+            self.snapshots.pop_front();
+        }
+    }
+
+    // Goes back to the end of the tick before `from` and plays up to the
+    // current tick again, with the inputs known by now
+    fn roll_back(&mut self, world: &mut World, from: u64) {
+        let tick = world.resource::<SimTick>().0;
+
+        while self
+            .snapshots
+            .back()
+            .is_some_and(|snapshot| snapshot.tick.0 >= from)
+        {
+            self.snapshots.pop_back();
+        }
+
+        match self.snapshots.back() {
+            Some(snapshot) => snapshot.restore(world),
+            None => return,
+        }
+
+        for _ in from..=tick {
+            self.play_tick(world);
+        }
+
+        self.stats.rollbacks += 1;
+        self.stats.resimulated += tick + 1 - from;
+    }
+
+    // Ticks that got every input are final: their checksums are sent, and
+    // the session ends at the first one that ends the match
+    fn confirm(&mut self, world: &mut World) {
+        let known = self.known();
+        let period = world
+            .resource::<GameConfig>()
+            .ticks(CHECKSUM_PERIOD_SECS)
+            .max(1);
+
+        while self.confirmed < known {
+            self.confirmed += 1;
+            let tick = self.confirmed;
+
+            let Some(snapshot) = self
+                .snapshots
+                .iter()
+                .find(|snapshot| snapshot.tick.0 == tick)
+            else {
+                continue;
+            };
+
+            let over = snapshot.state == GameState::MatchOver
+                || self
+                    .settings
+                    .max_ticks
+                    .is_some_and(|max_ticks| tick >= max_ticks);
+
+            // The last tick is checked too, whatever the period
+            if tick.is_multiple_of(period) || over {
+                let checksum = snapshot.checksum();
+                self.checksums.push_back((tick, checksum));
+                if self.checksums.len() > CHECKSUMS_KEPT {
+                    self.checksums.pop_front();
+                }
+
+                for to in (0..self.peers.len()).filter(|to| *to != self.settings.handle) {
+                    self.transport
+                        .send(to, &PeerMessage::Checksum { tick, checksum });
+                }
+            }
+
+            if over {
+                let snapshot = snapshot.clone();
+                self.report(world, &snapshot);
+                self.phase = Phase::Over;
+                return;
+            }
+        }
+    }
+
+    fn compare_checksums(&mut self) -> Result<(), SessionError> {
+        for (handle, peer) in self.peers.iter_mut().enumerate() {
+            let later = peer.checksums.split_off(&(self.confirmed + 1));
+            let checked = std::mem::replace(&mut peer.checksums, later);
+
+            for (tick, theirs) in checked {
+                let ours = self.checksums.iter().find(|(ours, _)| *ours == tick);
+                match ours {
+                    Some((_, ours)) if *ours != theirs => {
+                        return Err(SessionError::Desync { handle, tick });
+                    }
+                    Some(_) => self.stats.checksums += 1,
+                    None => {}
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    fn report(&self, world: &World, snapshot: &WorldSnapshot) {
+        let roster = world.resource::<Roster>();
+        let score = &snapshot.score;
+
+        if snapshot.state == GameState::MatchOver {
+            headless::print_champion(score, roster);
+        } else {
+            println!(
+                "Match stopped after {} ticks in round {}",
+                snapshot.tick.0, score.round
+            );
+        }
+        headless::print_score(score, roster);
+
+        println!(
+            "Peer {}: {} rollbacks played {} ticks again, stalled {} times, {} checksums matched",
+            self.settings.handle,
+            self.stats.rollbacks,
+            self.stats.resimulated,
+            self.stats.stalls,
+            self.stats.checksums
+        );
+    }
+
+    // Lingers after the end until the other peers have every local input,
+    // which they may still need to reach it
+    fn done(&self) -> bool {
+        self.remotes().all(|handle| {
+            let peer = &self.peers[handle];
+            peer.acked >= self.peers[self.settings.handle].known()
+                || peer.silence >= DISCONNECT_TIMEOUT
+        })
+    }
+}
+
+// Keeps going the same way for a while, like somebody holding a key
+fn wander(rng: &mut ChaCha8Rng, last: InputState) -> InputState {
+    let mut input = InputState {
+        plant_bomb: rng.gen_bool(PLANT_CHANCE),
+        detonate: rng.gen_bool(DETONATE_CHANCE),
+        ..last
+    };
+
+    if rng.gen_bool(TURN_CHANCE) {
+        let directions = [(0, 0), (1, 0), (-1, 0), (0, 1), (0, -1)];
+        let (horizontal, vertical) = directions[rng.gen_range(0..directions.len())];
+        input.horizontal_direction = horizontal;
+        input.vertical_direction = vertical;
+    }
+
+    input
+}
+
+// The session plays the ticks, the fixed loop of Bevy never gets any time
+fn pause_fixed_loop(mut time: ResMut<Time<Virtual>>) {
+    time.pause();
+}
+
+fn latch_local_input(
+    kbd_input: Res<ButtonInput<KeyCode>>,
+    bindings: Res<Bindings>,
+    mut session: ResMut<Session>,
+) {
+    if !matches!(session.settings.local_inputs, LocalInputs::Keyboard) {
+        return;
+    }
+    let Some(control_set) = bindings.keyboard.first() else {
+        return;
+    };
+
+    // Presses are kept until a tick takes them, frames may be shorter than ticks
+    let inputs = control_set.read(&kbd_input, None);
+    session.latched = InputState {
+        plant_bomb: session.latched.plant_bomb || inputs.plant_bomb,
+        detonate: session.latched.detonate || inputs.detonate,
+        ..inputs
+    };
+}
+
+fn run_session(world: &mut World) {
+    world.resource_scope(|world, mut session: Mut<Session>| {
+        let elapsed = world.resource::<Time<Real>>().delta();
+        session.receive(elapsed);
+
+        let result = match session.phase {
+            Phase::Waiting => {
+                session.start(world);
+                Ok(())
+            }
+            Phase::Running => session.advance(world, elapsed),
+            Phase::Over => {
+                if session.settings.exit_when_over && session.done() {
+                    world.send_event(AppExit::Success);
+                }
+                session.compare_checksums()
+            }
+            Phase::Failed => return,
+        };
+
+        if let Err(err) = result {
+            eprintln!("Rollback session failed: {}", err);
+            session.phase = Phase::Failed;
+            world.send_event(AppExit::error());
+            return;
+        }
+
+        session.send();
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::abtestbed::net::loopback::{self, LoopbackNetwork, LoopbackSettings};
+    use crate::abtestbed::setup::{GameRng, HeadlessSetupPlugin};
+    use crate::abtestbed::world::player::{RosterSettings, SlotKind};
+    use crate::abtestbed::world::WorldPlugin;
+
+    const PEERS: usize = 3;
+    const SEED: u64 = 11;
+    const MAX_TICKS: u64 = 300;
+
+    fn peer_app(handle: usize, network: &LoopbackNetwork) -> App {
+        let session = Session::new(
+            SessionSettings {
+                handle,
+                peers: PEERS,
+                local_inputs: LocalInputs::random(SEED + handle as u64),
+                max_ticks: Some(MAX_TICKS),
+                exit_when_over: true,
+            },
+            Box::new(network.endpoint(handle)),
+        );
+
+        let mut app = App::new();
+        app.insert_resource(GameConfig::default())
+            .insert_resource(GameRng::from_seed(SEED))
+            .insert_resource(RosterSettings {
+                slots: vec![SlotKind::Remote; PEERS],
+            })
+            .insert_resource(session)
+            .add_plugins(HeadlessSetupPlugin)
+            .add_plugins(RollbackPlugin)
+            .add_plugins(WorldPlugin);
+
+        app
+    }
+
+    #[test]
+    fn peers_agree_over_a_lossy_network() {
+        let settings = LoopbackSettings {
+            latency: Duration::from_millis(80),
+            loss: 0.2,
+        };
+        let network = LoopbackNetwork::new(settings, SEED);
+        let mut peers: Vec<App> = (0..PEERS)
+            .map(|handle| peer_app(handle, &network))
+            .collect();
+
+        let step = GameConfig::default().tick_duration();
+        let exit = loopback::run(&mut peers, &network, step);
+        assert!(!exit.is_error(), "a session failed");
+
+        let sessions: Vec<&Session> = peers
+            .iter()
+            .map(|app| app.world().resource::<Session>())
+            .collect();
+
+        for session in &sessions {
+            assert!(matches!(session.phase, Phase::Over));
+            assert!(session.stats.rollbacks > 0);
+            assert!(session.stats.checksums > 0);
+        }
+
+        let last_checksums: Vec<(u64, u64)> = sessions
+            .iter()
+            .map(|session| *session.checksums.back().unwrap())
+            .collect();
+        assert_eq!(last_checksums[0].0, MAX_TICKS);
+        assert!(last_checksums.iter().all(|last| *last == last_checksums[0]));
+    }
+}
@@ -0,0 +1,525 @@
+use std::collections::HashMap;
+use std::io;
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use bevy::prelude::*;
+use mio::net::TcpListener;
+use mio::{Events, Interest, Poll, Token};
+use uuid::Uuid;
+
+use crate::abtestbed::config::GameConfig;
+use crate::abtestbed::setup::GameRng;
+use crate::abtestbed::world::map;
+use crate::abtestbed::world::player::{
+    self, Controls, InputState, PendingInputs, Player, PlayerSlot, Roster,
+};
+use crate::abtestbed::world::round::GameState;
+
+use super::protocol::{ClientMessage, Connection, ServerMessage};
+use super::stream::{self, Stream, StreamPlugin};
+
+const LISTENER: Token = Token(0);
+const EVENTS_CAPACITY: usize = 128;
+
+pub struct ServerPlugin;
+
+impl Plugin for ServerPlugin {
+    fn build(&self, app: &mut App) {
+        if !app.is_plugin_added::<StreamPlugin>() {
+            app.add_plugins(StreamPlugin);
+        }
+
+        app.init_resource::<RemotePlayers>()
+            .init_resource::<Spectators>()
+            .add_systems(PreUpdate, receive_client_messages)
+            // After every tick of the frame was simulated
+            .add_systems(PostUpdate, broadcast_snapshot.after(stream::capture_stream));
+    }
+}
+
+// Clients are heard of with their first message, which tells whether they
+// play or watch
+pub enum ServerEvent {
+    Disconnected(Token),
+    Message(Token, ClientMessage),
+}
+
+/// Authoritative end of the connection, accepting any number of clients.
+#[derive(Resource)]
+pub struct Server {
+    poll: Poll,
+    events: Events,
+    listener: TcpListener,
+    connections: HashMap<Token, Connection>,
+    dropped: Vec<Token>,
+    next_token: usize,
+}
+
+impl Server {
+    pub fn bind(addr: SocketAddr) -> io::Result<Self> {
+        let poll = Poll::new()?;
+        let mut listener = TcpListener::bind(addr)?;
+        poll.registry()
+            .register(&mut listener, LISTENER, Interest::READABLE)?;
+
+        Ok(Server {
+            poll,
+            events: Events::with_capacity(EVENTS_CAPACITY),
+            listener,
+            connections: HashMap::new(),
+            dropped: Vec::new(),
+            next_token: LISTENER.0 + 1,
+        })
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+
+    /// Accepts pending connections and collects everything the clients sent
+    /// since the last call, without blocking.
+    pub fn poll(&mut self) -> io::Result<Vec<ServerEvent>> {
+        self.poll.poll(&mut self.events, Some(Duration::ZERO))?;
+
+        let ready: Vec<(Token, bool)> = self
+            .events
+            .iter()
+            .map(|event| (event.token(), event.is_readable()))
+            .collect();
+
+        let mut server_events = Vec::new();
+
+        for (token, readable) in ready {
+            if token == LISTENER {
+                self.accept()?;
+                continue;
+            }
+
+            let Some(connection) = self.connections.get_mut(&token) else {
+                continue;
+            };
+
+            let result = if readable {
+                connection.receive::<ClientMessage>()
+            } else {
+                connection.flush().map(|_| Vec::new())
+            };
+
+            match result {
+                Ok(messages) => server_events.extend(
+                    messages
+                        .into_iter()
+                        .map(|message| ServerEvent::Message(token, message)),
+                ),
+                Err(_) => self.disconnect(token),
+            }
+        }
+
+        server_events.extend(self.dropped.drain(..).map(ServerEvent::Disconnected));
+
+        Ok(server_events)
+    }
+
+    pub fn send(&mut self, token: Token, message: &ServerMessage) {
+        if let Some(connection) = self.connections.get_mut(&token) {
+            if connection
+                .queue(message)
+                .and_then(|_| connection.flush())
+                .is_err()
+            {
+                self.disconnect(token);
+            }
+        }
+    }
+
+    fn accept(&mut self) -> io::Result<()> {
+        loop {
+            let (stream, _) = match self.listener.accept() {
+                Ok(accepted) => accepted,
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock => return Ok(()),
+                Err(err) => return Err(err),
+            };
+
+            let token = Token(self.next_token);
+            self.next_token += 1;
+
+            let mut connection = Connection::new(stream);
+            self.poll.registry().register(
+                connection.stream_mut(),
+                token,
+                Interest::READABLE | Interest::WRITABLE,
+            )?;
+            connection.stream_mut().set_nodelay(true)?;
+
+            self.connections.insert(token, connection);
+        }
+    }
+
+    /// Drops a connection without reporting it as a disconnected player.
+    pub fn close(&mut self, token: Token) {
+        if let Some(mut connection) = self.connections.remove(&token) {
+            let _ = self.poll.registry().deregister(connection.stream_mut());
+        }
+    }
+
+    // Dropped connections are reported by the next poll
+    fn disconnect(&mut self, token: Token) {
+        if let Some(mut connection) = self.connections.remove(&token) {
+            let _ = self.poll.registry().deregister(connection.stream_mut());
+            self.dropped.push(token);
+        }
+    }
+}
+
+#[derive(Resource, Default)]
+struct RemotePlayers {
+    ids: HashMap<Token, Uuid>,
+}
+
+/// Read-only connections, sent the stream of the match instead of snapshots.
+#[derive(Resource, Default)]
+struct Spectators {
+    // Joined in this frame, still waiting for a keyframe
+    joining: Vec<Token>,
+    watching: Vec<Token>,
+}
+
+impl Spectators {
+    fn contains(&self, token: Token) -> bool {
+        self.joining.contains(&token) || self.watching.contains(&token)
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+fn receive_client_messages(
+    mut commands: Commands,
+    mut server: ResMut<Server>,
+    mut remote_players: ResMut<RemotePlayers>,
+    mut spectators: ResMut<Spectators>,
+    mut roster: ResMut<Roster>,
+    mut pending_inputs: ResMut<PendingInputs>,
+    mut rng: ResMut<GameRng>,
+    players: Query<(Entity, &Player)>,
+    config: Res<GameConfig>,
+    map_state: Res<map::MapState>,
+    state: Res<State<GameState>>,
+) {
+    let server_events = match server.poll() {
+        Ok(server_events) => server_events,
+        Err(err) => {
+            warn!("Failed to poll connections: {}", err);
+            return;
+        }
+    };
+
+    for server_event in server_events {
+        match server_event {
+            ServerEvent::Message(token, ClientMessage::Spectate) => {
+                if !remote_players.ids.contains_key(&token) && !spectators.contains(token) {
+                    spectators.joining.push(token);
+                }
+            }
+            ServerEvent::Message(token, ClientMessage::Join) => {
+                if remote_players.ids.contains_key(&token) || spectators.contains(token) {
+                    continue;
+                }
+
+                // A remote slot of the roster nobody joined yet, or a new one
+                let reserved = roster.slots.iter().position(|slot| {
+                    matches!(slot.controls, Controls::Remote)
+                        && !remote_players.ids.values().any(|id| *id == slot.id)
+                });
+
+                let index = match (reserved, roster.free_color()) {
+                    (Some(index), _) => index,
+                    (None, Some(color)) => {
+                        roster
+                            .slots
+                            .push(PlayerSlot::remote(rng.next_uuid(), color));
+                        roster.slots.len() - 1
+                    }
+                    (None, None) => {
+                        warn!("Turning a client away, the roster is full");
+                        server.close(token);
+                        continue;
+                    }
+                };
+
+                let slot = &roster.slots[index];
+                let player_id = slot.id;
+
+                // Players joining a round that is under way jump right in
+                let in_round = matches!(state.get(), GameState::RoundStarting | GameState::InRound);
+                if in_round && !players.iter().any(|(_, player)| player.id == player_id) {
+                    let cell = map_state.start_cells[index % map_state.start_cells.len()];
+                    player::spawn_player(&mut commands, &config, &map_state, slot, cell);
+                }
+
+                remote_players.ids.insert(token, player_id);
+                server.send(token, &ServerMessage::Welcome { player_id });
+            }
+            // Spectators have no player, their inputs go nowhere
+            ServerEvent::Message(token, ClientMessage::Input(inputs)) => {
+                let Some(player_id) = remote_players.ids.get(&token) else {
+                    continue;
+                };
+
+                // Keep a bomb or trigger press that no tick took yet
+                let pending = pending_inputs.by_id.entry(*player_id).or_default();
+                *pending = InputState {
+                    plant_bomb: pending.plant_bomb || inputs.plant_bomb,
+                    detonate: pending.detonate || inputs.detonate,
+                    ..inputs
+                };
+            }
+            ServerEvent::Disconnected(token) => {
+                spectators.joining.retain(|spectator| *spectator != token);
+                spectators.watching.retain(|spectator| *spectator != token);
+
+                let Some(player_id) = remote_players.ids.remove(&token) else {
+                    continue;
+                };
+
+                // The slot stays in the roster, free for the next client to join
+                for (entity, player) in &players {
+                    if player.id == player_id {
+                        commands.entity(entity).despawn();
+                    }
+                }
+            }
+        }
+    }
+}
+
+fn broadcast_snapshot(
+    mut server: ResMut<Server>,
+    remote_players: Res<RemotePlayers>,
+    mut spectators: ResMut<Spectators>,
+    stream: Res<Stream>,
+) {
+    let Some(snapshot) = stream.snapshot() else {
+        return;
+    };
+
+    let message = ServerMessage::Snapshot(Box::new(snapshot.clone()));
+    for token in remote_players.ids.keys() {
+        server.send(*token, &message);
+    }
+
+    if let Some(delta) = stream.delta() {
+        let message = ServerMessage::Stream(delta);
+        for token in &spectators.watching {
+            server.send(*token, &message);
+        }
+    }
+
+    // The deltas of the frames to come build on the arena as it is now
+    if let Some(keyframe) = stream.keyframe() {
+        let message = ServerMessage::Stream(keyframe);
+        for token in std::mem::take(&mut spectators.joining) {
+            server.send(token, &message);
+            spectators.watching.push(token);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::abtestbed::net::client::Client;
+    use crate::abtestbed::setup::HeadlessSetupPlugin;
+    use crate::abtestbed::world::player::{RosterSettings, SlotKind};
+    use crate::abtestbed::world::round::MatchSettings;
+    use crate::abtestbed::world::WorldPlugin;
+
+    const CLIENTS: usize = 3;
+    // Updates to wait for the sockets, a millisecond apart
+    const MAX_UPDATES: usize = 2000;
+
+    fn server_app() -> (App, SocketAddr) {
+        let server = Server::bind("127.0.0.1:0".parse().unwrap()).unwrap();
+        let addr = server.local_addr().unwrap();
+
+        let mut app = App::new();
+        app.insert_resource(GameConfig::default())
+            .insert_resource(GameRng::from_seed(7))
+            .insert_resource(MatchSettings {
+                auto_start: true,
+                ..default()
+            })
+            .insert_resource(RosterSettings {
+                slots: vec![SlotKind::Remote; CLIENTS],
+            })
+            .insert_resource(server)
+            .add_plugins(HeadlessSetupPlugin)
+            .add_plugins(ServerPlugin)
+            .add_plugins(WorldPlugin);
+        app.finish();
+        app.cleanup();
+
+        (app, addr)
+    }
+
+    // One update of the server, and what every client received since the last
+    fn update(app: &mut App, clients: &mut [Client]) -> Vec<(usize, ServerMessage)> {
+        std::thread::sleep(Duration::from_millis(1));
+        app.update();
+
+        let mut messages = Vec::new();
+        for (index, client) in clients.iter_mut().enumerate() {
+            messages.extend(
+                client
+                    .poll()
+                    .unwrap()
+                    .into_iter()
+                    .map(|message| (index, message)),
+            );
+        }
+
+        messages
+    }
+
+    fn steering(index: usize) -> InputState {
+        InputState {
+            horizontal_direction: if index.is_multiple_of(2) { 1 } else { -1 },
+            vertical_direction: index as i8 - 1,
+            ..default()
+        }
+    }
+
+    fn steered_by(app: &mut App, id: Uuid, index: usize) -> bool {
+        let expected = steering(index);
+
+        app.world_mut()
+            .query::<&Player>()
+            .iter(app.world())
+            .any(|player| player.id == id && player.inputs == expected)
+    }
+
+    #[test]
+    fn serves_several_clients() {
+        let (mut app, addr) = server_app();
+        let mut clients: Vec<Client> = (0..CLIENTS)
+            .map(|_| Client::connect(addr).unwrap())
+            .collect();
+
+        // Every client is welcomed as a player of its own, then sent snapshots
+        let mut welcomes = [None; CLIENTS];
+        let mut snapshots = [0; CLIENTS];
+
+        for _ in 0..MAX_UPDATES {
+            for (index, message) in update(&mut app, &mut clients) {
+                match message {
+                    ServerMessage::Welcome { player_id } => welcomes[index] = Some(player_id),
+                    ServerMessage::Snapshot(_) => snapshots[index] += 1,
+                    ServerMessage::Stream(_) => panic!("a player was sent the spectator stream"),
+                }
+            }
+
+            if welcomes.iter().all(Option::is_some) && snapshots.iter().all(|count| *count > 0) {
+                break;
+            }
+        }
+
+        assert!(
+            welcomes.iter().all(Option::is_some),
+            "a client was never welcomed"
+        );
+        assert!(
+            snapshots.iter().all(|count| *count > 0),
+            "a client got no snapshot"
+        );
+
+        let ids: Vec<Uuid> = welcomes.into_iter().flatten().collect();
+        for (index, id) in ids.iter().enumerate() {
+            assert!(
+                !ids[..index].contains(id),
+                "two clients got the same player"
+            );
+            assert_eq!(clients[index].player_id, Some(*id));
+        }
+
+        // Each client steers its own player, in a way no other client does
+        for (index, client) in clients.iter_mut().enumerate() {
+            client.send(&ClientMessage::Input(steering(index))).unwrap();
+        }
+
+        let mut steered = false;
+        for _ in 0..MAX_UPDATES {
+            update(&mut app, &mut clients);
+
+            steered = ids
+                .iter()
+                .enumerate()
+                .all(|(index, id)| steered_by(&mut app, *id, index));
+            if steered {
+                break;
+            }
+        }
+
+        assert!(steered, "inputs did not reach the players of their clients");
+    }
+
+    // A client joining after another one left
+    fn rejoin(app: &mut App, addr: SocketAddr) -> Client {
+        let mut client = Client::connect(addr).unwrap();
+
+        for _ in 0..MAX_UPDATES {
+            update(app, std::slice::from_mut(&mut client));
+            if client.player_id.is_some() {
+                break;
+            }
+        }
+
+        client
+    }
+
+    fn has_player(app: &mut App, id: Uuid) -> bool {
+        app.world_mut()
+            .query::<&Player>()
+            .iter(app.world())
+            .any(|player| player.id == id)
+    }
+
+    #[test]
+    fn keeps_the_slot_of_a_client_that_left() {
+        let (mut app, addr) = server_app();
+
+        let client = rejoin(&mut app, addr);
+        let id = client.player_id.expect("the client was never welcomed");
+
+        // Its player takes part in the round that starts
+        for _ in 0..MAX_UPDATES {
+            update(&mut app, &mut []);
+            if has_player(&mut app, id) {
+                break;
+            }
+        }
+        assert!(
+            has_player(&mut app, id),
+            "the player of the client never spawned"
+        );
+
+        drop(client);
+        for _ in 0..MAX_UPDATES {
+            update(&mut app, &mut []);
+            if !has_player(&mut app, id) {
+                break;
+            }
+        }
+
+        assert!(
+            !has_player(&mut app, id),
+            "the player of a gone client stayed"
+        );
+        assert_eq!(app.world().resource::<Roster>().slots.len(), CLIENTS);
+
+        let client = rejoin(&mut app, addr);
+        assert_eq!(
+            client.player_id,
+            Some(id),
+            "the freed slot was not handed out again"
+        );
+    }
+}
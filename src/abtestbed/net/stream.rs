@@ -0,0 +1,639 @@
+//! Live stream of a match for spectators: one keyframe with the whole arena,
+//! then only what changed from one snapshot to the next. The server sends it
+//! to its spectators, and the same frames can be written to a file to be
+//! watched later.
+//!
+//! A stream file is `MAGIC`, the format version as a little-endian `u32` and
+//! the tick rate as a little-endian `f32`, followed by every frame as its
+//! length in a little-endian `u32` and the bincode-encoded `StreamFrame`.
+
+use std::collections::VecDeque;
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::Path;
+use std::time::Duration;
+
+use bevy::prelude::*;
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use super::client::{self, Mirrored};
+use super::protocol::{PlayerSnapshot, Snapshot};
+use crate::abtestbed::config::GameConfig;
+use crate::abtestbed::setup::SimTick;
+use crate::abtestbed::world::bomb::Bomb;
+use crate::abtestbed::world::explosion::Explosion;
+use crate::abtestbed::world::map::{self, Cell, Grid};
+use crate::abtestbed::world::player::{Player, PlayerColor};
+use crate::abtestbed::world::powerup::{Powerup, PowerupKind};
+
+pub const VERSION: u32 = 1;
+const MAGIC: [u8; 4] = *b"ABST";
+const HEADER_SIZE: usize = MAGIC.len() + std::mem::size_of::<u32>() + std::mem::size_of::<f32>();
+const LENGTH_SIZE: usize = std::mem::size_of::<u32>();
+
+/// Captures a snapshot of the arena every frame, once every tick of the
+/// frame was simulated, and the changes since the one before.
+pub struct StreamPlugin;
+
+impl Plugin for StreamPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<Stream>()
+            .add_systems(PostUpdate, capture_stream);
+    }
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub enum StreamFrame {
+    // Starts the stream, or starts it over
+    Keyframe(Box<Snapshot>),
+    Delta(Box<Delta>),
+}
+
+impl StreamFrame {
+    pub fn tick(&self) -> u64 {
+        match self {
+            StreamFrame::Keyframe(snapshot) => snapshot.tick,
+            StreamFrame::Delta(delta) => delta.tick,
+        }
+    }
+}
+
+/// Everything that changed between two snapshots.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Delta {
+    pub tick: u64,
+    // The whole map when the arena changed size, and the tiles that changed
+    // otherwise
+    pub scheme: Option<Grid>,
+    pub tiles: Vec<(Cell, u8)>,
+    // Players who joined or moved, and those who left
+    pub players: Vec<PlayerSnapshot>,
+    pub players_gone: Vec<Uuid>,
+    pub bombs: Changes<(Cell, PlayerColor)>,
+    pub explosions: Changes<(Cell, PlayerColor)>,
+    pub powerups: Changes<(Cell, PowerupKind)>,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Changes<T> {
+    pub spawned: Vec<T>,
+    pub despawned: Vec<T>,
+}
+
+impl<T: Clone + PartialEq> Changes<T> {
+    fn between(old: &[T], new: &[T]) -> Self {
+        let mut despawned = old.to_vec();
+        let mut spawned = Vec::new();
+
+        // Two bombs may share a cell for a moment, each counts on its own
+        for item in new {
+            match despawned.iter().position(|old_item| old_item == item) {
+                Some(index) => {
+                    despawned.swap_remove(index);
+                }
+                None => spawned.push(item.clone()),
+            }
+        }
+
+        Changes { spawned, despawned }
+    }
+
+    fn apply(&self, items: &mut Vec<T>) {
+        for item in &self.despawned {
+            if let Some(index) = items.iter().position(|old_item| old_item == item) {
+                items.swap_remove(index);
+            }
+        }
+
+        items.extend(self.spawned.iter().cloned());
+    }
+
+    fn is_empty(&self) -> bool {
+        self.spawned.is_empty() && self.despawned.is_empty()
+    }
+}
+
+impl Snapshot {
+    /// Changes that turn this snapshot into `next`, none if they are the same.
+    pub fn delta(&self, next: &Snapshot) -> Option<Delta> {
+        let resized = self.scheme.width() != next.scheme.width()
+            || self.scheme.height() != next.scheme.height();
+
+        let tiles = if resized {
+            Vec::new()
+        } else {
+            self.scheme
+                .cells()
+                .zip(next.scheme.cells())
+                .filter(|((_, old_tile), (_, tile))| old_tile != tile)
+                .map(|(_, changed)| changed)
+                .collect()
+        };
+
+        let delta = Delta {
+            tick: next.tick,
+            scheme: resized.then(|| next.scheme.clone()),
+            tiles,
+            players: next
+                .players
+                .iter()
+                .filter(|player| !self.players.contains(player))
+                .cloned()
+                .collect(),
+            players_gone: self
+                .players
+                .iter()
+                .filter(|old| !next.players.iter().any(|player| player.id == old.id))
+                .map(|old| old.id)
+                .collect(),
+            bombs: Changes::between(&self.bombs, &next.bombs),
+            explosions: Changes::between(&self.explosions, &next.explosions),
+            powerups: Changes::between(&self.powerups, &next.powerups),
+        };
+
+        let unchanged = delta.tick == self.tick
+            && delta.scheme.is_none()
+            && delta.tiles.is_empty()
+            && delta.players.is_empty()
+            && delta.players_gone.is_empty()
+            && delta.bombs.is_empty()
+            && delta.explosions.is_empty()
+            && delta.powerups.is_empty();
+
+        (!unchanged).then_some(delta)
+    }
+
+    pub fn apply(&mut self, delta: &Delta) {
+        self.tick = delta.tick;
+
+        if let Some(scheme) = &delta.scheme {
+            self.scheme = scheme.clone();
+        }
+        for (cell, tile) in &delta.tiles {
+            self.scheme.set(*cell, *tile);
+        }
+
+        self.players
+            .retain(|player| !delta.players_gone.contains(&player.id));
+        for player in &delta.players {
+            match self.players.iter_mut().find(|old| old.id == player.id) {
+                Some(old) => *old = player.clone(),
+                None => self.players.push(player.clone()),
+            }
+        }
+
+        delta.bombs.apply(&mut self.bombs);
+        delta.explosions.apply(&mut self.explosions);
+        delta.powerups.apply(&mut self.powerups);
+    }
+}
+
+/// Snapshot of the current frame and what changed since the last one.
+#[derive(Resource, Default)]
+pub struct Stream {
+    snapshot: Option<Snapshot>,
+    delta: Option<Delta>,
+}
+
+impl Stream {
+    pub fn snapshot(&self) -> Option<&Snapshot> {
+        self.snapshot.as_ref()
+    }
+
+    /// Whole arena, for whoever starts watching in this frame.
+    pub fn keyframe(&self) -> Option<StreamFrame> {
+        let snapshot = self.snapshot.clone()?;
+        Some(StreamFrame::Keyframe(Box::new(snapshot)))
+    }
+
+    /// Changes of this frame, for whoever got every frame before.
+    pub fn delta(&self) -> Option<StreamFrame> {
+        let delta = self.delta.clone()?;
+        Some(StreamFrame::Delta(Box::new(delta)))
+    }
+}
+
+pub fn capture_stream(
+    mut stream: ResMut<Stream>,
+    tick: Res<SimTick>,
+    map_state: Res<map::MapState>,
+    players: Query<(&Player, &Transform)>,
+    bombs: Query<&Bomb>,
+    explosions: Query<&Explosion>,
+    powerups: Query<&Powerup>,
+) {
+    let snapshot = Snapshot {
+        tick: tick.0,
+        scheme: map_state.scheme.clone(),
+        players: players
+            .iter()
+            .map(|(player, transform)| PlayerSnapshot {
+                id: player.id,
+                color: player.color,
+                position: (transform.translation.x, transform.translation.y),
+            })
+            .collect(),
+        bombs: bombs
+            .iter()
+            .map(|bomb| (bomb.cell, bomb.player_color))
+            .collect(),
+        explosions: explosions
+            .iter()
+            .map(|explosion| (explosion.cell, explosion.player_color))
+            .collect(),
+        powerups: powerups
+            .iter()
+            .map(|powerup| (powerup.cell, powerup.kind))
+            .collect(),
+    };
+
+    stream.delta = stream
+        .snapshot
+        .as_ref()
+        .and_then(|last| last.delta(&snapshot));
+    stream.snapshot = Some(snapshot);
+}
+
+#[derive(Debug)]
+pub enum StreamError {
+    Io(io::Error),
+    Encoding(bincode::Error),
+    NotAStream,
+    UnsupportedVersion(u32),
+}
+
+impl fmt::Display for StreamError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            StreamError::Io(err) => write!(f, "{}", err),
+            StreamError::Encoding(err) => write!(f, "malformed stream: {}", err),
+            StreamError::NotAStream => write!(f, "not a stream file"),
+            StreamError::UnsupportedVersion(version) => write!(
+                f,
+                "stream format version {} is not supported, expected {}",
+                version, VERSION
+            ),
+        }
+    }
+}
+
+impl std::error::Error for StreamError {}
+
+impl From<io::Error> for StreamError {
+    fn from(err: io::Error) -> Self {
+        StreamError::Io(err)
+    }
+}
+
+impl From<bincode::Error> for StreamError {
+    fn from(err: bincode::Error) -> Self {
+        StreamError::Encoding(err)
+    }
+}
+
+/// Stream file being written, one frame at a time, so it can be watched up
+/// to the last frame even if the app never exits cleanly.
+#[derive(Resource)]
+pub struct StreamWriter {
+    file: File,
+    started: bool,
+}
+
+impl StreamWriter {
+    pub fn create<P: AsRef<Path>>(path: P, fps: f32) -> io::Result<Self> {
+        let mut file = File::create(path)?;
+
+        let mut header = MAGIC.to_vec();
+        header.extend_from_slice(&VERSION.to_le_bytes());
+        header.extend_from_slice(&fps.to_le_bytes());
+        file.write_all(&header)?;
+
+        Ok(StreamWriter {
+            file,
+            started: false,
+        })
+    }
+
+    pub fn write(&mut self, frame: &StreamFrame) -> Result<(), StreamError> {
+        let payload = bincode::serialize(frame)?;
+
+        let mut bytes = (payload.len() as u32).to_le_bytes().to_vec();
+        bytes.extend(payload);
+        self.file.write_all(&bytes)?;
+
+        Ok(())
+    }
+}
+
+/// Writes the stream to the file of the `StreamWriter` resource.
+pub struct StreamRecordPlugin;
+
+impl Plugin for StreamRecordPlugin {
+    fn build(&self, app: &mut App) {
+        if !app.is_plugin_added::<StreamPlugin>() {
+            app.add_plugins(StreamPlugin);
+        }
+
+        app.add_systems(
+            PostUpdate,
+            record_stream
+                .after(capture_stream)
+                .run_if(resource_exists::<StreamWriter>),
+        );
+    }
+}
+
+fn record_stream(mut commands: Commands, stream: Res<Stream>, mut writer: ResMut<StreamWriter>) {
+    let frame = if writer.started {
+        stream.delta()
+    } else {
+        stream.keyframe()
+    };
+
+    let Some(frame) = frame else {
+        return;
+    };
+
+    // Every later frame builds on the lost one, the rest is worth nothing
+    if let Err(err) = writer.write(&frame) {
+        error!("Failed to write the stream, recording stopped: {}", err);
+        commands.remove_resource::<StreamWriter>();
+        return;
+    }
+
+    writer.started = true;
+}
+
+/// Frames of a stream file.
+pub struct Recording {
+    pub fps: f32,
+    pub frames: Vec<StreamFrame>,
+}
+
+impl Recording {
+    /// Reads a stream file. A last frame cut short, as it is when the app
+    /// writing it was killed, is left out.
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, StreamError> {
+        let bytes = fs::read(path)?;
+
+        if bytes.len() < HEADER_SIZE || bytes[..4] != MAGIC {
+            return Err(StreamError::NotAStream);
+        }
+
+        let version = u32::from_le_bytes([bytes[4], bytes[5], bytes[6], bytes[7]]);
+        if version != VERSION {
+            return Err(StreamError::UnsupportedVersion(version));
+        }
+
+        let fps = f32::from_le_bytes([bytes[8], bytes[9], bytes[10], bytes[11]]);
+
+        let mut frames = Vec::new();
+        let mut rest = &bytes[HEADER_SIZE..];
+
+        while rest.len() >= LENGTH_SIZE {
+            let length = u32::from_le_bytes([rest[0], rest[1], rest[2], rest[3]]) as usize;
+            let Some(frame) = rest.get(LENGTH_SIZE..LENGTH_SIZE + length) else {
+                break;
+            };
+
+            frames.push(bincode::deserialize(frame)?);
+            rest = &rest[LENGTH_SIZE + length..];
+        }
+
+        Ok(Recording { fps, frames })
+    }
+}
+
+/// Plays a stream file at the speed it was recorded at, drawn like a client
+/// draws the snapshots of the server.
+pub struct ViewerPlugin {
+    pub recording: Recording,
+}
+
+impl Plugin for ViewerPlugin {
+    fn build(&self, app: &mut App) {
+        app.insert_resource(Viewer {
+            fps: self.recording.fps,
+            frames: self.recording.frames.iter().cloned().collect(),
+            first_tick: self.recording.frames.first().map(StreamFrame::tick),
+            started_at: None,
+            view: None,
+        })
+        .add_systems(Update, play_stream);
+    }
+}
+
+#[derive(Resource)]
+struct Viewer {
+    fps: f32,
+    frames: VecDeque<StreamFrame>,
+    first_tick: Option<u64>,
+    // App time of the first update, however long the window took to open
+    started_at: Option<Duration>,
+    view: Option<Snapshot>,
+}
+
+fn play_stream(
+    mut commands: Commands,
+    time: Res<Time>,
+    mut viewer: ResMut<Viewer>,
+    mirrored: Query<Entity, With<Mirrored>>,
+    mut projections: Query<&mut OrthographicProjection>,
+    config: Res<GameConfig>,
+) {
+    let Some(first_tick) = viewer.first_tick else {
+        return;
+    };
+
+    let started_at = *viewer.started_at.get_or_insert(time.elapsed());
+    let played = (time.elapsed() - started_at).as_secs_f32();
+    let tick = first_tick + (played * viewer.fps) as u64;
+    let mut changed = false;
+
+    while viewer
+        .frames
+        .front()
+        .is_some_and(|frame| frame.tick() <= tick)
+    {
+        let Some(frame) = viewer.frames.pop_front() else {
+            break;
+        };
+
+        changed |= apply_frame(&mut viewer.view, frame);
+    }
+
+    if !changed {
+        return;
+    }
+
+    if let Some(snapshot) = &viewer.view {
+        client::draw_snapshot(
+            &mut commands,
+            &mirrored,
+            &mut projections,
+            &config,
+            snapshot,
+        );
+    }
+}
+
+/// Brings the view up to date with a frame, and tells if it changed. A delta
+/// is of no use before the keyframe it builds on.
+pub fn apply_frame(view: &mut Option<Snapshot>, frame: StreamFrame) -> bool {
+    match (view, frame) {
+        (view, StreamFrame::Keyframe(snapshot)) => {
+            *view = Some(*snapshot);
+            true
+        }
+        (Some(snapshot), StreamFrame::Delta(delta)) => {
+            snapshot.apply(&delta);
+            true
+        }
+        (None, StreamFrame::Delta(_)) => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::abtestbed::world::map::legend;
+
+    fn player(id: u128, color: PlayerColor, position: (f32, f32)) -> PlayerSnapshot {
+        PlayerSnapshot {
+            id: Uuid::from_u128(id),
+            color,
+            position,
+        }
+    }
+
+    // A few ticks of a match, with joins, leaves, shared cells and a resize
+    fn snapshots() -> Vec<Snapshot> {
+        let start = Snapshot {
+            tick: 1,
+            scheme: Grid::new(5, 5),
+            players: vec![
+                player(1, PlayerColor::White, (0.0, 0.0)),
+                player(2, PlayerColor::Black, (40.0, 0.0)),
+            ],
+            bombs: Vec::new(),
+            explosions: Vec::new(),
+            powerups: Vec::new(),
+        };
+
+        let mut moved = start.clone();
+        moved.tick = 2;
+        moved.scheme.set(Cell(2, 2), legend::BRICK);
+        moved.players[0].position = (10.0, 0.0);
+        moved.bombs.push((Cell(0, 0), PlayerColor::White));
+
+        let mut blown = moved.clone();
+        blown.tick = 3;
+        blown.scheme.set(Cell(2, 2), legend::EMPTY);
+        blown.players.remove(1);
+        blown
+            .players
+            .push(player(3, PlayerColor::Red, (80.0, 40.0)));
+        blown.bombs.push((Cell(0, 0), PlayerColor::Black));
+        blown.explosions = vec![
+            (Cell(1, 0), PlayerColor::White),
+            (Cell(2, 0), PlayerColor::White),
+        ];
+        blown.powerups.push((Cell(2, 2), PowerupKind::Flame));
+
+        let mut resized = blown.clone();
+        resized.tick = 4;
+        resized.scheme = Grid::new(7, 5);
+        resized.bombs.swap_remove(0);
+        resized.explosions.clear();
+
+        let mut idle = resized.clone();
+        idle.tick = 5;
+
+        vec![start, moved, blown, resized, idle]
+    }
+
+    fn same_items<T: PartialEq>(a: &[T], b: &[T]) -> bool {
+        let count = |items: &[T], item: &T| items.iter().filter(|other| *other == item).count();
+        a.len() == b.len() && a.iter().all(|item| count(a, item) == count(b, item))
+    }
+
+    fn assert_same(view: &Snapshot, expected: &Snapshot) {
+        assert_eq!(view.tick, expected.tick);
+        assert_eq!(view.scheme, expected.scheme);
+        assert!(same_items(&view.players, &expected.players));
+        assert!(same_items(&view.bombs, &expected.bombs));
+        assert!(same_items(&view.explosions, &expected.explosions));
+        assert!(same_items(&view.powerups, &expected.powerups));
+    }
+
+    fn frames(snapshots: &[Snapshot]) -> Vec<StreamFrame> {
+        let deltas = snapshots
+            .windows(2)
+            .map(|pair| StreamFrame::Delta(Box::new(pair[0].delta(&pair[1]).unwrap())));
+
+        std::iter::once(StreamFrame::Keyframe(Box::new(snapshots[0].clone())))
+            .chain(deltas)
+            .collect()
+    }
+
+    #[test]
+    fn deltas_rebuild_the_last_snapshot() {
+        let snapshots = snapshots();
+        let mut view = snapshots[0].clone();
+
+        for pair in snapshots.windows(2) {
+            view.apply(&pair[0].delta(&pair[1]).unwrap());
+            assert_same(&view, &pair[1]);
+        }
+
+        assert!(view.delta(&view.clone()).is_none());
+    }
+
+    #[test]
+    fn leaves_out_a_truncated_last_frame() {
+        let path = std::env::temp_dir().join(format!("abtestbed-{}.stream", std::process::id()));
+        let frames = frames(&snapshots());
+
+        let mut writer = StreamWriter::create(&path, 60.0).unwrap();
+        for frame in &frames {
+            writer.write(frame).unwrap();
+        }
+        let length = writer.file.metadata().unwrap().len();
+        writer.file.set_len(length - 4).unwrap();
+        drop(writer);
+
+        let recording = Recording::load(&path);
+        fs::remove_file(&path).unwrap();
+
+        let recording = recording.unwrap();
+        assert_eq!(recording.fps, 60.0);
+        let ticks: Vec<u64> = recording.frames.iter().map(StreamFrame::tick).collect();
+        let expected: Vec<u64> = frames[..frames.len() - 1]
+            .iter()
+            .map(StreamFrame::tick)
+            .collect();
+        assert_eq!(ticks, expected);
+    }
+
+    #[test]
+    fn joins_at_the_next_keyframe() {
+        let snapshots = snapshots();
+        let mut view = None;
+
+        // Tuned in after the keyframe, the deltas until the next one are lost
+        for frame in frames(&snapshots).into_iter().skip(1).take(2) {
+            assert!(!apply_frame(&mut view, frame));
+        }
+        assert!(view.is_none());
+
+        let keyframe = StreamFrame::Keyframe(Box::new(snapshots[2].clone()));
+        assert!(apply_frame(&mut view, keyframe));
+
+        for frame in frames(&snapshots).into_iter().skip(3) {
+            assert!(apply_frame(&mut view, frame));
+        }
+        assert_same(view.as_ref().unwrap(), snapshots.last().unwrap());
+    }
+}
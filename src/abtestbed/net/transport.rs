@@ -0,0 +1,65 @@
+use std::io;
+use std::net::{SocketAddr, UdpSocket};
+
+use super::protocol::PeerMessage;
+
+// Far above the size of any message a peer sends
+const MAX_DATAGRAM_SIZE: usize = 2048;
+
+/// Unreliable, unordered delivery of messages between the peers of a
+/// session, who are told apart by their handle: their index in the list of
+/// peers every one of them was given.
+pub trait Transport: Send + Sync {
+    fn send(&mut self, to: usize, message: &PeerMessage);
+
+    /// Every message that arrived since the last call, with its sender.
+    fn receive(&mut self) -> Vec<(usize, PeerMessage)>;
+}
+
+/// Non-blocking UDP socket bound to the address of the local peer.
+pub struct UdpTransport {
+    socket: UdpSocket,
+    peers: Vec<SocketAddr>,
+}
+
+impl UdpTransport {
+    pub fn bind(peers: Vec<SocketAddr>, handle: usize) -> io::Result<Self> {
+        let socket = UdpSocket::bind(peers[handle])?;
+        socket.set_nonblocking(true)?;
+
+        Ok(UdpTransport { socket, peers })
+    }
+}
+
+impl Transport for UdpTransport {
+    // Datagrams get lost in the network too, one that cannot be sent is no different
+    fn send(&mut self, to: usize, message: &PeerMessage) {
+        if let Ok(bytes) = bincode::serialize(message) {
+            let _ = self.socket.send_to(&bytes, self.peers[to]);
+        }
+    }
+
+    fn receive(&mut self) -> Vec<(usize, PeerMessage)> {
+        let mut buffer = [0; MAX_DATAGRAM_SIZE];
+        let mut messages = Vec::new();
+
+        loop {
+            let (size, addr) = match self.socket.recv_from(&mut buffer) {
+                Ok(received) => received,
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock => break,
+                // Such as a peer that is not up yet refusing an earlier datagram
+                Err(_) => continue,
+            };
+
+            let Some(from) = self.peers.iter().position(|peer| *peer == addr) else {
+                continue;
+            };
+
+            if let Ok(message) = bincode::deserialize(&buffer[..size]) {
+                messages.push((from, message));
+            }
+        }
+
+        messages
+    }
+}
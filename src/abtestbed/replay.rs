@@ -0,0 +1,525 @@
+//! Recording of a match as its starting conditions plus every tick's inputs,
+//! and headless playback of such a recording.
+//!
+//! A replay file is `MAGIC`, the format version as a little-endian `u32` and
+//! the bincode-encoded `Replay`. Once a second of play the recording stores
+//! a hash of the world, which playback recomputes to catch divergence.
+
+use std::fmt;
+use std::fs;
+use std::hash::Hasher;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use bevy::prelude::*;
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use super::config::GameConfig;
+use super::headless;
+use super::setup::{self, SimTick};
+use super::snapshot::WorldSnapshot;
+use super::world::external;
+use super::world::map::{InitialMap, MapState};
+use super::world::player::{
+    self, InputState, PendingInputs, Player, PlayerColor, PlayerSlot, Roster, RosterSettings,
+};
+use super::world::round::{GameState, MatchSettings};
+
+pub const VERSION: u32 = 16;
+const MAGIC: [u8; 4] = *b"ABRP";
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Replay {
+    pub seed: u64,
+    // Tick the match started after, which the timers of the world count from
+    pub start_tick: u64,
+    // Rules the match was played with, applied before the plugins are built
+    pub config: GameConfig,
+    pub map_state: MapState,
+    pub roster: Vec<(Uuid, PlayerColor)>,
+    pub wins_to_win: u8,
+    pub ticks: Vec<ReplayTick>,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct ReplayTick {
+    pub inputs: Vec<(Uuid, InputState)>,
+    pub hash: Option<u64>,
+}
+
+#[derive(Debug)]
+pub enum ReplayError {
+    Io(io::Error),
+    Encoding(bincode::Error),
+    NotAReplay,
+    UnsupportedVersion(u32),
+}
+
+impl fmt::Display for ReplayError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ReplayError::Io(err) => write!(f, "{}", err),
+            ReplayError::Encoding(err) => write!(f, "malformed replay: {}", err),
+            ReplayError::NotAReplay => write!(f, "not a replay file"),
+            ReplayError::UnsupportedVersion(version) => write!(
+                f,
+                "replay format version {} is not supported, expected {}",
+                version, VERSION
+            ),
+        }
+    }
+}
+
+impl std::error::Error for ReplayError {}
+
+impl From<io::Error> for ReplayError {
+    fn from(err: io::Error) -> Self {
+        ReplayError::Io(err)
+    }
+}
+
+impl From<bincode::Error> for ReplayError {
+    fn from(err: bincode::Error) -> Self {
+        ReplayError::Encoding(err)
+    }
+}
+
+impl Replay {
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, ReplayError> {
+        let bytes = fs::read(path)?;
+
+        if bytes.len() < 8 || bytes[..4] != MAGIC {
+            return Err(ReplayError::NotAReplay);
+        }
+
+        let version = u32::from_le_bytes([bytes[4], bytes[5], bytes[6], bytes[7]]);
+        if version != VERSION {
+            return Err(ReplayError::UnsupportedVersion(version));
+        }
+
+        Ok(bincode::deserialize(&bytes[8..])?)
+    }
+
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<(), ReplayError> {
+        let mut bytes = MAGIC.to_vec();
+        bytes.extend_from_slice(&VERSION.to_le_bytes());
+        bytes.extend(bincode::serialize(self)?);
+
+        fs::write(path, bytes)?;
+        Ok(())
+    }
+}
+
+/// Records the first match played, from leaving the lobby until it is over,
+/// and writes the replay to `path` when the app exits.
+pub struct RecordPlugin {
+    pub seed: u64,
+    pub path: PathBuf,
+}
+
+impl Plugin for RecordPlugin {
+    fn build(&self, app: &mut App) {
+        app.insert_resource(Recorder {
+            seed: self.seed,
+            path: self.path.clone(),
+            replay: None,
+            stopped: false,
+        })
+        .add_systems(OnExit(GameState::Lobby), start_recording)
+        .add_systems(OnEnter(GameState::MatchOver), stop_recording)
+        .add_systems(FixedUpdate, record_inputs.in_set(player::InputsTaken))
+        .add_systems(FixedLast, record_hash)
+        .add_systems(Last, save_recording.after(headless::watch_tick_limit));
+    }
+}
+
+#[derive(Resource)]
+struct Recorder {
+    seed: u64,
+    path: PathBuf,
+    replay: Option<Replay>,
+    stopped: bool,
+}
+
+impl Recorder {
+    fn recording(&mut self) -> Option<&mut Replay> {
+        if self.stopped {
+            None
+        } else {
+            self.replay.as_mut()
+        }
+    }
+}
+
+fn start_recording(
+    mut recorder: ResMut<Recorder>,
+    initial_map: Res<InitialMap>,
+    roster: Res<Roster>,
+    settings: Res<MatchSettings>,
+    config: Res<GameConfig>,
+    tick: Res<SimTick>,
+    mut rng: ResMut<setup::GameRng>,
+) {
+    if recorder.replay.is_some() {
+        return;
+    }
+
+    // The random stream restarts with the match so that playback sees the same one
+    *rng = setup::GameRng::from_seed(recorder.seed);
+
+    recorder.replay = Some(Replay {
+        seed: recorder.seed,
+        start_tick: tick.0,
+        config: config.clone(),
+        map_state: initial_map.0.clone(),
+        roster: roster.slots.iter().map(|slot| (slot.id, slot.color)).collect(),
+        wins_to_win: settings.wins_to_win,
+        ticks: Vec::new(),
+    });
+}
+
+fn stop_recording(mut recorder: ResMut<Recorder>) {
+    recorder.stopped = true;
+}
+
+fn record_inputs(mut recorder: ResMut<Recorder>, players: Query<&Player>) {
+    let Some(replay) = recorder.recording() else {
+        return;
+    };
+
+    let mut inputs: Vec<(Uuid, InputState)> = players.iter().map(|p| (p.id, p.inputs)).collect();
+    inputs.sort_by_key(|(id, _)| *id);
+
+    replay.ticks.push(ReplayTick {
+        inputs,
+        hash: None,
+    });
+}
+
+fn record_hash(world: &mut World) {
+    let fps = world.resource::<GameConfig>().fps.round().max(1.0) as u64;
+    let Some(tick) = world
+        .resource_mut::<Recorder>()
+        .recording()
+        .map(|replay| replay.ticks.len() as u64)
+    else {
+        return;
+    };
+
+    // A world hash is stored once a second of play
+    if tick == 0 || !(tick - 1).is_multiple_of(fps) {
+        return;
+    }
+
+    let hash = WorldSnapshot::capture(world).checksum();
+    if let Some(last_tick) = world
+        .resource_mut::<Recorder>()
+        .recording()
+        .and_then(|replay| replay.ticks.last_mut())
+    {
+        last_tick.hash = Some(hash);
+    }
+}
+
+fn save_recording(recorder: Res<Recorder>, exit_events: EventReader<AppExit>) {
+    if exit_events.is_empty() {
+        return;
+    }
+
+    let path = recorder.path.display();
+
+    let Some(replay) = &recorder.replay else {
+        eprintln!("{}: no match was started, nothing recorded", path);
+        return;
+    };
+
+    match replay.save(&recorder.path) {
+        Ok(()) => println!("Recorded {} ticks to {}", replay.ticks.len(), path),
+        Err(err) => eprintln!("{}: {}", path, err),
+    }
+}
+
+/// Plays a replay back headless, feeding the recorded inputs to the players
+/// and exiting with an error as soon as the world stops matching it.
+pub struct PlaybackPlugin {
+    pub replay: Replay,
+}
+
+impl Plugin for PlaybackPlugin {
+    fn build(&self, app: &mut App) {
+        let replay = self.replay.clone();
+
+        // Recorded players take their inputs from the replay, like remote ones do from the network
+        let slots = replay
+            .roster
+            .iter()
+            .map(|(id, color)| PlayerSlot::remote(*id, *color))
+            .collect();
+
+        app.insert_resource(replay.map_state.clone())
+            .insert_resource(Roster { slots })
+            .insert_resource(RosterSettings { slots: Vec::new() })
+            .insert_resource(MatchSettings {
+                wins_to_win: replay.wins_to_win,
+                auto_start: true,
+            })
+            .insert_resource(Playback {
+                replay,
+                playing: false,
+                tick: 0,
+                hashes_checked: 0,
+            })
+            .add_systems(OnExit(GameState::Lobby), start_playback)
+            .add_systems(
+                FixedUpdate,
+                feed_inputs
+                    .after(external::drive_external_bots)
+                    .before(player::update_player_input),
+            )
+            .add_systems(FixedLast, check_hash);
+    }
+}
+
+#[derive(Resource)]
+struct Playback {
+    replay: Replay,
+    playing: bool,
+    tick: usize,
+    hashes_checked: usize,
+}
+
+fn start_playback(
+    mut playback: ResMut<Playback>,
+    mut tick: ResMut<SimTick>,
+    mut rng: ResMut<setup::GameRng>,
+    mut exit: EventWriter<AppExit>,
+) {
+    if playback.tick > 0 {
+        return;
+    }
+
+    if playback.replay.ticks.is_empty() {
+        println!("Replay is empty");
+        exit.send(AppExit::Success);
+        return;
+    }
+
+    // However long the lobby took, the timers count from where the recording's did
+    *tick = SimTick(playback.replay.start_tick);
+    *rng = setup::GameRng::from_seed(playback.replay.seed);
+    playback.playing = true;
+}
+
+fn feed_inputs(playback: Res<Playback>, mut pending_inputs: ResMut<PendingInputs>) {
+    if !playback.playing {
+        return;
+    }
+
+    pending_inputs
+        .by_id
+        .extend(playback.replay.ticks[playback.tick].inputs.iter().copied());
+}
+
+fn check_hash(world: &mut World) {
+    let expected = {
+        let playback = world.resource::<Playback>();
+        if !playback.playing {
+            return;
+        }
+        playback.replay.ticks[playback.tick].hash
+    };
+
+    let actual = expected.map(|_| WorldSnapshot::capture(world).checksum());
+    let mut playback = world.resource_mut::<Playback>();
+
+    if let (Some(expected), Some(actual)) = (expected, actual) {
+        if actual != expected {
+            eprintln!(
+                "Replay diverged at tick {}: expected world hash {:016x}, got {:016x}",
+                playback.tick, expected, actual
+            );
+            playback.playing = false;
+            world.send_event(AppExit::error());
+            return;
+        }
+
+        playback.hashes_checked += 1;
+    }
+
+    playback.tick += 1;
+
+    if playback.tick == playback.replay.ticks.len() {
+        println!(
+            "Replay of {} ticks matched all {} world hashes",
+            playback.tick, playback.hashes_checked
+        );
+        playback.playing = false;
+        world.send_event(AppExit::Success);
+    }
+}
+
+/// FNV-1a, whose output unlike `DefaultHasher` stays the same across builds.
+pub struct StableHasher(u64);
+
+impl std::default::Default for StableHasher {
+    fn default() -> Self {
+        StableHasher(0xcbf29ce484222325)
+    }
+}
+
+impl Hasher for StableHasher {
+    fn finish(&self) -> u64 {
+        self.0
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        for byte in bytes {
+            self.0 ^= *byte as u64;
+            self.0 = self.0.wrapping_mul(0x100000001b3);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::abtestbed::headless::HeadlessPlugin;
+    use crate::abtestbed::setup::HeadlessSetupPlugin;
+    use crate::abtestbed::world::ai::Difficulty;
+    use crate::abtestbed::world::player::SlotKind;
+    use crate::abtestbed::world::WorldPlugin;
+
+    // Long enough for a round to be decided and another started
+    const TICKS: u64 = 1200;
+
+    fn temp_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("abtestbed-{}-{}.abrp", name, std::process::id()))
+    }
+
+    fn record(seed: u64, path: &Path) -> Replay {
+        let mut app = App::new();
+        app.insert_resource(GameConfig::default())
+            .insert_resource(setup::GameRng::from_seed(seed))
+            .insert_resource(RosterSettings {
+                slots: vec![
+                    SlotKind::Bot(Difficulty::Hard),
+                    SlotKind::Bot(Difficulty::Hard),
+                    SlotKind::Bot(Difficulty::Normal),
+                    SlotKind::Bot(Difficulty::Easy),
+                ],
+            })
+            .add_plugins(HeadlessSetupPlugin)
+            .add_plugins(HeadlessPlugin { max_ticks: TICKS })
+            .add_plugins(RecordPlugin {
+                seed,
+                path: path.to_path_buf(),
+            })
+            .add_plugins(WorldPlugin);
+        app.finish();
+        app.cleanup();
+
+        while app.should_exit().is_none() {
+            app.update();
+        }
+
+        let replay = Replay::load(path).unwrap();
+        fs::remove_file(path).unwrap();
+        replay
+    }
+
+    fn play_back(replay: Replay) -> AppExit {
+        let mut app = App::new();
+        app.insert_resource(replay.config.clone())
+            .add_plugins(HeadlessSetupPlugin)
+            .add_plugins(PlaybackPlugin { replay })
+            .add_plugins(WorldPlugin);
+        app.finish();
+        app.cleanup();
+
+        loop {
+            app.update();
+            if let Some(exit) = app.should_exit() {
+                return exit;
+            }
+        }
+    }
+
+    fn tiny_replay() -> Replay {
+        Replay {
+            seed: 3,
+            start_tick: 1,
+            config: GameConfig::default(),
+            map_state: MapState::default(),
+            roster: vec![(Uuid::from_u128(1), PlayerColor::White)],
+            wins_to_win: 2,
+            ticks: vec![ReplayTick {
+                inputs: vec![(Uuid::from_u128(1), InputState::default())],
+                hash: Some(42),
+            }],
+        }
+    }
+
+    #[test]
+    fn save_and_load_round_trip() {
+        let path = temp_path("round-trip");
+        let replay = tiny_replay();
+
+        replay.save(&path).unwrap();
+        let loaded = Replay::load(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(
+            bincode::serialize(&loaded).unwrap(),
+            bincode::serialize(&replay).unwrap()
+        );
+    }
+
+    #[test]
+    fn rejects_other_files() {
+        let path = temp_path("bad-header");
+
+        fs::write(&path, b"RIFF\x0e\0\0\0").unwrap();
+        let not_a_replay = Replay::load(&path);
+
+        let mut bytes = MAGIC.to_vec();
+        bytes.extend_from_slice(&(VERSION + 1).to_le_bytes());
+        bytes.extend(bincode::serialize(&tiny_replay()).unwrap());
+        fs::write(&path, bytes).unwrap();
+        let newer = Replay::load(&path);
+
+        fs::write(&path, b"ABR").unwrap();
+        let truncated = Replay::load(&path);
+        fs::remove_file(&path).unwrap();
+
+        assert!(matches!(not_a_replay, Err(ReplayError::NotAReplay)));
+        assert!(matches!(newer, Err(ReplayError::UnsupportedVersion(v)) if v == VERSION + 1));
+        assert!(matches!(truncated, Err(ReplayError::NotAReplay)));
+    }
+
+    #[test]
+    fn playback_matches_the_recording() {
+        let replay = record(7, &temp_path("matches"));
+        assert!(replay.ticks.iter().any(|tick| tick.hash.is_some()));
+
+        assert_eq!(play_back(replay), AppExit::Success);
+    }
+
+    #[test]
+    fn playback_catches_a_changed_input() {
+        let mut replay = record(7, &temp_path("changed"));
+
+        // The first player drops a bomb wherever it happens to be early in the first round
+        let first = replay.roster[0].0;
+        for tick in &mut replay.ticks[60..120] {
+            for (id, inputs) in &mut tick.inputs {
+                if *id == first {
+                    inputs.plant_bomb = true;
+                    inputs.horizontal_direction = 1;
+                }
+            }
+        }
+
+        assert!(play_back(replay).is_error());
+    }
+}
@@ -1,7 +1,18 @@
+use std::time::Duration;
+
+use bevy::app::ScheduleRunnerPlugin;
+use bevy::ecs::schedule::ExecutorKind;
+use bevy::input::InputPlugin;
 use bevy::prelude::*;
+use bevy::state::app::StatesPlugin;
+use bevy::time::TimeUpdateStrategy;
 use bevy_rapier2d::prelude::*;
+use rand::{Rng, SeedableRng};
+use rand_chacha::ChaCha8Rng;
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
 
-const FPS: f32 = 40.0;
+use super::config::GameConfig;
 
 pub mod collision {
 
@@ -19,24 +30,200 @@ pub mod collision {
     }
 }
 
+/// Source of every random decision in the world, so a seed reproduces a match.
+#[derive(Resource, Clone, Serialize, Deserialize)]
+pub struct GameRng(pub ChaCha8Rng);
+
+impl GameRng {
+    pub fn from_seed(seed: u64) -> Self {
+        GameRng(ChaCha8Rng::seed_from_u64(seed))
+    }
+
+    pub fn next_uuid(&mut self) -> Uuid {
+        uuid::Builder::from_random_bytes(self.0.gen()).into_uuid()
+    }
+}
+
+impl std::default::Default for GameRng {
+    fn default() -> Self {
+        GameRng(ChaCha8Rng::from_entropy())
+    }
+}
+
+/// Fixed steps simulated since the app started, the clock every gameplay
+/// timer is kept in. Never goes back, not even between rounds.
+#[derive(
+    Resource, Debug, Default, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize,
+)]
+pub struct SimTick(pub u64);
+
 pub struct SetupPlugin;
 
 impl Plugin for SetupPlugin {
     fn build(&self, app: &mut App) {
         app.add_plugins(DefaultPlugins)
-            .add_plugins(RapierPhysicsPlugin::<NoUserData>::pixels_per_meter(100.0))
+            .add_plugins(PhysicsPlugin)
             .add_plugins(RapierDebugRenderPlugin::default())
-            .add_systems(Startup, setup_system)
-            .insert_resource(TimestepMode::Fixed {
-                dt: 1.0 / FPS,
-                substeps: 1,
-            });
+            .add_systems(Startup, spawn_camera);
     }
 }
 
-fn setup_system(mut commands: Commands, mut rapier_config: Query<&mut RapierConfiguration>) {
-    commands.spawn(Camera2d::default());
+/// Runs the world without a window or renderer, one fixed step per update and
+/// as fast as the CPU allows. Systems run on a single thread and every two
+/// touching the same data are ordered, so the same seed and inputs always
+/// produce the same match, in any process.
+pub struct HeadlessSetupPlugin;
 
-    let mut rapier_config = rapier_config.single_mut();
+impl Plugin for HeadlessSetupPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_plugins(MinimalPlugins.set(ScheduleRunnerPlugin::run_loop(Duration::ZERO)))
+            .add_plugins((
+                TransformPlugin,
+                HierarchyPlugin,
+                InputPlugin,
+                StatesPlugin,
+                AssetPlugin::default(),
+            ))
+            .init_asset::<Mesh>()
+            .add_plugins(PhysicsPlugin);
+
+        let tick_duration = app.world().resource::<GameConfig>().tick_duration();
+        app.insert_resource(TimeUpdateStrategy::ManualDuration(tick_duration));
+    }
+
+    // Runs once every plugin added its schedules, state transition ones included
+    fn finish(&self, app: &mut App) {
+        let mut schedules = app.world_mut().resource_mut::<Schedules>();
+
+        for (_, schedule) in schedules.iter_mut() {
+            schedule.set_executor_kind(ExecutorKind::SingleThreaded);
+        }
+    }
+}
+
+struct PhysicsPlugin;
+
+impl Plugin for PhysicsPlugin {
+    fn build(&self, app: &mut App) {
+        // Loaded from `--config` before the plugins are added, or the defaults
+        app.init_resource::<GameConfig>();
+        let config = app.world().resource::<GameConfig>().clone();
+
+        // Gameplay runs in `FixedUpdate`, so the physics step after it, once per tick
+        app.add_plugins(
+            RapierPhysicsPlugin::<NoUserData>::pixels_per_meter(100.0)
+                .in_schedule(FixedPostUpdate),
+        )
+        .init_resource::<GameRng>()
+        .init_resource::<SimTick>()
+        .insert_resource(Time::<Fixed>::from_duration(config.tick_duration()))
+        .add_systems(Startup, setup_physics)
+        .add_systems(FixedFirst, (advance_tick, apply_state_transitions).chain())
+        .insert_resource(TimestepMode::Fixed {
+            dt: 1.0 / config.fps,
+            substeps: 1,
+        });
+    }
+}
+
+fn advance_tick(mut tick: ResMut<SimTick>) {
+    tick.0 += 1;
+}
+
+// Bevy only applies state changes once a frame, which may hold several ticks
+// or none. Applying them with every tick keeps the rounds in step with the
+// simulation whatever the frame rate is.
+fn apply_state_transitions(world: &mut World) {
+    let _ = world.try_run_schedule(StateTransition);
+}
+
+fn spawn_camera(mut commands: Commands) {
+    commands.spawn(Camera2d);
+}
+
+fn setup_physics(mut rapier: Query<(&mut RapierConfiguration, &mut RapierContext)>) {
+    let (mut rapier_config, mut rapier_context) = rapier.single_mut();
     rapier_config.gravity = Vec2::ZERO;
+    // Contacts are solved from scratch every step. Impulses carried over from
+    // the step before are not part of a snapshot, and a restored world must
+    // play out exactly like the one it was taken from.
+    rapier_context.integration_parameters.warmstart_coefficient = 0.0;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::abtestbed::snapshot::WorldSnapshot;
+    use crate::abtestbed::world::ai::Difficulty;
+    use crate::abtestbed::world::player::{
+        Controls, InputState, PendingInputs, Roster, RosterSettings, SlotKind,
+    };
+    use crate::abtestbed::world::round::MatchSettings;
+    use crate::abtestbed::world::WorldPlugin;
+
+    const TICKS: u64 = 600;
+
+    // Remote players walk a square and plant a bomb at every corner
+    fn scripted_input(tick: u64, index: usize) -> InputState {
+        let directions = [(1, 0), (0, -1), (-1, 0), (0, 1)];
+        let leg = (tick / 40) as usize + index;
+        let (horizontal_direction, vertical_direction) = directions[leg % directions.len()];
+
+        InputState {
+            horizontal_direction,
+            vertical_direction,
+            plant_bomb: tick.is_multiple_of(40),
+            ..default()
+        }
+    }
+
+    // Everything a snapshot holds once the match ran for `TICKS` ticks
+    fn play(seed: u64) -> Vec<u8> {
+        let mut app = App::new();
+        app.insert_resource(GameConfig::default())
+            .insert_resource(GameRng::from_seed(seed))
+            .insert_resource(MatchSettings {
+                auto_start: true,
+                ..default()
+            })
+            .insert_resource(RosterSettings {
+                slots: vec![
+                    SlotKind::Remote,
+                    SlotKind::Remote,
+                    SlotKind::Bot(Difficulty::Hard),
+                    SlotKind::Bot(Difficulty::Easy),
+                ],
+            })
+            .add_plugins(HeadlessSetupPlugin)
+            .add_plugins(WorldPlugin);
+        app.finish();
+        app.cleanup();
+
+        while app.world().resource::<SimTick>().0 < TICKS {
+            let tick = app.world().resource::<SimTick>().0;
+            let remotes: Vec<Uuid> = app
+                .world()
+                .resource::<Roster>()
+                .slots
+                .iter()
+                .filter(|slot| matches!(slot.controls, Controls::Remote))
+                .map(|slot| slot.id)
+                .collect();
+
+            let mut pending_inputs = app.world_mut().resource_mut::<PendingInputs>();
+            for (index, id) in remotes.into_iter().enumerate() {
+                pending_inputs.by_id.insert(id, scripted_input(tick, index));
+            }
+
+            app.update();
+        }
+
+        WorldSnapshot::capture(app.world_mut()).to_bytes().unwrap()
+    }
+
+    #[test]
+    fn same_seed_plays_the_same_match() {
+        assert!(play(3) == play(3), "two runs of seed 3 drifted apart");
+        assert!(play(3) != play(4), "seeds 3 and 4 played the same match");
+    }
 }
@@ -0,0 +1,391 @@
+//! Snapshot of a match at the end of a tick, holding everything needed to
+//! resume it: the arena, every player, bomb, fire and item, the round and
+//! the random streams.
+//!
+//! Timers are kept as the tick they run out at next to the tick of the
+//! snapshot, so a bomb has `explode_at - tick` ticks of fuse left. Blocks,
+//! bricks and borders follow from the map and are not stored. What the tick
+//! left for the next one, a change of state and the bombs caught by fire, is
+//! stored along with the world.
+
+use std::collections::HashSet;
+use std::fmt;
+use std::hash::Hasher;
+
+use bevy::prelude::*;
+use bevy_rapier2d::prelude::*;
+use serde::{Deserialize, Serialize};
+
+use super::config::GameConfig;
+use super::replay::StableHasher;
+use super::setup::{GameRng, SimTick};
+use super::world::ai::Bot;
+use super::world::bomb::{self, Bomb, PlantedBombs};
+use super::world::collision::ExplosionHitBomb;
+use super::world::disease::Curse;
+use super::world::explosion::{self, Explosion};
+use super::world::map::{self, MapState};
+use super::world::player::{self, Player};
+use super::world::powerup::{self, Powerup};
+use super::world::round::{GameState, MatchScore, PhaseEnd, RoundEntity};
+use super::world::sudden_death::SuddenDeath;
+use super::world::{block, border, brick};
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct WorldSnapshot {
+    pub tick: SimTick,
+    pub state: GameState,
+    // Entered at the start of the next tick
+    pub next_state: Option<GameState>,
+    pub phase_end: PhaseEnd,
+    pub score: MatchScore,
+    pub rng: GameRng,
+    pub map_state: MapState,
+    pub planted_bombs: PlantedBombs,
+    pub sudden_death: SuddenDeath,
+    // Players by id, bombs in planting order, fire and items by cell
+    pub players: Vec<PlayerSnapshot>,
+    pub bombs: Vec<BombSnapshot>,
+    pub explosions: Vec<Explosion>,
+    pub powerups: Vec<Powerup>,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct PlayerSnapshot {
+    pub player: Player,
+    pub translation: Vec3,
+    pub linvel: Vec2,
+    pub curse: Option<Curse>,
+    pub bot: Option<Bot>,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct BombSnapshot {
+    pub bomb: Bomb,
+    pub translation: Vec3,
+    // Still passable for the players who stood on it when it was planted
+    pub sensor: bool,
+    // Caught by fire in the tick of the snapshot, it goes off in the next one
+    pub hit: bool,
+}
+
+#[derive(Debug)]
+pub enum SnapshotError {
+    Binary(bincode::Error),
+    Json(serde_json::Error),
+}
+
+impl fmt::Display for SnapshotError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SnapshotError::Binary(err) => write!(f, "malformed snapshot: {}", err),
+            SnapshotError::Json(err) => write!(f, "malformed snapshot: {}", err),
+        }
+    }
+}
+
+impl std::error::Error for SnapshotError {}
+
+impl From<bincode::Error> for SnapshotError {
+    fn from(err: bincode::Error) -> Self {
+        SnapshotError::Binary(err)
+    }
+}
+
+impl From<serde_json::Error> for SnapshotError {
+    fn from(err: serde_json::Error) -> Self {
+        SnapshotError::Json(err)
+    }
+}
+
+impl WorldSnapshot {
+    pub fn capture(world: &mut World) -> Self {
+        let mut players: Vec<PlayerSnapshot> = world
+            .query::<(&Player, &Transform, &Velocity, Option<&Curse>, Option<&Bot>)>()
+            .iter(world)
+            .map(|(player, transform, velocity, curse, bot)| PlayerSnapshot {
+                player: player.clone(),
+                translation: transform.translation,
+                linvel: velocity.linvel,
+                curse: curse.copied(),
+                bot: bot.cloned(),
+            })
+            .collect();
+        players.sort_by_key(|snapshot| snapshot.player.id);
+
+        let tick = *world.resource::<SimTick>();
+        let events = world.resource::<Events<ExplosionHitBomb>>();
+        let hit: HashSet<Entity> = events
+            .get_cursor()
+            .read(events)
+            .filter(|event| event.tick == tick.0)
+            .map(|event| event.bomb)
+            .collect();
+
+        let mut bombs: Vec<BombSnapshot> = world
+            .query::<(Entity, &Bomb, &Transform, Has<Sensor>)>()
+            .iter(world)
+            .map(|(entity, bomb, transform, sensor)| BombSnapshot {
+                bomb: bomb.clone(),
+                translation: transform.translation,
+                sensor,
+                hit: hit.contains(&entity),
+            })
+            .collect();
+        bombs.sort_by_key(|snapshot| snapshot.bomb.order());
+
+        let mut explosions: Vec<Explosion> =
+            world.query::<&Explosion>().iter(world).cloned().collect();
+        explosions.sort_by_key(|explosion| (explosion.cell.1, explosion.cell.0));
+
+        let mut powerups: Vec<Powerup> = world.query::<&Powerup>().iter(world).cloned().collect();
+        powerups.sort_by_key(|powerup| (powerup.cell.1, powerup.cell.0));
+
+        let next_state = match world.resource::<NextState<GameState>>() {
+            NextState::Pending(state) => Some(*state),
+            NextState::Unchanged => None,
+        };
+
+        WorldSnapshot {
+            tick,
+            state: *world.resource::<State<GameState>>().get(),
+            next_state,
+            phase_end: *world.resource::<PhaseEnd>(),
+            score: world.resource::<MatchScore>().clone(),
+            rng: world.resource::<GameRng>().clone(),
+            map_state: world.resource::<MapState>().clone(),
+            planted_bombs: world.resource::<PlantedBombs>().clone(),
+            sudden_death: world.resource::<SuddenDeath>().clone(),
+            players,
+            bombs,
+            explosions,
+            powerups,
+        }
+    }
+
+    /// Replaces the arena and the match of `world` with the snapshot. The
+    /// state is set without running its enter and exit systems.
+    pub fn restore(&self, world: &mut World) {
+        let arena: Vec<Entity> = world
+            .query_filtered::<Entity, With<RoundEntity>>()
+            .iter(world)
+            .collect();
+        for entity in arena {
+            world.entity_mut(entity).despawn_recursive();
+        }
+
+        world.insert_resource(self.tick);
+        world.insert_resource(State::new(self.state));
+        world.insert_resource(match self.next_state {
+            Some(state) => NextState::Pending(state),
+            None => NextState::Unchanged,
+        });
+        world.insert_resource(self.phase_end);
+        world.insert_resource(self.score.clone());
+        world.insert_resource(self.rng.clone());
+        world.insert_resource(self.map_state.clone());
+        world.insert_resource(self.planted_bombs.clone());
+        world.insert_resource(self.sudden_death.clone());
+
+        let config = world.resource::<GameConfig>().clone();
+        let grid = &self.map_state.scheme;
+        let mut commands = world.commands();
+
+        // Bodies are added in the order a round adds them, whatever order the
+        // snapshot lists them in, so the physics steps through them the same way
+        border::spawn_walls(&mut commands, &config, grid);
+
+        for (cell, tile) in grid.cells() {
+            if tile == map::legend::BLOCK {
+                block::spawn_block(&mut commands, &config, grid, cell);
+            }
+        }
+        for (cell, tile) in grid.cells() {
+            if tile == map::legend::BRICK {
+                brick::spawn_brick(&mut commands, &config, grid, cell);
+            }
+        }
+
+        let mut players: Vec<&PlayerSnapshot> = self.players.iter().collect();
+        players.sort_by_key(|snapshot| snapshot.player.id);
+
+        for snapshot in players {
+            let transform = Transform::from_translation(snapshot.translation);
+            let entity = player::spawn_player_body(
+                &mut commands,
+                &config,
+                snapshot.player.clone(),
+                transform,
+            );
+
+            let mut entity = commands.entity(entity);
+            entity.insert(Velocity::linear(snapshot.linvel));
+            if let Some(curse) = snapshot.curse {
+                entity.insert(curse);
+            }
+            if let Some(bot) = &snapshot.bot {
+                entity.insert(bot.clone());
+            }
+        }
+
+        let mut hit = Vec::new();
+        let mut bombs: Vec<&BombSnapshot> = self.bombs.iter().collect();
+        bombs.sort_by_key(|snapshot| snapshot.bomb.order());
+
+        for snapshot in bombs {
+            let transform = Transform::from_translation(snapshot.translation);
+            let entity = bomb::spawn_bomb(&mut commands, &config, snapshot.bomb.clone(), transform);
+
+            if !snapshot.sensor {
+                commands.entity(entity).remove::<Sensor>();
+            }
+            if snapshot.hit {
+                hit.push(entity);
+            }
+        }
+
+        let mut explosions: Vec<&Explosion> = self.explosions.iter().collect();
+        explosions.sort_by_key(|explosion| (explosion.cell.1, explosion.cell.0));
+        for explosion in explosions {
+            explosion::spawn_fire(&mut commands, &config, grid, explosion.clone());
+        }
+
+        let mut powerups: Vec<&Powerup> = self.powerups.iter().collect();
+        powerups.sort_by_key(|powerup| (powerup.cell.1, powerup.cell.0));
+        for powerup in powerups {
+            powerup::spawn_powerup(&mut commands, &config, grid, powerup.clone());
+        }
+
+        world.flush();
+
+        // Hits of the ticks played out since are told apart by their tick
+        let mut events = world.resource_mut::<Events<ExplosionHitBomb>>();
+        for bomb in hit {
+            events.send(ExplosionHitBomb {
+                bomb,
+                tick: self.tick.0,
+            });
+        }
+    }
+
+    /// Compact binary encoding, with bincode.
+    pub fn to_bytes(&self) -> Result<Vec<u8>, SnapshotError> {
+        Ok(bincode::serialize(self)?)
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, SnapshotError> {
+        Ok(bincode::deserialize(bytes)?)
+    }
+
+    pub fn to_json(&self) -> Result<String, SnapshotError> {
+        Ok(serde_json::to_string(self)?)
+    }
+
+    pub fn from_json(json: &str) -> Result<Self, SnapshotError> {
+        Ok(serde_json::from_str(json)?)
+    }
+
+    /// Stable hash of the simulated world. Who drives the players is left
+    /// out, so a recording and its playback by remote inputs hash the same.
+    pub fn checksum(&self) -> u64 {
+        let mut snapshot = self.clone();
+        for player in &mut snapshot.players {
+            player.player.make_remote();
+            player.bot = None;
+        }
+
+        let mut hasher = StableHasher::default();
+        // Snapshots of the same world encode the same way
+        hasher.write(&snapshot.to_bytes().unwrap_or_default());
+        hasher.finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::abtestbed::setup::HeadlessSetupPlugin;
+    use crate::abtestbed::world::ai::Difficulty;
+    use crate::abtestbed::world::player::{RosterSettings, SlotKind};
+    use crate::abtestbed::world::round::MatchSettings;
+    use crate::abtestbed::world::WorldPlugin;
+
+    // Ticks played out after the snapshot, and before it in every case
+    const TICKS: u64 = 240;
+
+    fn bots_app(seed: u64) -> App {
+        let mut app = App::new();
+        app.insert_resource(GameConfig::default())
+            .insert_resource(GameRng::from_seed(seed))
+            .insert_resource(MatchSettings {
+                auto_start: true,
+                ..default()
+            })
+            .insert_resource(RosterSettings {
+                slots: vec![
+                    SlotKind::Bot(Difficulty::Hard),
+                    SlotKind::Bot(Difficulty::Hard),
+                    SlotKind::Bot(Difficulty::Normal),
+                    SlotKind::Bot(Difficulty::Easy),
+                ],
+            })
+            .add_plugins(HeadlessSetupPlugin)
+            .add_plugins(WorldPlugin);
+        app.finish();
+        app.cleanup();
+
+        app
+    }
+
+    fn play(app: &mut App, ticks: u64) -> Vec<u8> {
+        let end = app.world().resource::<SimTick>().0 + ticks;
+        while app.world().resource::<SimTick>().0 < end {
+            app.update();
+        }
+
+        WorldSnapshot::capture(app.world_mut()).to_bytes().unwrap()
+    }
+
+    #[test]
+    fn restored_world_plays_out_the_same() {
+        for (seed, before) in [(1, TICKS), (2, 4 * TICKS)] {
+            let mut app = bots_app(seed);
+            play(&mut app, before);
+
+            let snapshot = WorldSnapshot::capture(app.world_mut());
+            let bytes = snapshot.to_bytes().unwrap();
+            let json = snapshot.to_json().unwrap();
+            let played = play(&mut app, TICKS);
+
+            for restored in [
+                WorldSnapshot::from_bytes(&bytes).unwrap(),
+                WorldSnapshot::from_json(&json).unwrap(),
+            ] {
+                restored.restore(app.world_mut());
+                assert_eq!(
+                    WorldSnapshot::capture(app.world_mut()).to_bytes().unwrap(),
+                    bytes,
+                    "seed {}: the restored world differs from the snapshot",
+                    seed
+                );
+                assert!(
+                    play(&mut app, TICKS) == played,
+                    "seed {}: the restored world played out differently",
+                    seed
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn rejects_malformed_snapshots() {
+        assert!(matches!(
+            WorldSnapshot::from_bytes(&[1, 2, 3]),
+            Err(SnapshotError::Binary(_))
+        ));
+        assert!(matches!(
+            WorldSnapshot::from_json("{\"tick\": 1}"),
+            Err(SnapshotError::Json(_))
+        ));
+    }
+}
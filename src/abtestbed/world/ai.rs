@@ -0,0 +1,501 @@
+//! Scripted bots that drive a `Player` by writing its `InputState`.
+//!
+//! Every decision a bot builds a danger map telling when fire reaches each
+//! cell, flees along a breadth-first path if its own cell is threatened and
+//! otherwise walks to the nearest power-up or to a cell from which a bomb
+//! would hit a brick or an opponent. A bomb is only planted when an escape
+//! route from its blast exists.
+
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::fmt;
+use std::str::FromStr;
+
+use bevy::prelude::*;
+use rand::{Rng, SeedableRng};
+use rand_chacha::ChaCha8Rng;
+use serde::{Deserialize, Serialize};
+
+use super::bomb;
+use super::explosion;
+use super::map;
+use super::player;
+use super::powerup;
+use super::round;
+use crate::abtestbed::config::GameConfig;
+use crate::abtestbed::setup::SimTick;
+
+// Distance from a cell centre under which a bot counts as standing in it
+const CENTER_TOLERANCE: f32 = 3.0;
+// Seconds a bot keeps between itself and the fire when crossing a blast zone
+const SAFETY_MARGIN: f32 = 0.3;
+
+pub struct AiPlugin;
+
+impl Plugin for AiPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_systems(
+            FixedUpdate,
+            drive_bots
+                .before(player::update_player_input)
+                .run_if(round::round_running),
+        );
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Difficulty {
+    Easy,
+    Normal,
+    Hard,
+}
+
+impl Difficulty {
+    // Seconds between two decisions
+    fn reaction_period(self) -> f32 {
+        match self {
+            Difficulty::Easy => 0.5,
+            Difficulty::Normal => 0.25,
+            Difficulty::Hard => 0.1,
+        }
+    }
+
+    fn hunts_players(self) -> bool {
+        self != Difficulty::Easy
+    }
+
+    fn seeks_powerups(self) -> bool {
+        self != Difficulty::Easy
+    }
+
+    // Whether the bot expects bombs to set each other off
+    fn follows_chains(self) -> bool {
+        self == Difficulty::Hard
+    }
+}
+
+#[derive(Debug)]
+pub struct UnknownDifficulty(String);
+
+impl fmt::Display for UnknownDifficulty {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "unknown difficulty '{}', expected easy, normal or hard", self.0)
+    }
+}
+
+impl std::error::Error for UnknownDifficulty {}
+
+impl FromStr for Difficulty {
+    type Err = UnknownDifficulty;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "easy" => Ok(Difficulty::Easy),
+            "normal" => Ok(Difficulty::Normal),
+            "hard" => Ok(Difficulty::Hard),
+            _ => Err(UnknownDifficulty(s.to_string())),
+        }
+    }
+}
+
+#[derive(Component, Clone, Serialize, Deserialize)]
+pub struct Bot {
+    difficulty: Difficulty,
+    next_decision_at: u64,
+    // Cells left to walk through, nearest first
+    path: VecDeque<map::Cell>,
+    // Kept apart from `GameRng` so that bots do not shift the world's random stream
+    rng: ChaCha8Rng,
+}
+
+impl Bot {
+    pub fn new(difficulty: Difficulty, seed: u64) -> Self {
+        Bot {
+            difficulty,
+            next_decision_at: 0,
+            path: VecDeque::new(),
+            rng: ChaCha8Rng::seed_from_u64(seed),
+        }
+    }
+}
+
+struct Plan {
+    path: VecDeque<map::Cell>,
+    plant_bomb: bool,
+}
+
+/// What a bot knows about the grid when making a decision.
+struct Surroundings<'a> {
+    map_state: &'a map::MapState,
+    // Cell, fire range and the tick the bomb is expected to go off
+    bombs: Vec<(map::Cell, u8, u64)>,
+    burning_cells: HashSet<map::Cell>,
+    opponent_cells: HashSet<map::Cell>,
+    powerup_cells: HashSet<map::Cell>,
+    now: u64,
+    // Ticks it takes the bot to walk one cell
+    step: u64,
+    // Fuse of a bomb planted now
+    detonation_period: u64,
+    safety_margin: u64,
+    follows_chains: bool,
+}
+
+impl Surroundings<'_> {
+    fn bomb_cells(&self) -> HashSet<map::Cell> {
+        self.bombs.iter().map(|(cell, _, _)| *cell).collect()
+    }
+
+    /// Earliest tick the fire reaches every threatened cell.
+    fn danger_map(&self) -> HashMap<map::Cell, u64> {
+        let blasts: Vec<Vec<map::Cell>> = self
+            .bombs
+            .iter()
+            .map(|(cell, fire_range, _)| explosion::blast_cells(self.map_state, *cell, *fire_range))
+            .collect();
+        let mut explode_at: Vec<u64> = self.bombs.iter().map(|(_, _, at)| *at).collect();
+
+        if self.follows_chains {
+            let mut changed = true;
+
+            while changed {
+                changed = false;
+
+                for (i, blast) in blasts.iter().enumerate() {
+                    for (j, (cell, _, _)) in self.bombs.iter().enumerate() {
+                        if explode_at[i] < explode_at[j] && blast.contains(cell) {
+                            explode_at[j] = explode_at[i];
+                            changed = true;
+                        }
+                    }
+                }
+            }
+        }
+
+        let mut danger = HashMap::new();
+
+        for (blast, at) in blasts.iter().zip(explode_at) {
+            for cell in blast {
+                danger
+                    .entry(*cell)
+                    .and_modify(|earliest: &mut u64| *earliest = (*earliest).min(at))
+                    .or_insert(at);
+            }
+        }
+
+        for cell in &self.burning_cells {
+            danger.insert(*cell, self.now);
+        }
+
+        danger
+    }
+
+    /// Breadth-first search from `start` over cells for which `passable`
+    /// holds, given the cell and the number of steps to it. Returns the
+    /// visited cells nearest first along with the cell each was reached from.
+    fn explore(
+        &self,
+        start: map::Cell,
+        passable: impl Fn(map::Cell, u32) -> bool,
+    ) -> (Vec<map::Cell>, HashMap<map::Cell, map::Cell>) {
+        let bomb_cells = self.bomb_cells();
+        let mut order = vec![start];
+        let mut parents = HashMap::new();
+        let mut queue = VecDeque::from([(start, 0)]);
+
+        while let Some((cell, distance)) = queue.pop_front() {
+            for (dx, dy) in explosion::DIRECTIONS {
+                let Some(next_cell) = cell.neighbour(&self.map_state.scheme, dx, dy) else {
+                    continue;
+                };
+
+                if next_cell == start
+                    || parents.contains_key(&next_cell)
+                    || self.map_state.tile(next_cell) != map::legend::EMPTY
+                    || bomb_cells.contains(&next_cell)
+                    || !passable(next_cell, distance + 1)
+                {
+                    continue;
+                }
+
+                parents.insert(next_cell, cell);
+                order.push(next_cell);
+                queue.push_back((next_cell, distance + 1));
+            }
+        }
+
+        (order, parents)
+    }
+
+    /// Path from `start` to the nearest cell out of every blast, walking
+    /// only through cells the fire will not reach before the bot leaves them.
+    fn escape_route(
+        &self,
+        start: map::Cell,
+        danger: &HashMap<map::Cell, u64>,
+    ) -> Option<VecDeque<map::Cell>> {
+        let (order, parents) = self.explore(start, |cell, distance| match danger.get(&cell) {
+            None => true,
+            Some(at) => self.now + self.step * (distance as u64 + 1) + self.safety_margin < *at,
+        });
+
+        order
+            .into_iter()
+            .find(|cell| !danger.contains_key(cell))
+            .map(|cell| path_to(&parents, start, cell))
+    }
+
+    /// Escape route the bot would have after planting a bomb at `cell`.
+    fn escape_after_planting(&self, cell: map::Cell, fire_range: u8) -> Option<VecDeque<map::Cell>> {
+        let mut planted = Surroundings {
+            bombs: self.bombs.clone(),
+            burning_cells: self.burning_cells.clone(),
+            opponent_cells: HashSet::new(),
+            powerup_cells: HashSet::new(),
+            ..*self
+        };
+        planted.bombs.push((cell, fire_range, self.now + self.detonation_period));
+
+        planted.escape_route(cell, &planted.danger_map())
+    }
+
+    // Whether a bomb at `cell` would destroy a brick or catch an opponent
+    fn is_worth_bombing(&self, cell: map::Cell, fire_range: u8, hunts_players: bool) -> bool {
+        explosion::blast_cells(self.map_state, cell, fire_range)
+            .into_iter()
+            .any(|blast_cell| {
+                self.map_state.tile(blast_cell) == map::legend::BRICK
+                    || (hunts_players && self.opponent_cells.contains(&blast_cell))
+            })
+    }
+}
+
+fn path_to(
+    parents: &HashMap<map::Cell, map::Cell>,
+    start: map::Cell,
+    goal: map::Cell,
+) -> VecDeque<map::Cell> {
+    let mut path = VecDeque::new();
+    let mut cell = goal;
+
+    while cell != start {
+        path.push_front(cell);
+        cell = parents[&cell];
+    }
+
+    path
+}
+
+fn decide(
+    surroundings: &Surroundings,
+    bot: &mut Bot,
+    player: &player::Player,
+    cell: map::Cell,
+) -> Plan {
+    let difficulty = bot.difficulty;
+    let danger = surroundings.danger_map();
+
+    if danger.contains_key(&cell) {
+        return Plan {
+            path: surroundings.escape_route(cell, &danger).unwrap_or_default(),
+            plant_bomb: false,
+        };
+    }
+
+    let can_plant = player.bomb_capacity() > 0;
+    let bomb_cells = surroundings.bomb_cells();
+
+    let (order, parents) = surroundings.explore(cell, |next_cell, _| !danger.contains_key(&next_cell));
+
+    for target in &order {
+        if difficulty.seeks_powerups() && surroundings.powerup_cells.contains(target) {
+            return Plan {
+                path: path_to(&parents, cell, *target),
+                plant_bomb: false,
+            };
+        }
+
+        if !can_plant
+            || bomb_cells.contains(target)
+            || !surroundings.is_worth_bombing(*target, player.fire_range(), difficulty.hunts_players())
+        {
+            continue;
+        }
+
+        let Some(escape) = surroundings.escape_after_planting(*target, player.fire_range()) else {
+            continue;
+        };
+
+        if *target == cell {
+            return Plan {
+                path: escape,
+                plant_bomb: true,
+            };
+        }
+
+        return Plan {
+            path: path_to(&parents, cell, *target),
+            plant_bomb: false,
+        };
+    }
+
+    // Nothing to do nearby, so wander about while staying out of harm's way
+    let keep_path = bot.path.back().is_some_and(|goal| parents.contains_key(goal));
+    if keep_path {
+        return Plan {
+            path: bot.path.clone(),
+            plant_bomb: false,
+        };
+    }
+
+    let goal = order[bot.rng.gen_range(0..order.len())];
+
+    Plan {
+        path: if goal == cell {
+            VecDeque::new()
+        } else {
+            path_to(&parents, cell, goal)
+        },
+        plant_bomb: false,
+    }
+}
+
+/// Directions that walk the bot along its path, lining it up with the
+/// centre of its cell before it turns.
+fn steer(bot: &mut Bot, grid: &map::Grid, position: Vec2, cell: map::Cell) -> (i8, i8) {
+    if bot.path.front() == Some(&cell) {
+        bot.path.pop_front();
+    }
+
+    let is_adjacent = |next_cell: &map::Cell| {
+        (next_cell.0 as i16 - cell.0 as i16).abs() + (next_cell.1 as i16 - cell.1 as i16).abs() == 1
+    };
+
+    if !bot.path.front().is_some_and(is_adjacent) {
+        bot.path.clear();
+    }
+
+    let offset = cell.center(grid).translation.truncate() - position;
+    let towards_center = |delta: f32| {
+        if delta.abs() > CENTER_TOLERANCE {
+            delta.signum() as i8
+        } else {
+            0
+        }
+    };
+
+    let Some(next_cell) = bot.path.front() else {
+        return (towards_center(offset.x), towards_center(offset.y));
+    };
+
+    let dx = next_cell.0 as i8 - cell.0 as i8;
+    let dy = cell.1 as i8 - next_cell.1 as i8;
+
+    if dx != 0 {
+        match towards_center(offset.y) {
+            0 => (dx, 0),
+            v => (0, v),
+        }
+    } else {
+        match towards_center(offset.x) {
+            0 => (0, dy),
+            h => (h, 0),
+        }
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+pub fn drive_bots(
+    mut players: Query<(&mut player::Player, Option<&mut Bot>, &Transform)>,
+    bombs: Query<&bomb::Bomb>,
+    explosions: Query<&explosion::Explosion>,
+    powerups: Query<&powerup::Powerup>,
+    config: Res<GameConfig>,
+    planted_bombs: Res<bomb::PlantedBombs>,
+    map_state: Res<map::MapState>,
+    tick: Res<SimTick>,
+) {
+    let standing: Vec<(uuid::Uuid, map::Cell)> = players
+        .iter()
+        .map(|(player, _, transform)| {
+            (player.id, map::Cell::from_transform(&map_state.scheme, transform))
+        })
+        .collect();
+
+    // Bombs planted this tick are not spawned yet and count as fresh ones
+    let detonation_period = config.ticks(config.bomb.detonation_period);
+    let fresh_fuse = tick.0 + detonation_period;
+    let planted: Vec<(map::Cell, u8, Option<u64>, Option<uuid::Uuid>)> = planted_bombs
+        .set
+        .iter()
+        .map(|cell| match bombs.iter().find(|bomb| bomb.cell == *cell && !bomb.is_airborne()) {
+            Some(bomb) => (*cell, bomb.fire_range, bomb.explode_at(), Some(bomb.player_id)),
+            None => (*cell, config.player.max_fire_range, Some(fresh_fuse), None),
+        })
+        .collect();
+
+    for (mut player, bot, transform) in &mut players {
+        let Some(mut bot) = bot else {
+            continue;
+        };
+
+        let cell = map::Cell::from_transform(&map_state.scheme, transform);
+
+        if tick.0 >= bot.next_decision_at {
+            bot.next_decision_at = tick.0 + config.ticks(bot.difficulty.reaction_period());
+
+            // The bot sets its own trigger bombs off once it is clear of them, so they
+            // are planned around like fresh ones. Anybody else's may go off any moment,
+            // and are expected within half a fuse so that there is still time to flee.
+            let bomb_timers = planted
+                .iter()
+                .map(|(cell, fire_range, explode_at, owner)| {
+                    let at = match explode_at {
+                        Some(at) => *at,
+                        None if *owner == Some(player.id) => fresh_fuse,
+                        None => tick.0 + detonation_period / 2,
+                    };
+
+                    (*cell, *fire_range, at)
+                })
+                .collect();
+            let has_trigger_bombs = planted
+                .iter()
+                .any(|(_, _, explode_at, owner)| explode_at.is_none() && *owner == Some(player.id));
+
+            let surroundings = Surroundings {
+                map_state: &map_state,
+                bombs: bomb_timers,
+                burning_cells: explosions.iter().map(|e| e.cell).collect(),
+                opponent_cells: standing
+                    .iter()
+                    .filter(|(id, _)| *id != player.id)
+                    .map(|(_, cell)| *cell)
+                    .collect(),
+                powerup_cells: powerups
+                    .iter()
+                    .filter(|p| p.kind != powerup::PowerupKind::Disease)
+                    .map(|p| p.cell)
+                    .collect(),
+                now: tick.0,
+                step: config.ticks(map::CELL_SIZE.x / player.speed()),
+                detonation_period,
+                safety_margin: config.ticks(SAFETY_MARGIN),
+                follows_chains: bot.difficulty.follows_chains(),
+            };
+
+            // Out of reach of every blast, a chain set off now cannot catch the bot
+            if has_trigger_bombs && !surroundings.danger_map().contains_key(&cell) {
+                player.inputs.detonate = true;
+            }
+
+            let plan = decide(&surroundings, &mut bot, &player, cell);
+            bot.path = plan.path;
+            player.inputs.plant_bomb |= plan.plant_bomb;
+        }
+
+        let (horizontal_direction, vertical_direction) =
+            steer(&mut bot, &map_state.scheme, transform.translation.truncate(), cell);
+        player.inputs.horizontal_direction = horizontal_direction;
+        player.inputs.vertical_direction = vertical_direction;
+    }
+}
@@ -1,21 +1,27 @@
-use std::collections::HashSet;
-use std::time::Duration;
+use std::collections::{BTreeSet, HashMap, HashSet, VecDeque};
 
 use bevy::prelude::*;
 use bevy_rapier2d::prelude::*;
+use serde::{Deserialize, Serialize};
 use uuid::Uuid;
 
+use super::collision;
 use super::explosion;
 use super::map;
 use super::player;
-use crate::abtestbed::setup;
+use super::powerup::PowerupKind;
+use super::round;
+use crate::abtestbed::config::GameConfig;
+use crate::abtestbed::setup::{self, SimTick};
 
-pub const DEFAULT_DETONATION_PERIOD: f32 = 2.0;
-const SIZE: Vec2 = Vec2::new(40.0, 36.0);
-const MASS: f32 = 100.0;
 const FRICTION: f32 = 0.0;
 const RESTITUTION: f32 = 0.0;
 
+// How close a player must be to a bomb's side to kick or punch it
+const KICK_REACH: f32 = 2.0;
+// Draws bombs in the air over everything on the ground
+const AIRBORNE_Z: f32 = 1.0;
+
 pub struct BombPlugin;
 
 impl Plugin for BombPlugin {
@@ -23,23 +29,40 @@ impl Plugin for BombPlugin {
         app.insert_resource(PlantedBombs::default())
             .add_event::<BombPlanted>()
             .add_event::<BombExploded>()
+            .add_event::<BombCrushed>()
+            .add_systems(OnEnter(round::GameState::RoundStarting), clear_planted_bombs)
             .add_systems(
-                Update,
+                FixedUpdate,
                 (
                     set_bomb,
-                    explode_bomb,
+                    kick_bombs,
+                    slide_bombs,
+                    fly_bombs,
+                    detonate_bombs,
+                    explode_bombs,
                     track_planted_bombs,
-                    track_explosion_bombs,
                     track_player_gone,
                 )
-                    .chain(),
+                    .chain()
+                    .after(player::movement_system)
+                    .run_if(round::round_running),
+            )
+            .add_systems(
+                FixedUpdate,
+                punch_and_grab_bombs
+                    .after(player::InputsTaken)
+                    .before(player::plant_bombs)
+                    .run_if(round::round_running),
             );
     }
 }
 
-#[derive(Resource, Default)]
+#[derive(Resource, Default, Clone, Serialize, Deserialize)]
 pub struct PlantedBombs {
-    pub set: HashSet<map::Cell>,
+    // Ordered so a snapshot of the same world always encodes the same way
+    pub set: BTreeSet<map::Cell>,
+    // Handed to the next bomb planted this round
+    next_order: u64,
 }
 
 #[derive(Event)]
@@ -49,6 +72,8 @@ pub struct BombPlanted {
     pub player_cell: map::Cell,
     pub player_fire_range: u8,
     pub player_bomb_detonation_period: f32,
+    // Bombs planted with the trigger wait for the owner to set them off
+    pub player_has_trigger: bool,
 }
 
 #[derive(Event)]
@@ -56,126 +81,631 @@ pub struct BombExploded {
     pub player_id: Uuid,
     pub player_color: player::PlayerColor,
     pub bomb_cell: map::Cell,
-    pub bomb_fire_range: u8,
+    pub blast_cells: Vec<map::Cell>,
+}
+
+/// A bomb went under a block dropped in sudden death without going off.
+#[derive(Event)]
+pub struct BombCrushed {
+    pub player_id: Uuid,
 }
 
-#[derive(Component)]
+#[derive(Component, Clone, Serialize, Deserialize)]
 pub struct Bomb {
     pub player_id: Uuid,
     pub player_color: player::PlayerColor,
     pub fire_range: u8,
-    players_at_bomb_count: u8,
-    explode_at: Duration,
-}
-
-fn set_bomb(mut commands: Commands, mut events: EventReader<BombPlanted>, time: Res<Time>) {
-    for event in events.read() {
-        commands.spawn((
-            Bomb {
-                player_id: event.player_id,
-                player_color: event.player_color,
-                fire_range: event.player_fire_range,
-                players_at_bomb_count: 0,
-                explode_at: time.elapsed()
-                    + Duration::from_secs_f32(event.player_bomb_detonation_period),
-            },
-            Sprite {
-                color: event.player_color.to_bevy_color(),
-                custom_size: Some(SIZE),
-                ..default()
-            },
-            event.player_cell.center(),
-            RigidBody::Dynamic,
-            Sensor,
-            ActiveEvents::COLLISION_EVENTS,
-            Velocity::zero(),
-            LockedAxes::TRANSLATION_LOCKED | LockedAxes::ROTATION_LOCKED,
-            Collider::cuboid(SIZE.x / 2.0, SIZE.y / 2.0),
-            CollisionGroups::new(
-                Group::from_bits(setup::collision::policy::BOMB.0).unwrap(),
-                Group::from_bits(setup::collision::policy::BOMB.1).unwrap(),
-            ),
-            ColliderMassProperties::Mass(MASS),
-            Friction::new(FRICTION),
-            Restitution::new(RESTITUTION),
-            ExternalForce::default(),
-        ));
-    }
-}
-
-fn explode_bomb(
+    // Cell the bomb occupies, or is sliding into once kicked
+    pub cell: map::Cell,
+    // Tick of the blast, unset while a trigger bomb waits for its owner
+    explode_at: Option<u64>,
+    // Bombs planted earlier have a lower number
+    order: u64,
+    slide: Option<Slide>,
+    // Off the ground the fuse stops burning and fire does not reach the bomb
+    airborne: Option<Airborne>,
+    launched_at: u64,
+}
+
+impl Bomb {
+    pub fn explode_at(&self) -> Option<u64> {
+        self.explode_at
+    }
+
+    pub fn order(&self) -> u64 {
+        self.order
+    }
+
+    pub fn is_airborne(&self) -> bool {
+        self.airborne.is_some()
+    }
+
+    pub fn is_flying(&self) -> bool {
+        matches!(self.airborne, Some(Airborne::Flying { .. }))
+    }
+}
+
+#[derive(Copy, Clone, Serialize, Deserialize)]
+enum Airborne {
+    // Held over the head of a player with grab
+    Carried { carrier: Uuid },
+    // On its way to `Bomb::cell`, with that many cells still to go after it
+    Flying { direction: (i8, i8), cells_left: u8 },
+}
+
+#[derive(Copy, Clone, Serialize, Deserialize)]
+struct Slide {
+    direction: (i8, i8),
+    // Set when the kicker holds jelly: the bomb bounces back instead of stopping
+    bouncy: bool,
+}
+
+fn set_bomb(
     mut commands: Commands,
-    query: Query<(Entity, &Bomb, &Transform)>,
-    time: Res<Time>,
-    mut events: EventWriter<BombExploded>,
+    mut events: EventReader<BombPlanted>,
+    mut planted_bombs: ResMut<PlantedBombs>,
+    config: Res<GameConfig>,
+    map_state: Res<map::MapState>,
+    tick: Res<SimTick>,
 ) {
-    for (e, b, t) in &query {
-        if time.elapsed() < b.explode_at {
+    // Numbered by player rather than by the order the players were queried
+    // in, which changes when the world is restored from a snapshot
+    let mut events: Vec<&BombPlanted> = events.read().collect();
+    events.sort_by_key(|event| event.player_id);
+
+    for event in events {
+        let explode_at = (!event.player_has_trigger)
+            .then(|| tick.0 + config.ticks(event.player_bomb_detonation_period));
+        planted_bombs.next_order += 1;
+
+        let bomb = Bomb {
+            player_id: event.player_id,
+            player_color: event.player_color,
+            fire_range: event.player_fire_range,
+            cell: event.player_cell,
+            explode_at,
+            order: planted_bombs.next_order,
+            slide: None,
+            airborne: None,
+            launched_at: 0,
+        };
+        let transform = event.player_cell.center(&map_state.scheme);
+
+        spawn_bomb(&mut commands, &config, bomb, transform);
+    }
+}
+
+/// Spawns `bomb` as a sensor players standing on it can walk off of. Bombs
+/// in the air collide with nothing.
+pub fn spawn_bomb(
+    commands: &mut Commands,
+    config: &GameConfig,
+    bomb: Bomb,
+    transform: Transform,
+) -> Entity {
+    let size = Vec2::from(config.bomb.size);
+    let color = bomb.player_color.to_bevy_color();
+    let airborne = bomb.is_airborne();
+
+    let mut entity = commands.spawn((
+        round::RoundEntity,
+        bomb,
+        Sprite {
+            color,
+            custom_size: Some(size),
+            ..default()
+        },
+        transform,
+        RigidBody::Dynamic,
+        Sensor,
+        ActiveEvents::COLLISION_EVENTS,
+        Velocity::zero(),
+        LockedAxes::TRANSLATION_LOCKED | LockedAxes::ROTATION_LOCKED,
+        Collider::cuboid(size.x / 2.0, size.y / 2.0),
+        CollisionGroups::new(
+            Group::from_bits(setup::collision::policy::BOMB.0).unwrap(),
+            Group::from_bits(setup::collision::policy::BOMB.1).unwrap(),
+        ),
+        ColliderMassProperties::Mass(config.bomb.mass),
+        Friction::new(FRICTION),
+        Restitution::new(RESTITUTION),
+        ExternalForce::default(),
+    ));
+
+    if airborne {
+        entity.insert(ColliderDisabled);
+    }
+
+    entity.id()
+}
+
+/// Starts a slide for every resting bomb a player with kick walks into.
+fn kick_bombs(
+    config: Res<GameConfig>,
+    map_state: Res<map::MapState>,
+    players: Query<(&player::Player, &Transform)>,
+    mut bombs: Query<(&mut Bomb, &Transform), Without<player::Player>>,
+) {
+    let reach = (Vec2::from(config.bomb.size) + Vec2::from(config.player.size)) / 2.0 + KICK_REACH;
+
+    for (player, player_transform) in &players {
+        if !player.has_ability(PowerupKind::Kick) {
             continue;
         }
 
-        commands.entity(e).despawn();
+        let direction = match (
+            player.inputs.horizontal_direction,
+            player.inputs.vertical_direction,
+        ) {
+            (dx, 0) if dx != 0 => (dx, 0),
+            (0, dy) if dy != 0 => (0, dy),
+            _ => continue,
+        };
 
-        events.send(BombExploded {
-            player_id: b.player_id,
-            player_color: b.player_color,
-            bomb_cell: map::Cell::from_transform(t),
-            bomb_fire_range: b.fire_range,
-        });
+        let player_cell = map::Cell::from_transform(&map_state.scheme, player_transform);
+        let Some(target_cell) = player_cell.neighbour(&map_state.scheme, direction.0, direction.1)
+        else {
+            continue;
+        };
+
+        for (mut bomb, bomb_transform) in &mut bombs {
+            if bomb.cell != target_cell || bomb.slide.is_some() || bomb.is_airborne() {
+                continue;
+            }
+
+            let gap = (bomb_transform.translation - player_transform.translation)
+                .truncate()
+                .abs();
+            let touching = if direction.0 != 0 {
+                gap.x <= reach.x
+            } else {
+                gap.y <= reach.y
+            };
+
+            if touching {
+                bomb.slide = Some(Slide {
+                    direction,
+                    bouncy: player.has_ability(PowerupKind::Jelly),
+                });
+            }
+        }
+    }
+}
+
+/// Moves kicked bombs towards their next cell, claiming each cell in
+/// `PlantedBombs` as the bomb enters it, until something is in the way.
+fn slide_bombs(
+    mut bombs: Query<(Entity, &mut Bomb, &mut Transform)>,
+    players: Query<&Transform, (With<player::Player>, Without<Bomb>)>,
+    config: Res<GameConfig>,
+    map_state: Res<map::MapState>,
+    mut planted_bombs: ResMut<PlantedBombs>,
+) {
+    let player_cells: HashSet<map::Cell> = players
+        .iter()
+        .map(|transform| map::Cell::from_transform(&map_state.scheme, transform))
+        .collect();
+
+    for entity in in_planting_order(&bombs) {
+        let Ok((_, mut bomb, mut transform)) = bombs.get_mut(entity) else {
+            continue;
+        };
+        let Some(mut slide) = bomb.slide else {
+            continue;
+        };
+
+        let mut travel = config.bomb.slide_speed / config.fps;
+
+        loop {
+            let target = bomb.cell.center(&map_state.scheme).translation.truncate();
+            let offset = target - transform.translation.truncate();
+
+            if offset.length() > travel {
+                let step = offset.normalize() * travel;
+                transform.translation += step.extend(0.0);
+                break;
+            }
+
+            transform.translation = target.extend(transform.translation.z);
+            travel -= offset.length();
+
+            let is_free = |cell: map::Cell| {
+                map_state.tile(cell) == map::legend::EMPTY
+                    && !planted_bombs.set.contains(&cell)
+                    && !player_cells.contains(&cell)
+            };
+
+            let mut next_cell = bomb
+                .cell
+                .neighbour(&map_state.scheme, slide.direction.0, slide.direction.1)
+                .filter(|cell| is_free(*cell));
+
+            if next_cell.is_none() && slide.bouncy {
+                slide.direction = (-slide.direction.0, -slide.direction.1);
+                next_cell = bomb
+                    .cell
+                    .neighbour(&map_state.scheme, slide.direction.0, slide.direction.1)
+                    .filter(|cell| is_free(*cell));
+            }
+
+            let Some(next_cell) = next_cell else {
+                bomb.slide = None;
+                break;
+            };
+
+            let prev_cell = bomb.cell;
+            planted_bombs.set.remove(&prev_cell);
+            planted_bombs.set.insert(next_cell);
+            bomb.cell = next_cell;
+            bomb.slide = Some(slide);
+        }
     }
 }
 
-fn track_player_gone(
-    mut commands: Commands, 
-    mut collision_events: EventReader<CollisionEvent>,
-    mut bombs: Query<(Entity, &mut Bomb), With<Sensor>>,
-    players: Query<(), With<player::Player>>,
+/// Lets a player with grab pick up the bomb they stand on with the bomb action
+/// and throw it with the next press, and a player with punch knock the bomb in
+/// front of them away with the second action. A press used up here neither
+/// plants a bomb nor sets off a trigger bomb.
+fn punch_and_grab_bombs(
+    mut commands: Commands,
+    config: Res<GameConfig>,
+    map_state: Res<map::MapState>,
+    mut planted_bombs: ResMut<PlantedBombs>,
+    mut players: Query<(Entity, &mut player::Player, &Transform)>,
+    mut bombs: Query<(Entity, &mut Bomb, &Transform), Without<player::Player>>,
+    tick: Res<SimTick>,
 ) {
-    for collision_event in collision_events.read() {
-        match collision_event {
-            CollisionEvent::Started(e1, e2, _) => {
-                let bomb_entity;
-
-                if players.get(*e1).is_ok() && bombs.get(*e2).is_ok() {
-                    bomb_entity = e2;
-                } else if players.get(*e2).is_ok() && bombs.get(*e1).is_ok() {
-                    bomb_entity = e1;
-                } else {
-                    return;
+    let reach = (Vec2::from(config.bomb.size) + Vec2::from(config.player.size)) / 2.0 + KICK_REACH;
+
+    // Two players reaching for one bomb are served by id
+    let mut order: Vec<(Uuid, Entity)> = players
+        .iter()
+        .map(|(entity, player, _)| (player.id, entity))
+        .collect();
+    order.sort();
+
+    for (_, entity) in order {
+        let Ok((_, mut player, player_transform)) = players.get_mut(entity) else {
+            continue;
+        };
+        let player_cell = map::Cell::from_transform(&map_state.scheme, player_transform);
+        let direction = player.facing();
+
+        if player.inputs.plant_bomb {
+            let carried = bombs.iter_mut().find(|(_, bomb, _)| {
+                matches!(bomb.airborne, Some(Airborne::Carried { carrier }) if carrier == player.id)
+            });
+
+            if let Some((_, mut bomb, _)) = carried {
+                player.inputs.plant_bomb = false;
+                bomb.cell = player_cell;
+                bomb.airborne = Some(Airborne::Flying {
+                    direction,
+                    cells_left: config.bomb.throw_distance,
+                });
+                continue;
+            }
+
+            if player.has_ability(PowerupKind::Grab) {
+                let standing_on = bombs
+                    .iter_mut()
+                    .find(|(_, bomb, _)| bomb.cell == player_cell && !bomb.is_airborne());
+
+                if let Some((entity, mut bomb, _)) = standing_on {
+                    player.inputs.plant_bomb = false;
+                    lift_bomb(&mut commands, &mut planted_bombs, entity, &mut bomb, *tick);
+                    bomb.airborne = Some(Airborne::Carried { carrier: player.id });
+                    continue;
                 }
+            }
+        }
+
+        if !player.inputs.detonate || !player.has_ability(PowerupKind::Punch) {
+            continue;
+        }
+
+        let Some(target_cell) = player_cell.neighbour(&map_state.scheme, direction.0, direction.1)
+        else {
+            continue;
+        };
+
+        let in_front = bombs.iter_mut().find(|(_, bomb, bomb_transform)| {
+            let gap = (bomb_transform.translation - player_transform.translation)
+                .truncate()
+                .abs();
+            let touching = if direction.0 != 0 {
+                gap.x <= reach.x
+            } else {
+                gap.y <= reach.y
+            };
+
+            bomb.cell == target_cell && !bomb.is_airborne() && touching
+        });
+
+        if let Some((entity, mut bomb, _)) = in_front {
+            player.inputs.detonate = false;
+            lift_bomb(&mut commands, &mut planted_bombs, entity, &mut bomb, *tick);
+            bomb.airborne = Some(Airborne::Flying {
+                direction,
+                cells_left: config.bomb.punch_distance,
+            });
+        }
+    }
+}
+
+// Bombs moving into cells another one may claim in the same tick go oldest
+// first, as the order entities are stored in changes when the world is
+// restored from a snapshot
+fn in_planting_order(bombs: &Query<(Entity, &mut Bomb, &mut Transform)>) -> Vec<Entity> {
+    let mut order: Vec<(u64, Entity)> = bombs
+        .iter()
+        .map(|(entity, bomb, _)| (bomb.order, entity))
+        .collect();
+    order.sort();
+
+    order.into_iter().map(|(_, entity)| entity).collect()
+}
+
+// Takes a bomb off the ground, where nothing collides with it any more
+fn lift_bomb(
+    commands: &mut Commands,
+    planted_bombs: &mut PlantedBombs,
+    entity: Entity,
+    bomb: &mut Bomb,
+    tick: SimTick,
+) {
+    planted_bombs.set.remove(&bomb.cell);
+    bomb.slide = None;
+    bomb.launched_at = tick.0;
+    commands.entity(entity).insert(ColliderDisabled);
+}
+
+/// Moves carried bombs along with their carrier and flying bombs over
+/// everything in their way, wrapping around the edges of the arena. A bomb
+/// coming down on a taken cell bounces on to the next one; once it lands its
+/// fuse burns on from where it stopped.
+fn fly_bombs(
+    mut commands: Commands,
+    mut bombs: Query<(Entity, &mut Bomb, &mut Transform)>,
+    players: Query<(&player::Player, &Transform), Without<Bomb>>,
+    config: Res<GameConfig>,
+    map_state: Res<map::MapState>,
+    mut planted_bombs: ResMut<PlantedBombs>,
+    tick: Res<SimTick>,
+) {
+    let grid = &map_state.scheme;
+    let player_cells: HashSet<map::Cell> = players
+        .iter()
+        .map(|(_, transform)| map::Cell::from_transform(grid, transform))
+        .collect();
 
-                for (e, mut b) in &mut bombs {
-                    if e.index() == bomb_entity.index() {
-                        b.players_at_bomb_count += 1;
+    for entity in in_planting_order(&bombs) {
+        let Ok((_, mut bomb, mut transform)) = bombs.get_mut(entity) else {
+            continue;
+        };
+        let (mut direction, mut cells_left) = match bomb.airborne {
+            None => continue,
+            Some(Airborne::Carried { carrier }) => {
+                match players.iter().find(|(player, _)| player.id == carrier) {
+                    Some((_, carrier_transform)) => {
+                        bomb.cell = map::Cell::from_transform(grid, carrier_transform);
+                        transform.translation = carrier_transform.translation.with_z(AIRBORNE_Z);
+                        continue;
                     }
+                    // Dropped where the carrier fell
+                    None => ((0, 0), 0),
                 }
             }
-            CollisionEvent::Stopped(e1, e2, _) => {
-                let bomb_entity;
-
-                if players.get(*e1).is_ok() && bombs.get(*e2).is_ok() {
-                    bomb_entity = e2;
-                } else if players.get(*e2).is_ok() && bombs.get(*e1).is_ok() {
-                    bomb_entity = e1;
-                } else {
-                    return;
+            Some(Airborne::Flying {
+                direction,
+                cells_left,
+            }) => (direction, cells_left),
+        };
+
+        let mut travel = config.bomb.flight_speed / config.fps;
+        let mut landed = false;
+
+        loop {
+            let target = bomb.cell.center(grid).translation.truncate();
+            let offset = target - transform.translation.truncate();
+
+            if offset.length() > travel {
+                let step = offset.normalize() * travel;
+                transform.translation += step.extend(0.0);
+                break;
+            }
+
+            transform.translation = target.extend(AIRBORNE_Z);
+            travel -= offset.length();
+
+            if cells_left == 0 {
+                if is_landing_free(&map_state, &planted_bombs, &player_cells, bomb.cell) {
+                    landed = true;
+                    break;
                 }
 
-                for (e, mut b) in &mut bombs {
-                    if e.index() == bomb_entity.index() {
-                        b.players_at_bomb_count -= 1;
+                // A bomb dropped by its fallen carrier bounces on to a free neighbour
+                if direction == (0, 0) {
+                    direction = explosion::DIRECTIONS
+                        .into_iter()
+                        .find(|(dx, dy)| {
+                            let cell = bomb.cell.wrapping_neighbour(grid, *dx, *dy);
+                            is_landing_free(&map_state, &planted_bombs, &player_cells, cell)
+                        })
+                        .unwrap_or(explosion::DIRECTIONS[0]);
+                }
 
-                        if b.players_at_bomb_count == 0 {
-                            commands.entity(*bomb_entity).remove::<Sensor>();
-                            break;
-                        }
-                    }
+                cells_left = 1;
+            }
+
+            let next_cell = bomb.cell.wrapping_neighbour(grid, direction.0, direction.1);
+
+            // Over the edge the bomb comes in from the opposite side
+            if bomb.cell.neighbour(grid, direction.0, direction.1) != Some(next_cell) {
+                let entry = next_cell.center(grid).translation.truncate()
+                    - Vec2::new(direction.0 as f32, direction.1 as f32) * map::CELL_SIZE;
+                transform.translation = entry.extend(AIRBORNE_Z);
+            }
+
+            bomb.cell = next_cell;
+            cells_left -= 1;
+        }
+
+        if !landed {
+            bomb.airborne = Some(Airborne::Flying {
+                direction,
+                cells_left,
+            });
+            continue;
+        }
+
+        let airtime = tick.0 - bomb.launched_at;
+        bomb.explode_at = bomb.explode_at.map(|at| at + airtime);
+        bomb.airborne = None;
+        transform.translation.z = 0.0;
+        planted_bombs.set.insert(bomb.cell);
+        commands
+            .entity(entity)
+            .remove::<(ColliderDisabled, Sensor)>();
+    }
+}
+
+fn is_landing_free(
+    map_state: &map::MapState,
+    planted_bombs: &PlantedBombs,
+    player_cells: &HashSet<map::Cell>,
+    cell: map::Cell,
+) -> bool {
+    map_state.tile(cell) == map::legend::EMPTY
+        && !planted_bombs.set.contains(&cell)
+        && !player_cells.contains(&cell)
+}
+
+/// Sets off the oldest trigger bomb of every player pressing the trigger. The
+/// trigger bombs of a player who is gone get a fuse instead.
+pub fn detonate_bombs(
+    mut players: Query<&mut player::Player>,
+    mut bombs: Query<&mut Bomb>,
+    config: Res<GameConfig>,
+    tick: Res<SimTick>,
+) {
+    for mut player in &mut players {
+        if !std::mem::take(&mut player.inputs.detonate) {
+            continue;
+        }
+
+        let oldest = bombs
+            .iter_mut()
+            .filter(|bomb| {
+                bomb.player_id == player.id && bomb.explode_at.is_none() && !bomb.is_airborne()
+            })
+            .min_by_key(|bomb| bomb.order);
+
+        if let Some(mut bomb) = oldest {
+            bomb.explode_at = Some(tick.0);
+        }
+    }
+
+    let fuse = config.ticks(config.bomb.detonation_period);
+
+    for mut bomb in &mut bombs {
+        let owner_is_gone = players.iter().all(|player| player.id != bomb.player_id);
+
+        if bomb.explode_at.is_none() && !bomb.is_airborne() && owner_is_gone {
+            bomb.explode_at = Some(tick.0 + fuse);
+        }
+    }
+}
+
+/// Detonates every bomb whose fuse ran out or whose cell is on fire, then
+/// every bomb caught in their blasts, in chain order.
+pub fn explode_bombs(
+    mut commands: Commands,
+    query: Query<(Entity, &Bomb)>,
+    mut hits: EventReader<collision::ExplosionHitBomb>,
+    map_state: Res<map::MapState>,
+    tick: Res<SimTick>,
+    mut events: EventWriter<BombExploded>,
+) {
+    // Routed after the fire of the last tick was spawned. Hits of ticks that
+    // were played out before the world was restored from a snapshot are stale
+    let burning: HashSet<Entity> = hits
+        .read()
+        .filter(|hit| hit.tick + 1 == tick.0)
+        .map(|hit| hit.bomb)
+        .collect();
+    let bomb_cells: HashMap<map::Cell, Entity> = query
+        .iter()
+        .filter(|(_, b)| !b.is_airborne())
+        .map(|(e, b)| (b.cell, e))
+        .collect();
+
+    let mut triggered: Vec<(u64, map::Cell, Entity)> = query
+        .iter()
+        .filter(|(_, b)| !b.is_airborne())
+        .filter_map(|(e, b)| {
+            let fuse_ran_out = b.explode_at.is_some_and(|at| tick.0 >= at);
+
+            if fuse_ran_out || burning.contains(&e) {
+                Some((b.explode_at.unwrap_or(tick.0), b.cell, e))
+            } else {
+                None
+            }
+        })
+        .collect();
+    triggered.sort_by_key(|(explode_at, cell, _)| (*explode_at, cell.1, cell.0));
+
+    let mut chain: VecDeque<Entity> = triggered.into_iter().map(|(_, _, e)| e).collect();
+    let mut exploded = HashSet::new();
+
+    while let Some(e) = chain.pop_front() {
+        if !exploded.insert(e) {
+            continue;
+        }
+
+        let Ok((_, b)) = query.get(e) else {
+            continue;
+        };
+
+        let bomb_cell = b.cell;
+        let blast_cells = explosion::blast_cells(&map_state, bomb_cell, b.fire_range);
+
+        for cell in &blast_cells {
+            if let Some(other) = bomb_cells.get(cell) {
+                if !exploded.contains(other) {
+                    chain.push_back(*other);
                 }
             }
         }
+
+        commands.entity(e).despawn();
+
+        events.send(BombExploded {
+            player_id: b.player_id,
+            player_color: b.player_color,
+            bomb_cell,
+            blast_cells,
+        });
+    }
+}
+
+/// Makes a bomb solid once the players who stood on it walked off.
+pub fn track_player_gone(
+    mut commands: Commands,
+    mut left_events: EventReader<collision::PlayerLeftBomb>,
+    bombs: Query<(), (With<Bomb>, With<Sensor>)>,
+) {
+    for event in left_events.read() {
+        if bombs.contains(event.bomb) {
+            commands.entity(event.bomb).remove::<Sensor>();
+        }
     }
 }
 
+fn clear_planted_bombs(mut planted_bombs: ResMut<PlantedBombs>) {
+    planted_bombs.set.clear();
+}
+
 fn track_planted_bombs(
     mut bomb_planted_events: EventReader<BombPlanted>,
     mut bomb_exploded_events: EventReader<BombExploded>,
@@ -190,40 +720,62 @@ fn track_planted_bombs(
     }
 }
 
-fn track_explosion_bombs(
-    mut commands: Commands,
-    mut collision_events: EventReader<CollisionEvent>,
-    mut bomb_exploded_events: EventWriter<BombExploded>,
-    explosions: Query<(), With<explosion::Explosion>>,
-    bombs: Query<(Entity, &Bomb, &Transform)>,
-) {
-    for collision_event in collision_events.read() {
-        match collision_event {
-            CollisionEvent::Started(e1, e2, _) => {
-                let bomb_entity;
-
-                if explosions.get(*e1).is_ok() && bombs.get(*e2).is_ok() {
-                    bomb_entity = e2;
-                } else if explosions.get(*e2).is_ok() && bombs.get(*e1).is_ok() {
-                    bomb_entity = e1;
-                } else {
-                    return;
-                }
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bevy::ecs::system::RunSystemOnce;
 
-                for (e, b, t) in &bombs {
-                    if e.index() == bomb_entity.index() {
-                        bomb_exploded_events.send(BombExploded {
-                            player_id: b.player_id,
-                            player_color: b.player_color,
-                            bomb_cell: map::Cell::from_transform(t),
-                            bomb_fire_range: b.fire_range,
-                        });
-                    }
-                }
+    fn bomb_at(cell: map::Cell, airborne: Option<Airborne>) -> Bomb {
+        Bomb {
+            player_id: Uuid::from_u128(1),
+            player_color: player::PlayerColor::White,
+            fire_range: 2,
+            cell,
+            explode_at: None,
+            order: 0,
+            slide: None,
+            airborne,
+            launched_at: 0,
+        }
+    }
 
-                commands.entity(*bomb_entity).despawn();
-            }
-            _ => {}
+    #[test]
+    fn dropped_bomb_leaves_a_planted_one_alone() {
+        let map_state = map::MapState::default();
+        let cell = map::Cell(0, 0);
+        let center = cell.center(&map_state.scheme);
+
+        let mut world = World::new();
+        world.insert_resource(PlantedBombs {
+            set: BTreeSet::from([cell]),
+            next_order: 1,
+        });
+        world.insert_resource(GameConfig::default());
+        world.insert_resource(SimTick(1));
+
+        // The carrier fell while standing on a bomb of its own
+        world.spawn((bomb_at(cell, None), center));
+        let carrier = Uuid::from_u128(1);
+        let dropped = world
+            .spawn((
+                bomb_at(cell, Some(Airborne::Carried { carrier })),
+                center.with_translation(center.translation.with_z(AIRBORNE_Z)),
+            ))
+            .id();
+        world.insert_resource(map_state);
+
+        for _ in 0..60 {
+            world.run_system_once(fly_bombs).unwrap();
         }
+
+        let bomb = world.get::<Bomb>(dropped).unwrap();
+        let grid = &world.resource::<map::MapState>().scheme;
+        let next_to = explosion::DIRECTIONS
+            .iter()
+            .any(|(dx, dy)| cell.wrapping_neighbour(grid, *dx, *dy) == bomb.cell);
+
+        assert!(!bomb.is_airborne(), "the dropped bomb never came down");
+        assert!(next_to, "the dropped bomb landed away from the planted one");
+        assert!(world.resource::<PlantedBombs>().set.contains(&bomb.cell));
     }
 }
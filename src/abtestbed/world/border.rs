@@ -2,9 +2,9 @@ use bevy::prelude::*;
 use bevy_rapier2d::prelude::*;
 
 use super::map;
+use super::round;
+use crate::abtestbed::config::GameConfig;
 
-const HOR_SIZE: Vec2 = Vec2::new(608.0, 4.0);
-const VER_SIZE: Vec2 = Vec2::new(4.0, 392.0);
 const MAIN_COLOR: Color = Color::srgb(0.2, 0.8, 0.2);
 const FRICTION: f32 = 0.0;
 
@@ -12,60 +12,78 @@ pub struct BorderPlugin;
 
 impl Plugin for BorderPlugin {
     fn build(&self, app: &mut App) {
-        app.add_systems(Startup, spawn_borders);
+        app.add_systems(
+            OnEnter(round::GameState::RoundStarting),
+            spawn_borders.after(map::reset_map),
+        );
     }
 }
 
-fn spawn_borders(mut commands: Commands) {
+pub fn spawn_borders(mut commands: Commands, map_state: Res<map::MapState>, config: Res<GameConfig>) {
+    spawn_walls(&mut commands, &config, &map_state.scheme);
+}
+
+/// The four borders around `grid`.
+pub fn spawn_walls(commands: &mut Commands, config: &GameConfig, grid: &map::Grid) {
+    let map_size = grid.size();
+    let thickness = config.border.thickness;
+    // Horizontal borders also cover the corners
+    let hor_size = Vec2::new(map_size.x + 2.0 * thickness, thickness);
+    let ver_size = Vec2::new(thickness, map_size.y);
+
     // Spawn top border
     commands.spawn((
+        round::RoundEntity,
         Sprite {
             color: MAIN_COLOR,
-            custom_size: Some(HOR_SIZE),
+            custom_size: Some(hor_size),
             ..Default::default()
         },
-        Transform::from_xyz(0.0, (map::SIZE.y / 2.0) + (HOR_SIZE.y / 2.0), 0.0),
+        Transform::from_xyz(0.0, (map_size.y / 2.0) + (hor_size.y / 2.0), 0.0),
         RigidBody::Fixed,
-        Collider::cuboid(HOR_SIZE.x / 2.0, HOR_SIZE.y / 2.0),
+        Collider::cuboid(hor_size.x / 2.0, hor_size.y / 2.0),
         Friction::new(FRICTION),
     ));
 
     // Spawn bottom border
     commands.spawn((
+        round::RoundEntity,
         Sprite {
             color: MAIN_COLOR,
-            custom_size: Some(HOR_SIZE),
+            custom_size: Some(hor_size),
             ..Default::default()
         },
-        Transform::from_xyz(0.0, -(map::SIZE.y / 2.0) - (HOR_SIZE.y / 2.0), 0.0),
+        Transform::from_xyz(0.0, -(map_size.y / 2.0) - (hor_size.y / 2.0), 0.0),
         RigidBody::Fixed,
-        Collider::cuboid(HOR_SIZE.x / 2.0, HOR_SIZE.y / 2.0),
+        Collider::cuboid(hor_size.x / 2.0, hor_size.y / 2.0),
         Friction::new(FRICTION),
     ));
 
     // Spawn left border
     commands.spawn((
+        round::RoundEntity,
         Sprite {
             color: MAIN_COLOR,
-            custom_size: Some(VER_SIZE),
+            custom_size: Some(ver_size),
             ..Default::default()
         },
-        Transform::from_xyz(-(map::SIZE.x / 2.0) - (VER_SIZE.x / 2.0), 0.0, 0.0),
+        Transform::from_xyz(-(map_size.x / 2.0) - (ver_size.x / 2.0), 0.0, 0.0),
         RigidBody::Fixed,
-        Collider::cuboid(VER_SIZE.x / 2.0, VER_SIZE.y / 2.0),
+        Collider::cuboid(ver_size.x / 2.0, ver_size.y / 2.0),
         Friction::new(FRICTION),
     ));
 
     // Spawn right border
     commands.spawn((
+        round::RoundEntity,
         Sprite {
             color: MAIN_COLOR,
-            custom_size: Some(VER_SIZE),
+            custom_size: Some(ver_size),
             ..Default::default()
         },
-        Transform::from_xyz((map::SIZE.x / 2.0) + (VER_SIZE.x / 2.0), 0.0, 0.0),
+        Transform::from_xyz((map_size.x / 2.0) + (ver_size.x / 2.0), 0.0, 0.0),
         RigidBody::Fixed,
-        Collider::cuboid(VER_SIZE.x / 2.0, VER_SIZE.y / 2.0),
+        Collider::cuboid(ver_size.x / 2.0, ver_size.y / 2.0),
         Friction::new(FRICTION),
     ));
 }
\ No newline at end of file
@@ -1,83 +1,98 @@
+use std::collections::BTreeMap;
+
 use bevy::prelude::*;
 use bevy_rapier2d::prelude::*;
 
+use super::block;
+use super::collision;
 use super::explosion;
 use super::map;
-use super::map::Cell;
+use super::round;
+use crate::abtestbed::config::GameConfig;
 
 const FRICTION: f32 = 0.0;
-const SIZE: Vec2 = Vec2::new(40.0, 36.0);
-const MAIN_COLOR: Color = Color::srgb(0.1, 0.1, 0.7);
+pub const MAIN_COLOR: Color = Color::srgb(0.1, 0.1, 0.7);
 
 pub struct BrickPlugin;
 
 impl Plugin for BrickPlugin {
     fn build(&self, app: &mut App) {
-        app.add_systems(Startup, spawn_bricks)
-            .add_systems(Update, track_explosion_bricks);
+        app.add_event::<BrickDestroyed>()
+            .add_systems(
+                OnEnter(round::GameState::RoundStarting),
+                spawn_bricks.after(block::spawn_blocks),
+            )
+            .add_systems(
+                FixedUpdate,
+                track_explosion_bricks
+                    .after(explosion::spawn_explosion)
+                    .run_if(round::round_running),
+            );
     }
 }
 
 #[derive(Component)]
 pub struct Brick;
 
-fn spawn_bricks(mut commands: Commands, map_state: Res<map::MapState>) {
-    for j in 0..map::NET_SIZE.1 {
-        for i in 0..map::NET_SIZE.0 {
-            if map_state.scheme[j as usize][i as usize] != map::legend::BRICK {
-                continue;
-            }
+#[derive(Event)]
+pub struct BrickDestroyed {
+    pub cell: map::Cell,
+}
 
-            commands.spawn((
-                Brick,
-                Sprite {
-                    color: MAIN_COLOR,
-                    custom_size: Some(SIZE),
-                    ..default()
-                },
-                Transform::from_xyz(
-                    map::CELL_START_POS.x + (i as f32 * map::CELL_SIZE.x),
-                    map::CELL_START_POS.y - (j as f32 * map::CELL_SIZE.y),
-                    0.0,
-                ),
-                RigidBody::KinematicPositionBased,
-                Collider::cuboid(SIZE.x / 2.0, SIZE.y / 2.0),
-                ActiveEvents::COLLISION_EVENTS,
-                Friction::new(FRICTION),
-            ));
+pub fn spawn_bricks(mut commands: Commands, map_state: Res<map::MapState>, config: Res<GameConfig>) {
+    for (cell, tile) in map_state.scheme.cells() {
+        if tile != map::legend::BRICK {
+            continue;
         }
+
+        spawn_brick(&mut commands, &config, &map_state.scheme, cell);
     }
 }
 
-fn track_explosion_bricks(
+pub fn spawn_brick(
+    commands: &mut Commands,
+    config: &GameConfig,
+    grid: &map::Grid,
+    cell: map::Cell,
+) {
+    let size: Vec2 = config.brick.size.into();
+
+    commands.spawn((
+        round::RoundEntity,
+        Brick,
+        Sprite {
+            color: MAIN_COLOR,
+            custom_size: Some(size),
+            ..default()
+        },
+        cell.center(grid),
+        RigidBody::KinematicPositionBased,
+        Collider::cuboid(size.x / 2.0, size.y / 2.0),
+        Friction::new(FRICTION),
+    ));
+}
+
+pub fn track_explosion_bricks(
     mut commands: Commands,
-    mut collision_events: EventReader<CollisionEvent>,
-    explosions: Query<(), With<explosion::Explosion>>,
-    bricks: Query<(Entity, &Transform, &Brick)>,
+    mut hits: EventReader<collision::ExplosionHitBrick>,
+    bricks: Query<&Transform, With<Brick>>,
     mut map_state: ResMut<map::MapState>,
+    mut brick_destroyed_events: EventWriter<BrickDestroyed>,
 ) {
-    for collision_event in collision_events.read() {
-        match collision_event {
-            CollisionEvent::Started(e1, e2, _) => {
-                let brick_entity;
+    // Ordered by cell, as every destroyed brick rolls for a powerup
+    let burnt: BTreeMap<(u8, u8), (map::Cell, Entity)> = hits
+        .read()
+        .filter_map(|hit| {
+            let transform = bricks.get(hit.brick).ok()?;
+            let cell = map::Cell::from_transform(&map_state.scheme, transform);
+            Some(((cell.1, cell.0), (cell, hit.brick)))
+        })
+        .collect();
 
-                if explosions.get(*e1).is_ok() && bricks.get(*e2).is_ok() {
-                    brick_entity = e2;
-                } else if explosions.get(*e2).is_ok() && bricks.get(*e1).is_ok() {
-                    brick_entity = e1;
-                } else {
-                    return;
-                }
+    for (cell, brick) in burnt.into_values() {
+        map_state.set_tile(cell, map::legend::EMPTY);
+        commands.entity(brick).despawn();
 
-                for (e, t, _) in &bricks {
-                    if e.index() == brick_entity.index() {
-                        let cell = Cell::from_transform(&t);
-                        map_state.scheme[cell.0 as usize][cell.1 as usize] = map::legend::EMPTY;
-                        commands.entity(*brick_entity).despawn();
-                    }
-                }
-            }
-            _ => {}
-        }
+        brick_destroyed_events.send(BrickDestroyed { cell });
     }
 }
@@ -0,0 +1,265 @@
+//! Turns contacts between entities into typed gameplay events. Players are
+//! checked against the bombs they may walk through by their shapes, while
+//! fire, which has no collider, touches whatever shares its cell: players,
+//! bombs, powerups and bricks. Players pick up the powerups on their cell, and
+//! a block dropped in sudden death lands on everything in the cell it fills.
+//!
+//! Everything is worked out anew every tick from where the entities are.
+//! Rapier's contact events are not used, as the contacts they track carry
+//! over from one tick to the next where a world snapshot cannot hold them.
+
+use std::collections::HashMap;
+
+use bevy::prelude::*;
+use bevy_rapier2d::prelude::*;
+
+use super::bomb::{self, Bomb};
+use super::brick;
+use super::explosion::{self, Explosion};
+use super::map;
+use super::player::{self, Player};
+use super::powerup::{self, Powerup};
+use super::round;
+use super::sudden_death;
+use crate::abtestbed::config::GameConfig;
+use crate::abtestbed::setup::SimTick;
+
+pub struct CollisionPlugin;
+
+impl Plugin for CollisionPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_event::<PlayerLeftBomb>()
+            .add_event::<ExplosionHitPlayer>()
+            .add_event::<ExplosionHitBomb>()
+            .add_event::<ExplosionHitPowerup>()
+            .add_event::<ExplosionHitBrick>()
+            .add_event::<PlayerTouchedPowerup>()
+            .add_event::<BlockHitPlayer>()
+            .add_event::<BlockHitBomb>()
+            .add_event::<BlockHitBrick>()
+            .add_event::<BlockHitPowerup>()
+            .add_systems(
+                FixedUpdate,
+                (
+                    route_overlaps
+                        .after(bomb::detonate_bombs)
+                        .before(bomb::explode_bombs),
+                    route_explosions
+                        .after(explosion::spawn_explosion)
+                        .before(brick::track_explosion_bricks)
+                        .before(player::track_explosion_players),
+                    route_pickups
+                        .after(powerup::reveal_powerups)
+                        .before(powerup::collect_powerups),
+                    route_block_drops
+                        .after(sudden_death::drop_blocks)
+                        .before(sudden_death::crush_under_blocks),
+                )
+                    .run_if(round::round_running),
+            );
+    }
+}
+
+/// Nobody overlaps a bomb players could walk through any more.
+#[derive(Event)]
+pub struct PlayerLeftBomb {
+    pub bomb: Entity,
+}
+
+/// Sent every tick the player stands in the fire.
+#[derive(Event)]
+pub struct ExplosionHitPlayer {
+    pub player: Entity,
+}
+
+/// Read on the tick after the one it was routed in, once the fire spawned by
+/// the blasts of that tick had its say.
+#[derive(Event)]
+pub struct ExplosionHitBomb {
+    pub bomb: Entity,
+    pub tick: u64,
+}
+
+#[derive(Event)]
+pub struct ExplosionHitPowerup {
+    pub explosion: Entity,
+    pub powerup: Entity,
+}
+
+#[derive(Event)]
+pub struct ExplosionHitBrick {
+    pub brick: Entity,
+}
+
+/// Sent every tick the player stands on the cell of the powerup.
+#[derive(Event)]
+pub struct PlayerTouchedPowerup {
+    pub player: Entity,
+    pub powerup: Entity,
+}
+
+#[derive(Event)]
+pub struct BlockHitPlayer {
+    pub player: Entity,
+}
+
+/// Bombs flying over the cell are not hit, carried ones are hit along with
+/// their carrier.
+#[derive(Event)]
+pub struct BlockHitBomb {
+    pub bomb: Entity,
+}
+
+#[derive(Event)]
+pub struct BlockHitBrick {
+    pub brick: Entity,
+}
+
+#[derive(Event)]
+pub struct BlockHitPowerup {
+    pub powerup: Entity,
+}
+
+fn route_overlaps(
+    players: Query<&Transform, With<Player>>,
+    bombs: Query<(Entity, &Bomb, &Transform), With<Sensor>>,
+    config: Res<GameConfig>,
+    mut left_events: EventWriter<PlayerLeftBomb>,
+) {
+    // Both colliders are boxes that never turn
+    let reach = (Vec2::from(config.bomb.size) + Vec2::from(config.player.size)) / 2.0;
+
+    for (bomb, b, bomb_transform) in &bombs {
+        if b.is_airborne() {
+            continue;
+        }
+
+        let overlapped = players.iter().any(|transform| {
+            let gap = (transform.translation - bomb_transform.translation)
+                .truncate()
+                .abs();
+            gap.x < reach.x && gap.y < reach.y
+        });
+
+        if !overlapped {
+            left_events.send(PlayerLeftBomb { bomb });
+        }
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+fn route_explosions(
+    explosions: Query<(Entity, &Explosion)>,
+    players: Query<(Entity, &Transform), With<Player>>,
+    bombs: Query<(Entity, &Bomb)>,
+    powerups: Query<(Entity, &Powerup)>,
+    bricks: Query<(Entity, &Transform), With<brick::Brick>>,
+    map_state: Res<map::MapState>,
+    tick: Res<SimTick>,
+    mut player_hits: EventWriter<ExplosionHitPlayer>,
+    mut bomb_hits: EventWriter<ExplosionHitBomb>,
+    mut powerup_hits: EventWriter<ExplosionHitPowerup>,
+    mut brick_hits: EventWriter<ExplosionHitBrick>,
+) {
+    let mut burning_cells: HashMap<map::Cell, Vec<Entity>> = HashMap::new();
+    for (entity, explosion) in &explosions {
+        burning_cells
+            .entry(explosion.cell)
+            .or_default()
+            .push(entity);
+    }
+
+    if burning_cells.is_empty() {
+        return;
+    }
+
+    for (player, transform) in &players {
+        let cell = map::Cell::from_transform(&map_state.scheme, transform);
+
+        if burning_cells.contains_key(&cell) {
+            player_hits.send(ExplosionHitPlayer { player });
+        }
+    }
+
+    for (bomb, b) in &bombs {
+        if burning_cells.contains_key(&b.cell) {
+            bomb_hits.send(ExplosionHitBomb { bomb, tick: tick.0 });
+        }
+    }
+
+    for (powerup, p) in &powerups {
+        for explosion in burning_cells.get(&p.cell).into_iter().flatten() {
+            powerup_hits.send(ExplosionHitPowerup {
+                explosion: *explosion,
+                powerup,
+            });
+        }
+    }
+
+    for (brick, transform) in &bricks {
+        let cell = map::Cell::from_transform(&map_state.scheme, transform);
+
+        if burning_cells.contains_key(&cell) {
+            brick_hits.send(ExplosionHitBrick { brick });
+        }
+    }
+}
+
+fn route_pickups(
+    players: Query<(Entity, &Transform), With<Player>>,
+    powerups: Query<(Entity, &Powerup)>,
+    map_state: Res<map::MapState>,
+    mut touches: EventWriter<PlayerTouchedPowerup>,
+) {
+    for (player, transform) in &players {
+        let cell = map::Cell::from_transform(&map_state.scheme, transform);
+
+        for (powerup, p) in &powerups {
+            if p.cell == cell {
+                touches.send(PlayerTouchedPowerup { player, powerup });
+            }
+        }
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+fn route_block_drops(
+    mut drops: EventReader<sudden_death::BlockDropped>,
+    players: Query<(Entity, &Transform), With<Player>>,
+    bombs: Query<(Entity, &Bomb)>,
+    bricks: Query<(Entity, &Transform), With<brick::Brick>>,
+    powerups: Query<(Entity, &Powerup)>,
+    map_state: Res<map::MapState>,
+    mut player_hits: EventWriter<BlockHitPlayer>,
+    mut bomb_hits: EventWriter<BlockHitBomb>,
+    mut brick_hits: EventWriter<BlockHitBrick>,
+    mut powerup_hits: EventWriter<BlockHitPowerup>,
+) {
+    let grid = &map_state.scheme;
+
+    for drop in drops.read() {
+        for (player, transform) in &players {
+            if map::Cell::from_transform(grid, transform) == drop.cell {
+                player_hits.send(BlockHitPlayer { player });
+            }
+        }
+
+        for (bomb, b) in &bombs {
+            if b.cell == drop.cell && !b.is_flying() {
+                bomb_hits.send(BlockHitBomb { bomb });
+            }
+        }
+
+        for (brick, transform) in &bricks {
+            if map::Cell::from_transform(grid, transform) == drop.cell {
+                brick_hits.send(BlockHitBrick { brick });
+            }
+        }
+
+        for (powerup, p) in &powerups {
+            if p.cell == drop.cell {
+                powerup_hits.send(BlockHitPowerup { powerup });
+            }
+        }
+    }
+}
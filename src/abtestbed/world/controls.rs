@@ -0,0 +1,336 @@
+//! What the players press: keys, gamepad buttons and stick directions bound
+//! to the actions of a player, read from a RON file such as
+//!
+//! ```ron
+//! (
+//!     keyboard: [
+//!         (north: [Key(KeyI)], south: [Key(KeyK)], west: [Key(KeyJ)], east: [Key(KeyL)],
+//!          bomb: [Key(KeyN)], detonate: [Key(KeyM)]),
+//!     ],
+//! )
+//! ```
+//!
+//! Every keyboard control set is a layout for one more player at the keyboard,
+//! while all gamepads share the gamepad set. Gamepads take the first free seat
+//! when they are plugged in and keep it while they are away.
+
+use std::fmt;
+use std::fs;
+use std::path::Path;
+
+use bevy::input::gamepad::{GamepadConnection, GamepadConnectionEvent};
+use bevy::input::InputSystem;
+use bevy::prelude::*;
+use serde::{Deserialize, Serialize};
+
+use super::player::InputState;
+
+// How far a stick has to lean before it counts as pressed
+pub const STICK_DEADZONE: f32 = 0.5;
+
+pub struct ControlsPlugin;
+
+impl Plugin for ControlsPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<Bindings>()
+            .init_resource::<GamepadSeats>()
+            .add_systems(PreUpdate, seat_gamepads.after(InputSystem));
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Binding {
+    Key(KeyCode),
+    Button(GamepadButton),
+    // A stick leaning towards the positive or negative end of an axis
+    Axis { axis: GamepadAxis, positive: bool },
+}
+
+impl Binding {
+    pub fn pressed(&self, keys: &ButtonInput<KeyCode>, gamepad: Option<&Gamepad>) -> bool {
+        match (self, gamepad) {
+            (Binding::Key(key), _) => keys.pressed(*key),
+            (Binding::Button(button), Some(gamepad)) => gamepad.pressed(*button),
+            (Binding::Axis { axis, positive }, Some(gamepad)) => {
+                let value = gamepad.get(*axis).unwrap_or_default();
+                if *positive {
+                    value >= STICK_DEADZONE
+                } else {
+                    value <= -STICK_DEADZONE
+                }
+            }
+            (_, None) => false,
+        }
+    }
+
+    /// Whether the binding went down this frame. Sticks have no such moment
+    /// and never count.
+    pub fn just_pressed(&self, keys: &ButtonInput<KeyCode>, gamepad: Option<&Gamepad>) -> bool {
+        match (self, gamepad) {
+            (Binding::Key(key), _) => keys.just_pressed(*key),
+            (Binding::Button(button), Some(gamepad)) => gamepad.just_pressed(*button),
+            _ => false,
+        }
+    }
+}
+
+impl fmt::Display for Binding {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Binding::Key(key) => write!(f, "{:?}", key),
+            Binding::Button(button) => write!(f, "{:?}", button),
+            Binding::Axis { axis, positive } => {
+                write!(f, "{:?}{}", axis, if *positive { '+' } else { '-' })
+            }
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Action {
+    North,
+    South,
+    West,
+    East,
+    Bomb,
+    Detonate,
+}
+
+impl Action {
+    pub const ALL: [Action; 6] = [
+        Action::North,
+        Action::South,
+        Action::West,
+        Action::East,
+        Action::Bomb,
+        Action::Detonate,
+    ];
+}
+
+/// The bindings of every action of one player, any of which will do.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ControlSet {
+    pub north: Vec<Binding>,
+    pub south: Vec<Binding>,
+    pub west: Vec<Binding>,
+    pub east: Vec<Binding>,
+    pub bomb: Vec<Binding>,
+    // Optional in the file, bindings saved before trigger bombs have none
+    #[serde(default)]
+    pub detonate: Vec<Binding>,
+}
+
+impl ControlSet {
+    fn keys([north, south, west, east, bomb, detonate]: [KeyCode; 6]) -> Self {
+        ControlSet {
+            north: vec![Binding::Key(north)],
+            south: vec![Binding::Key(south)],
+            west: vec![Binding::Key(west)],
+            east: vec![Binding::Key(east)],
+            bomb: vec![Binding::Key(bomb)],
+            detonate: vec![Binding::Key(detonate)],
+        }
+    }
+
+    pub fn get(&self, action: Action) -> &Vec<Binding> {
+        match action {
+            Action::North => &self.north,
+            Action::South => &self.south,
+            Action::West => &self.west,
+            Action::East => &self.east,
+            Action::Bomb => &self.bomb,
+            Action::Detonate => &self.detonate,
+        }
+    }
+
+    pub fn get_mut(&mut self, action: Action) -> &mut Vec<Binding> {
+        match action {
+            Action::North => &mut self.north,
+            Action::South => &mut self.south,
+            Action::West => &mut self.west,
+            Action::East => &mut self.east,
+            Action::Bomb => &mut self.bomb,
+            Action::Detonate => &mut self.detonate,
+        }
+    }
+
+    pub fn read(&self, keys: &ButtonInput<KeyCode>, gamepad: Option<&Gamepad>) -> InputState {
+        let pressed = |action| {
+            self.get(action)
+                .iter()
+                .any(|binding| binding.pressed(keys, gamepad))
+        };
+        let just_pressed = |action| {
+            self.get(action)
+                .iter()
+                .any(|binding| binding.just_pressed(keys, gamepad))
+        };
+
+        InputState {
+            horizontal_direction: pressed(Action::East) as i8 - pressed(Action::West) as i8,
+            vertical_direction: pressed(Action::North) as i8 - pressed(Action::South) as i8,
+            plant_bomb: just_pressed(Action::Bomb),
+            detonate: just_pressed(Action::Detonate),
+        }
+    }
+}
+
+#[derive(Resource, Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Bindings {
+    // One layout per player sharing the keyboard
+    pub keyboard: Vec<ControlSet>,
+    pub gamepad: ControlSet,
+}
+
+impl std::default::Default for Bindings {
+    fn default() -> Self {
+        let axis = |axis, positive| Binding::Axis { axis, positive };
+
+        Bindings {
+            keyboard: vec![
+                ControlSet::keys([
+                    KeyCode::ArrowUp,
+                    KeyCode::ArrowDown,
+                    KeyCode::ArrowLeft,
+                    KeyCode::ArrowRight,
+                    KeyCode::Space,
+                    KeyCode::ControlRight,
+                ]),
+                ControlSet::keys([
+                    KeyCode::KeyW,
+                    KeyCode::KeyS,
+                    KeyCode::KeyA,
+                    KeyCode::KeyD,
+                    KeyCode::KeyV,
+                    KeyCode::KeyB,
+                ]),
+            ],
+            gamepad: ControlSet {
+                north: vec![
+                    Binding::Button(GamepadButton::DPadUp),
+                    axis(GamepadAxis::LeftStickY, true),
+                ],
+                south: vec![
+                    Binding::Button(GamepadButton::DPadDown),
+                    axis(GamepadAxis::LeftStickY, false),
+                ],
+                west: vec![
+                    Binding::Button(GamepadButton::DPadLeft),
+                    axis(GamepadAxis::LeftStickX, false),
+                ],
+                east: vec![
+                    Binding::Button(GamepadButton::DPadRight),
+                    axis(GamepadAxis::LeftStickX, true),
+                ],
+                bomb: vec![Binding::Button(GamepadButton::South)],
+                detonate: vec![Binding::Button(GamepadButton::East)],
+            },
+        }
+    }
+}
+
+/// Where the bindings were loaded from, and where the controls screen saves them.
+#[derive(Resource)]
+pub struct BindingsPath(pub std::path::PathBuf);
+
+#[derive(Debug)]
+pub enum BindingsError {
+    Io(std::io::Error),
+    Syntax(ron::error::SpannedError),
+    Encoding(ron::Error),
+}
+
+impl fmt::Display for BindingsError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BindingsError::Io(err) => write!(f, "{}", err),
+            BindingsError::Syntax(err) => write!(f, "{}", err),
+            BindingsError::Encoding(err) => write!(f, "failed to encode bindings: {}", err),
+        }
+    }
+}
+
+impl std::error::Error for BindingsError {}
+
+impl From<std::io::Error> for BindingsError {
+    fn from(err: std::io::Error) -> Self {
+        BindingsError::Io(err)
+    }
+}
+
+impl From<ron::error::SpannedError> for BindingsError {
+    fn from(err: ron::error::SpannedError) -> Self {
+        BindingsError::Syntax(err)
+    }
+}
+
+impl From<ron::Error> for BindingsError {
+    fn from(err: ron::Error) -> Self {
+        BindingsError::Encoding(err)
+    }
+}
+
+impl Bindings {
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, BindingsError> {
+        Ok(ron::from_str(&fs::read_to_string(path)?)?)
+    }
+
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<(), BindingsError> {
+        let source = ron::ser::to_string_pretty(self, ron::ser::PrettyConfig::default())?;
+        fs::write(path, source)?;
+
+        Ok(())
+    }
+}
+
+/// Gamepads in the order they joined; `Controls::Gamepad(n)` plays with seat n.
+#[derive(Resource, Default)]
+pub struct GamepadSeats {
+    seats: Vec<Entity>,
+}
+
+impl GamepadSeats {
+    pub fn gamepad<'a>(&self, seat: usize, gamepads: &'a Query<&Gamepad>) -> Option<&'a Gamepad> {
+        self.seats
+            .get(seat)
+            .and_then(|entity| gamepads.get(*entity).ok())
+    }
+}
+
+fn seat_gamepads(
+    mut events: EventReader<GamepadConnectionEvent>,
+    mut seats: ResMut<GamepadSeats>,
+    gamepads: Query<(), With<Gamepad>>,
+) {
+    for event in events.read() {
+        let GamepadConnection::Connected { name, .. } = &event.connection else {
+            continue;
+        };
+
+        // A gamepad coming back finds its seat kept, a new one takes the seat
+        // of a gamepad that is gone or a new seat
+        if let Some(seat) = seats.seats.iter().position(|e| *e == event.gamepad) {
+            info!("Gamepad {} is back in seat {}", name, seat + 1);
+            continue;
+        }
+
+        let free_seat = seats
+            .seats
+            .iter()
+            .position(|entity| !gamepads.contains(*entity));
+
+        let seat = match free_seat {
+            Some(seat) => {
+                seats.seats[seat] = event.gamepad;
+                seat
+            }
+            None => {
+                seats.seats.push(event.gamepad);
+                seats.seats.len() - 1
+            }
+        };
+
+        info!("Gamepad {} took seat {}", name, seat + 1);
+    }
+}
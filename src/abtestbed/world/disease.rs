@@ -0,0 +1,231 @@
+//! Curses handed out by the disease item. A curse lasts for a while and passes
+//! on to every healthy player the cursed player runs into.
+
+use bevy::prelude::*;
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use super::player::{InputState, Player};
+use super::powerup;
+use super::round;
+use crate::abtestbed::config::{DiseaseConfig, GameConfig};
+use crate::abtestbed::setup::{self, SimTick};
+
+pub const KINDS: usize = 9;
+
+// How long each colour shows while a cursed player blinks
+const BLINK_PERIOD: f32 = 0.25;
+
+pub struct DiseasePlugin;
+
+impl Plugin for DiseasePlugin {
+    fn build(&self, app: &mut App) {
+        app.add_event::<DiseaseCaught>().add_systems(
+            FixedUpdate,
+            (
+                catch_diseases,
+                spread_diseases,
+                cure_players,
+                blink_cursed_players,
+            )
+                .chain()
+                .after(powerup::collect_powerups)
+                .run_if(round::round_running),
+        );
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum CurseKind {
+    ReversedControls,
+    Molasses,
+    Crack,
+    ShortFuse,
+    LongFuse,
+    MinimumFlame,
+    Constipation,
+    Diarrhea,
+    // Takes effect once, when caught
+    SwapPositions,
+}
+
+impl CurseKind {
+    pub const ALL: [CurseKind; KINDS] = [
+        CurseKind::ReversedControls,
+        CurseKind::Molasses,
+        CurseKind::Crack,
+        CurseKind::ShortFuse,
+        CurseKind::LongFuse,
+        CurseKind::MinimumFlame,
+        CurseKind::Constipation,
+        CurseKind::Diarrhea,
+        CurseKind::SwapPositions,
+    ];
+}
+
+#[derive(Component, Debug, Copy, Clone, Serialize, Deserialize)]
+pub struct Curse {
+    pub kind: CurseKind,
+    // Tick the curse wears off at
+    expires_at: u64,
+}
+
+impl Curse {
+    /// What the cursed player actually does when pressing `inputs`.
+    pub fn apply(&self, inputs: &mut InputState) {
+        match self.kind {
+            CurseKind::ReversedControls => {
+                inputs.horizontal_direction = -inputs.horizontal_direction;
+                inputs.vertical_direction = -inputs.vertical_direction;
+            }
+            CurseKind::Constipation => inputs.plant_bomb = false,
+            CurseKind::Diarrhea => inputs.plant_bomb = true,
+            _ => {}
+        }
+    }
+
+    pub fn speed(&self, speed: f32, config: &DiseaseConfig) -> f32 {
+        match self.kind {
+            CurseKind::Molasses => config.molasses_speed,
+            CurseKind::Crack => config.crack_speed,
+            _ => speed,
+        }
+    }
+
+    pub fn detonation_period(&self, period: f32, config: &DiseaseConfig) -> f32 {
+        match self.kind {
+            CurseKind::ShortFuse => config.short_fuse,
+            CurseKind::LongFuse => config.long_fuse,
+            _ => period,
+        }
+    }
+
+    pub fn fire_range(&self, range: u8) -> u8 {
+        match self.kind {
+            CurseKind::MinimumFlame => 1,
+            _ => range,
+        }
+    }
+}
+
+/// Sent when a player picks up a disease item.
+#[derive(Event)]
+pub struct DiseaseCaught {
+    pub player: Entity,
+}
+
+fn catch_diseases(
+    mut commands: Commands,
+    mut events: EventReader<DiseaseCaught>,
+    tick: Res<SimTick>,
+    config: Res<GameConfig>,
+    mut rng: ResMut<setup::GameRng>,
+    mut players: Query<(Entity, &Player, &mut Transform)>,
+) {
+    for event in events.read() {
+        let kind = CurseKind::ALL[rng.0.gen_range(0..KINDS)];
+
+        let Ok((_, player, _)) = players.get(event.player) else {
+            continue;
+        };
+
+        info!("{:?} caught {:?}", player.color, kind);
+
+        if kind != CurseKind::SwapPositions {
+            commands.entity(event.player).insert(Curse {
+                kind,
+                expires_at: tick.0 + config.ticks(config.disease.duration),
+            });
+            continue;
+        }
+
+        let mut others: Vec<(Uuid, Entity)> = players
+            .iter()
+            .map(|(entity, other, _)| (other.id, entity))
+            .filter(|(_, entity)| *entity != event.player)
+            .collect();
+        others.sort();
+
+        if others.is_empty() {
+            continue;
+        }
+
+        let (_, other) = others[rng.0.gen_range(0..others.len())];
+        let Ok([(_, _, mut transform), (_, _, mut other_transform)]) =
+            players.get_many_mut([event.player, other])
+        else {
+            continue;
+        };
+
+        std::mem::swap(&mut transform.translation, &mut other_transform.translation);
+    }
+}
+
+// Curses pass on to healthy players overlapping a cursed one, with a fresh timeout
+fn spread_diseases(
+    mut commands: Commands,
+    tick: Res<SimTick>,
+    config: Res<GameConfig>,
+    cursed: Query<(&Curse, &Player, &Transform)>,
+    healthy: Query<(Entity, &Player, &Transform), Without<Curse>>,
+) {
+    let size = Vec2::from(config.player.size);
+    let mut infected = Vec::new();
+
+    // A player touching several cursed ones catches the curse of the lowest id
+    let mut cursed: Vec<(&Curse, &Player, &Transform)> = cursed.iter().collect();
+    cursed.sort_by_key(|(_, player, _)| player.id);
+
+    for (curse, _, transform) in cursed {
+        for (entity, player, other_transform) in &healthy {
+            let distance = (transform.translation - other_transform.translation)
+                .truncate()
+                .abs();
+            if distance.x >= size.x || distance.y >= size.y || infected.contains(&entity) {
+                continue;
+            }
+
+            info!("{:?} caught {:?}", player.color, curse.kind);
+
+            infected.push(entity);
+            commands.entity(entity).insert(Curse {
+                kind: curse.kind,
+                expires_at: tick.0 + config.ticks(config.disease.duration),
+            });
+        }
+    }
+}
+
+fn cure_players(
+    mut commands: Commands,
+    tick: Res<SimTick>,
+    mut players: Query<(Entity, &Player, &Curse, &mut Sprite)>,
+) {
+    for (entity, player, curse, mut sprite) in &mut players {
+        if tick.0 < curse.expires_at {
+            continue;
+        }
+
+        info!("{:?} got over {:?}", player.color, curse.kind);
+
+        sprite.color = player.color.to_bevy_color();
+        commands.entity(entity).remove::<Curse>();
+    }
+}
+
+pub fn blink_cursed_players(
+    tick: Res<SimTick>,
+    config: Res<GameConfig>,
+    mut players: Query<(&Player, &mut Sprite), With<Curse>>,
+) {
+    let blink = (tick.0 / config.ticks(BLINK_PERIOD).max(1)) % 2 == 1;
+
+    for (player, mut sprite) in &mut players {
+        sprite.color = if blink {
+            powerup::PowerupKind::Disease.to_bevy_color()
+        } else {
+            player.color.to_bevy_color()
+        };
+    }
+}
@@ -1,146 +1,129 @@
-use std::time::Duration;
-
 use bevy::prelude::*;
-use bevy_rapier2d::prelude::*;
+use serde::{Deserialize, Serialize};
 
 use super::bomb;
 use super::map;
+use super::player;
+use super::round;
+use crate::abtestbed::config::GameConfig;
+use crate::abtestbed::setup::SimTick;
 
-const SIZE: Vec2 = Vec2::new(36.0, 32.0);
-const DEFAULT_EXPLOSION_PERIOD: f32 = 0.8;
+// North, south, west and east, with `dy` pointing north
+pub const DIRECTIONS: [(i8, i8); 4] = [(0, 1), (0, -1), (-1, 0), (1, 0)];
 
 pub struct ExplosionPlugin;
 
 impl Plugin for ExplosionPlugin {
     fn build(&self, app: &mut App) {
-        app.add_systems(Update, (spawn_explosion, extingush_explosion));
+        app.add_systems(
+            FixedUpdate,
+            (
+                spawn_explosion.after(bomb::explode_bombs),
+                extinguish_explosion
+                    .after(bomb::explode_bombs)
+                    .before(spawn_explosion),
+            )
+                .run_if(round::round_running),
+        );
     }
 }
 
-#[derive(Component)]
+#[derive(Component, Clone, Serialize, Deserialize)]
 pub struct Explosion {
-    extinguish_at: Duration,
+    pub player_color: player::PlayerColor,
+    pub cell: map::Cell,
+    lit_at: u64,
+    extinguish_at: u64,
 }
 
-fn spawn_explosion(
-    mut commands: Commands,
-    mut bomb_exploded_events: EventReader<bomb::BombExploded>,
-    time: Res<Time>,
-    map_state: Res<map::MapState>,
-) {
-    for be_event in bomb_exploded_events.read() {
-        let mut cells = Vec::<map::Cell>::new();
-
-        // Generate middle
-        cells.push(be_event.bomb_cell);
-
-        // Generate north side
-        let x = std::cmp::max(
-            0,
-            be_event.bomb_cell.1 as i8 - be_event.bomb_fire_range as i8,
-        ) as u8;
-        let y = be_event.bomb_cell.1;
-        for j in (x..y).rev() {
-            let cell = map::Cell(be_event.bomb_cell.0, j);
+impl Explosion {
+    pub fn lit_at(&self) -> u64 {
+        self.lit_at
+    }
+}
 
-            if map_state.scheme[cell.0 as usize][cell.1 as usize] == map::legend::BLOCK {
-                break;
-            }
+/// Cells covered by a blast: the centre and up to `fire_range` cells in each
+/// direction. An arm stops before a block and on the first brick.
+pub fn blast_cells(map_state: &map::MapState, center: map::Cell, fire_range: u8) -> Vec<map::Cell> {
+    let mut cells = vec![center];
 
-            cells.push(cell);
+    for (dx, dy) in DIRECTIONS {
+        let mut cell = center;
 
-            if map_state.scheme[cell.0 as usize][cell.1 as usize] == map::legend::BRICK {
+        for _ in 0..fire_range {
+            let Some(next_cell) = cell.neighbour(&map_state.scheme, dx, dy) else {
                 break;
-            }
-        }
+            };
 
-        // Generate south side
-        let x = be_event.bomb_cell.1 + 1;
-        let y = std::cmp::min(
-            map::NET_SIZE.1 - 1,
-            be_event.bomb_cell.1 + be_event.bomb_fire_range,
-        ) + 1;
-        for j in x..y {
-            let cell = map::Cell(be_event.bomb_cell.0, j);
+            cell = next_cell;
 
-            if map_state.scheme[cell.0 as usize][cell.1 as usize] == map::legend::BLOCK {
+            let tile = map_state.tile(cell);
+            if tile == map::legend::BLOCK {
                 break;
             }
 
             cells.push(cell);
 
-            if map_state.scheme[cell.0 as usize][cell.1 as usize] == map::legend::BRICK {
+            if tile == map::legend::BRICK {
                 break;
             }
         }
+    }
 
-        // Generate west side
-        let x = std::cmp::max(
-            0,
-            be_event.bomb_cell.0 as i8 - be_event.bomb_fire_range as i8,
-        ) as u8;
-        let y = be_event.bomb_cell.0;
-        for i in (x..y).rev() {
-            let cell = map::Cell(i, be_event.bomb_cell.1);
-
-            if map_state.scheme[cell.0 as usize][cell.1 as usize] == map::legend::BLOCK {
-                break;
-            }
-
-            cells.push(cell);
-
-            if map_state.scheme[cell.0 as usize][cell.1 as usize] == map::legend::BRICK {
-                break;
-            }
-        }
-
-        // Generate east side
-        let x = be_event.bomb_cell.0 + 1;
-        let y = std::cmp::min(
-            map::NET_SIZE.0 - 1,
-            be_event.bomb_cell.0 + be_event.bomb_fire_range,
-        ) + 1;
-        for i in x..y {
-            let cell = map::Cell(i, be_event.bomb_cell.1);
-
-            if map_state.scheme[cell.0 as usize][cell.1 as usize] == map::legend::BLOCK {
-                break;
-            }
-
-            cells.push(cell);
-
-            if map_state.scheme[cell.0 as usize][cell.1 as usize] == map::legend::BRICK {
-                break;
-            }
-        }
+    cells
+}
 
-        for cell in cells {
-            commands.spawn((
-                Explosion {
-                    extinguish_at: time.elapsed() + Duration::from_secs_f32(DEFAULT_EXPLOSION_PERIOD)
-                },
-                Sprite {
-                    color: be_event.player_color.to_bevy_color(),
-                    custom_size: Some(SIZE),
-                    ..default()
-                },
-                cell.center(),
-                RigidBody::Dynamic,
-                Collider::cuboid(SIZE.x / 2.0, SIZE.y / 2.0),
-                LockedAxes::TRANSLATION_LOCKED | LockedAxes::ROTATION_LOCKED, 
-                ActiveEvents::COLLISION_EVENTS,
-            ));
+pub fn spawn_explosion(
+    mut commands: Commands,
+    mut bomb_exploded_events: EventReader<bomb::BombExploded>,
+    config: Res<GameConfig>,
+    map_state: Res<map::MapState>,
+    tick: Res<SimTick>,
+) {
+    for be_event in bomb_exploded_events.read() {
+        for cell in &be_event.blast_cells {
+            let explosion = Explosion {
+                player_color: be_event.player_color,
+                cell: *cell,
+                lit_at: tick.0,
+                extinguish_at: tick.0 + config.ticks(config.explosion.period),
+            };
+
+            spawn_fire(&mut commands, &config, &map_state.scheme, explosion);
         }
     }
 }
 
-fn extingush_explosion(
+pub fn spawn_fire(
+    commands: &mut Commands,
+    config: &GameConfig,
+    grid: &map::Grid,
+    explosion: Explosion,
+) -> Entity {
+    let color = explosion.player_color.to_bevy_color();
+    let transform = explosion.cell.center(grid);
+
+    commands
+        .spawn((
+            round::RoundEntity,
+            explosion,
+            Sprite {
+                color,
+                custom_size: Some(config.explosion.size.into()),
+                ..default()
+            },
+            transform,
+        ))
+        .id()
+}
+
+fn extinguish_explosion(
     mut commands: Commands,
     query: Query<(Entity, &Explosion)>,
-    time: Res<Time>,
+    tick: Res<SimTick>,
 ) {
     for (e, expl) in &query {
-        if time.elapsed() < expl.extinguish_at {
+        if tick.0 < expl.extinguish_at {
             continue;
         }
 
@@ -0,0 +1,591 @@
+//! Bots living in child processes, written in any language. Every tick of the
+//! round each process gets the world as one JSON line on its stdin, such as
+//!
+//! ```json
+//! {"tick":12,"you":"…","width":15,"height":11,"tiles":[[0,0,2,…],…],
+//!  "bombs":[{"cell":[3,4],"owner":"…","fire_range":2,"explodes_in":1.2,"airborne":false}],
+//!  "explosions":[{"cell":[3,5]}],"powerups":[{"cell":[7,1],"kind":"Kick"}],
+//!  "players":[{"id":"…","color":"Red","position":[-120.0,80.0],"cell":[2,3],"speed":70.0,
+//!              "fire_range":2,"bomb_capacity":1,"abilities":["Kick"],"curse":null}]}
+//! ```
+//!
+//! and answers with one line of its own for the same tick, every field but
+//! `tick` being optional:
+//!
+//! ```json
+//! {"tick":12,"direction":"north","bomb":false,"detonate":false}
+//! ```
+//!
+//! Tiles follow `map::legend` row by row from the top left cell, and a trigger
+//! bomb waiting for its owner has no `explodes_in`. A bot that misses the
+//! deadline of a tick stands still for it, one that exits or closes its pipes
+//! stands still for the rest of the match. Whatever a bot writes to stderr
+//! goes to the terminal.
+
+use std::collections::HashSet;
+use std::io::{self, BufRead, BufReader, Write};
+use std::process::{Child, Command, Stdio};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use bevy::prelude::*;
+use bevy::utils::synccell::SyncCell;
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use super::ai;
+use super::bomb;
+use super::disease;
+use super::explosion;
+use super::map;
+use super::player::{self, Controls, InputState};
+use super::powerup::{self, PowerupKind};
+use super::round;
+use crate::abtestbed::config::GameConfig;
+use crate::abtestbed::setup::SimTick;
+
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_millis(50);
+
+pub struct ExternalPlugin;
+
+impl Plugin for ExternalPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<ExternalSettings>()
+            .init_resource::<ExternalBots>()
+            .add_systems(Startup, start_processes.after(player::init_roster))
+            .add_systems(
+                FixedUpdate,
+                drive_external_bots
+                    .after(ai::drive_bots)
+                    .before(player::update_player_input)
+                    .run_if(round::round_running),
+            );
+    }
+}
+
+/// Commands started for the external slots, `Controls::External(n)` being
+/// driven by command n, and how long a tick waits for their answers.
+#[derive(Resource)]
+pub struct ExternalSettings {
+    pub commands: Vec<String>,
+    pub timeout: Duration,
+}
+
+impl std::default::Default for ExternalSettings {
+    fn default() -> Self {
+        ExternalSettings {
+            commands: Vec::new(),
+            timeout: DEFAULT_TIMEOUT,
+        }
+    }
+}
+
+#[derive(Resource, Default)]
+pub struct ExternalBots {
+    processes: Vec<BotProcess>,
+}
+
+struct BotProcess {
+    player_id: Uuid,
+    command: String,
+    child: Option<Child>,
+    lines_out: Sender<String>,
+    // Only ever read from the system driving the bots
+    lines_in: SyncCell<Receiver<String>>,
+    // The process died or broke its pipes, its player stands still
+    gone: bool,
+    missed_deadline: bool,
+}
+
+enum Reply {
+    Inputs(InputState),
+    Timeout,
+    Gone,
+}
+
+impl BotProcess {
+    fn start(player_id: Uuid, command: &str) -> io::Result<Self> {
+        let mut child = shell(command)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::inherit())
+            .spawn()?;
+
+        let (Some(mut stdin), Some(stdout)) = (child.stdin.take(), child.stdout.take()) else {
+            return Err(io::Error::other("the pipes of the process are missing"));
+        };
+
+        // Both ends get a thread of their own, so that a bot that stops
+        // reading or writing never blocks the game
+        let (lines_out, outgoing) = mpsc::channel::<String>();
+        thread::spawn(move || {
+            for line in outgoing {
+                if writeln!(stdin, "{}", line)
+                    .and_then(|_| stdin.flush())
+                    .is_err()
+                {
+                    break;
+                }
+            }
+        });
+
+        let (incoming, lines_in) = mpsc::channel();
+        thread::spawn(move || {
+            for line in BufReader::new(stdout).lines() {
+                let Ok(line) = line else {
+                    break;
+                };
+
+                if incoming.send(line).is_err() {
+                    break;
+                }
+            }
+        });
+
+        Ok(BotProcess {
+            player_id,
+            command: command.to_string(),
+            child: Some(child),
+            lines_out,
+            lines_in: SyncCell::new(lines_in),
+            gone: false,
+            missed_deadline: false,
+        })
+    }
+
+    fn reply(&mut self, tick: u64, deadline: Instant) -> Reply {
+        loop {
+            let line = match self
+                .lines_in
+                .get()
+                .recv_timeout(deadline.saturating_duration_since(Instant::now()))
+            {
+                Ok(line) => line,
+                Err(RecvTimeoutError::Timeout) => return Reply::Timeout,
+                Err(RecvTimeoutError::Disconnected) => return Reply::Gone,
+            };
+
+            match serde_json::from_str::<ActionMessage>(&line) {
+                Ok(action) if action.tick == tick => return Reply::Inputs(action.inputs()),
+                // The late answer to an earlier tick
+                Ok(_) => continue,
+                Err(err) => warn!("External bot '{}' sent '{}': {}", self.command, line, err),
+            }
+        }
+    }
+
+    fn lose(&mut self) {
+        self.gone = true;
+
+        let status = match self.child.as_mut().map(Child::try_wait) {
+            Some(Ok(Some(status))) => status.to_string(),
+            _ => "its pipes are closed".to_string(),
+        };
+
+        warn!(
+            "External bot '{}' is gone ({}), its player stands still",
+            self.command, status
+        );
+    }
+}
+
+impl Drop for BotProcess {
+    fn drop(&mut self) {
+        if let Some(mut child) = self.child.take() {
+            let _ = child.kill();
+            let _ = child.wait();
+        }
+    }
+}
+
+#[cfg(unix)]
+fn shell(command: &str) -> Command {
+    let mut shell = Command::new("sh");
+    shell.arg("-c").arg(command);
+    shell
+}
+
+#[cfg(windows)]
+fn shell(command: &str) -> Command {
+    let mut shell = Command::new("cmd");
+    shell.arg("/C").arg(command);
+    shell
+}
+
+#[derive(Serialize)]
+struct TickMessage<'a> {
+    tick: u64,
+    you: Uuid,
+    #[serde(flatten)]
+    world: &'a WorldView,
+}
+
+#[derive(Serialize)]
+struct WorldView {
+    width: u8,
+    height: u8,
+    tiles: Vec<Vec<u8>>,
+    bombs: Vec<BombView>,
+    explosions: Vec<ExplosionView>,
+    powerups: Vec<PowerupView>,
+    players: Vec<PlayerView>,
+}
+
+#[derive(Serialize)]
+struct BombView {
+    cell: map::Cell,
+    owner: Uuid,
+    fire_range: u8,
+    // Seconds
+    explodes_in: Option<f32>,
+    airborne: bool,
+}
+
+#[derive(Serialize)]
+struct ExplosionView {
+    cell: map::Cell,
+}
+
+#[derive(Serialize)]
+struct PowerupView {
+    cell: map::Cell,
+    kind: PowerupKind,
+}
+
+#[derive(Serialize)]
+struct PlayerView {
+    id: Uuid,
+    color: player::PlayerColor,
+    position: (f32, f32),
+    cell: map::Cell,
+    speed: f32,
+    fire_range: u8,
+    bomb_capacity: u8,
+    abilities: Vec<PowerupKind>,
+    curse: Option<disease::CurseKind>,
+}
+
+#[derive(Deserialize)]
+struct ActionMessage {
+    tick: u64,
+    #[serde(default)]
+    direction: Option<Direction>,
+    #[serde(default)]
+    bomb: bool,
+    #[serde(default)]
+    detonate: bool,
+}
+
+#[derive(Copy, Clone, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum Direction {
+    North,
+    South,
+    West,
+    East,
+}
+
+impl ActionMessage {
+    fn inputs(&self) -> InputState {
+        let (horizontal_direction, vertical_direction) = match self.direction {
+            Some(Direction::North) => (0, 1),
+            Some(Direction::South) => (0, -1),
+            Some(Direction::West) => (-1, 0),
+            Some(Direction::East) => (1, 0),
+            None => (0, 0),
+        };
+
+        InputState {
+            horizontal_direction,
+            vertical_direction,
+            plant_bomb: self.bomb,
+            detonate: self.detonate,
+        }
+    }
+}
+
+fn start_processes(
+    settings: Res<ExternalSettings>,
+    roster: Res<player::Roster>,
+    mut bots: ResMut<ExternalBots>,
+) {
+    for slot in &roster.slots {
+        let Controls::External(index) = slot.controls else {
+            continue;
+        };
+
+        let Some(command) = settings.commands.get(index) else {
+            warn!("No command was given for external bot {}", index + 1);
+            continue;
+        };
+
+        match BotProcess::start(slot.id, command) {
+            Ok(process) => bots.processes.push(process),
+            Err(err) => warn!("Failed to start external bot '{}': {}", command, err),
+        }
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+pub fn drive_external_bots(
+    tick: Res<SimTick>,
+    mut bots: ResMut<ExternalBots>,
+    settings: Res<ExternalSettings>,
+    players: Query<(&player::Player, &Transform, Option<&disease::Curse>)>,
+    bombs: Query<&bomb::Bomb>,
+    explosions: Query<&explosion::Explosion>,
+    powerups: Query<&powerup::Powerup>,
+    map_state: Res<map::MapState>,
+    config: Res<GameConfig>,
+    mut pending_inputs: ResMut<player::PendingInputs>,
+) {
+    if bots.processes.iter().all(|process| process.gone) {
+        return;
+    }
+
+    let grid = &map_state.scheme;
+
+    let world = WorldView {
+        width: grid.width(),
+        height: grid.height(),
+        tiles: grid
+            .cells()
+            .collect::<Vec<_>>()
+            .chunks(grid.width().max(1) as usize)
+            .map(|row| row.iter().map(|(_, tile)| *tile).collect())
+            .collect(),
+        bombs: bombs
+            .iter()
+            .map(|bomb| BombView {
+                cell: bomb.cell,
+                owner: bomb.player_id,
+                fire_range: bomb.fire_range,
+                explodes_in: bomb
+                    .explode_at()
+                    .map(|at| at.saturating_sub(tick.0) as f32 / config.fps),
+                airborne: bomb.is_airborne(),
+            })
+            .collect(),
+        explosions: explosions
+            .iter()
+            .map(|explosion| ExplosionView {
+                cell: explosion.cell,
+            })
+            .collect(),
+        powerups: powerups
+            .iter()
+            .map(|powerup| PowerupView {
+                cell: powerup.cell,
+                kind: powerup.kind,
+            })
+            .collect(),
+        players: players
+            .iter()
+            .map(|(player, transform, curse)| PlayerView {
+                id: player.id,
+                color: player.color,
+                position: (transform.translation.x, transform.translation.y),
+                cell: map::Cell::from_transform(grid, transform),
+                speed: player.speed(),
+                fire_range: player.fire_range(),
+                bomb_capacity: player.bomb_capacity(),
+                abilities: PowerupKind::ALL
+                    .into_iter()
+                    .filter(|kind| player.has_ability(*kind))
+                    .collect(),
+                curse: curse.map(|curse| curse.kind),
+            })
+            .collect(),
+    };
+
+    let living: HashSet<Uuid> = world.players.iter().map(|player| player.id).collect();
+
+    // Every bot gets the tick before any answer is awaited, so that they
+    // think at the same time
+    let mut asked = Vec::new();
+
+    for (index, process) in bots.processes.iter_mut().enumerate() {
+        if process.gone || !living.contains(&process.player_id) {
+            continue;
+        }
+
+        let message = TickMessage {
+            tick: tick.0,
+            you: process.player_id,
+            world: &world,
+        };
+
+        let line = match serde_json::to_string(&message) {
+            Ok(line) => line,
+            Err(err) => {
+                error!("Failed to encode the world: {}", err);
+                return;
+            }
+        };
+
+        if process.lines_out.send(line).is_err() {
+            process.lose();
+            pending_inputs
+                .by_id
+                .insert(process.player_id, InputState::default());
+            continue;
+        }
+
+        asked.push(index);
+    }
+
+    let deadline = Instant::now() + settings.timeout;
+
+    for index in asked {
+        let process = &mut bots.processes[index];
+
+        let inputs = match process.reply(tick.0, deadline) {
+            Reply::Inputs(inputs) => inputs,
+            Reply::Timeout => {
+                if !process.missed_deadline {
+                    process.missed_deadline = true;
+                    warn!(
+                        "External bot '{}' missed tick {}, later misses are not reported",
+                        process.command, tick.0
+                    );
+                }
+
+                InputState::default()
+            }
+            Reply::Gone => {
+                process.lose();
+                InputState::default()
+            }
+        };
+
+        pending_inputs.by_id.insert(process.player_id, inputs);
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use crate::abtestbed::setup::HeadlessSetupPlugin;
+    use crate::abtestbed::world::player::{PendingInputs, Player, RosterSettings, SlotKind};
+    use crate::abtestbed::world::round::{GameState, MatchSettings};
+    use crate::abtestbed::world::WorldPlugin;
+
+    // Ticks for the bot to get a few of, once the round is under way
+    const TICKS: usize = 5;
+    const MAX_UPDATES: usize = 1000;
+
+    // A round with an external bot and an opponent that stands still
+    fn external_app(command: &str, timeout: Duration) -> (App, Uuid) {
+        let mut app = App::new();
+        app.insert_resource(GameConfig::default())
+            .insert_resource(MatchSettings {
+                auto_start: true,
+                ..default()
+            })
+            .insert_resource(RosterSettings {
+                slots: vec![SlotKind::External, SlotKind::Remote],
+            })
+            .insert_resource(ExternalSettings {
+                commands: vec![command.to_string()],
+                timeout,
+            })
+            .add_plugins(HeadlessSetupPlugin)
+            .add_plugins(WorldPlugin);
+        app.finish();
+        app.cleanup();
+
+        for _ in 0..MAX_UPDATES {
+            app.update();
+            if *app.world().resource::<State<GameState>>().get() == GameState::InRound {
+                break;
+            }
+        }
+
+        let id = app.world().resource::<player::Roster>().slots[0].id;
+        (app, id)
+    }
+
+    // Runs a tick in which the player was last told to walk west
+    fn step(app: &mut App, id: Uuid) -> Option<InputState> {
+        let west = InputState {
+            horizontal_direction: -1,
+            ..default()
+        };
+        app.world_mut()
+            .resource_mut::<PendingInputs>()
+            .by_id
+            .insert(id, west);
+
+        tick(app, id)
+    }
+
+    // The inputs of the player after the next tick, if it is still around
+    fn tick(app: &mut App, id: Uuid) -> Option<InputState> {
+        app.update();
+
+        app.world_mut()
+            .query::<&Player>()
+            .iter(app.world())
+            .find(|player| player.id == id)
+            .map(|player| player.inputs)
+    }
+
+    fn gone(app: &App) -> bool {
+        app.world().resource::<ExternalBots>().processes[0].gone
+    }
+
+    #[test]
+    fn silent_bot_stands_still() {
+        let timeout = Duration::from_millis(20);
+        let (mut app, id) = external_app("cat > /dev/null", timeout);
+
+        for _ in 0..TICKS {
+            let started = Instant::now();
+            let inputs = step(&mut app, id);
+
+            assert!(started.elapsed() < timeout * 50, "the tick hung");
+            assert_eq!(inputs, Some(InputState::default()));
+        }
+
+        assert!(!gone(&app));
+    }
+
+    #[test]
+    fn exited_bot_keeps_its_player_standing_still() {
+        let (mut app, id) = external_app("exit 0", Duration::from_secs(1));
+
+        for _ in 0..MAX_UPDATES {
+            if gone(&app) {
+                break;
+            }
+            tick(&mut app, id);
+        }
+        assert!(gone(&app), "the bot that exited was not noticed");
+
+        for _ in 0..TICKS {
+            assert_eq!(tick(&mut app, id), Some(InputState::default()));
+        }
+    }
+
+    #[test]
+    fn skips_lines_that_are_not_actions() {
+        // Every tick gets a broken line, then the answer
+        let command = r#"while read -r line; do
+            echo '{not json'
+            tick=${line#*\"tick\":}
+            echo "{\"tick\":${tick%%,*},\"direction\":\"east\"}"
+        done"#;
+        let (mut app, id) = external_app(command, Duration::from_secs(5));
+
+        let east = InputState {
+            horizontal_direction: 1,
+            ..default()
+        };
+        for _ in 0..TICKS {
+            assert_eq!(step(&mut app, id), Some(east));
+        }
+
+        assert!(!gone(&app));
+    }
+}
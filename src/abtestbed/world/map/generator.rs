@@ -0,0 +1,599 @@
+//! Procedural maps built from a seed and a handful of parameters.
+//!
+//! Blocks are laid first, then the start cells are spread as far apart as the
+//! blocks allow and the area around each one is kept clear, and finally the
+//! rest of the map is filled with bricks. With a symmetry every decision is
+//! taken once for a cell and all of its mirror images.
+//!
+//! On the command line the parameters are given as a comma separated list of
+//! `key=value` pairs, any of which may be left out:
+//! `size=21x13,blocks=random,bricks=0.6,starts=4,symmetry=rotational`.
+
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::fmt;
+use std::str::FromStr;
+
+use rand::seq::SliceRandom;
+use rand::{Rng, SeedableRng};
+use rand_chacha::ChaCha8Rng;
+
+use super::scheme::MAX_PLAYERS;
+use super::{legend, Cell, Grid, MapState};
+use crate::abtestbed::world::explosion::DIRECTIONS;
+use crate::abtestbed::world::powerup;
+
+const MIN_SIZE: u8 = 3;
+// Share of the cells the random pattern tries to turn into blocks
+const RANDOM_BLOCK_DENSITY: f32 = 0.25;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum BlockPattern {
+    // A block on every cell with odd coordinates, like the default scheme
+    Pillars,
+    // Blocks anywhere, as long as every free cell stays reachable
+    Random,
+    None,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Symmetry {
+    None,
+    // The right half mirrors the left one
+    Mirror,
+    // The map looks the same turned upside down
+    Rotational,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct GeneratorParams {
+    pub width: u8,
+    pub height: u8,
+    pub blocks: BlockPattern,
+    // Chance of a brick on every free cell outside of the start areas
+    pub brick_density: f32,
+    pub start_cells: usize,
+    pub symmetry: Symmetry,
+}
+
+impl std::default::Default for GeneratorParams {
+    fn default() -> Self {
+        GeneratorParams {
+            width: 15,
+            height: 11,
+            blocks: BlockPattern::Pillars,
+            brick_density: 0.8,
+            start_cells: 2,
+            symmetry: Symmetry::None,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum GeneratorError {
+    UnknownKey { key: String },
+    InvalidValue { key: String, value: String },
+    TooSmall { width: u8, height: u8 },
+    BrickDensity { density: f32 },
+    StartCells { count: usize },
+    NoRoomForStarts { count: usize, free: usize },
+}
+
+impl fmt::Display for GeneratorError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            GeneratorError::UnknownKey { key } => write!(f, "unknown parameter `{}`", key),
+            GeneratorError::InvalidValue { key, value } => {
+                write!(f, "`{}` is not a valid value for `{}`", value, key)
+            }
+            GeneratorError::TooSmall { width, height } => write!(
+                f,
+                "a {}x{} map is too small, both sides need at least {} cells",
+                width, height, MIN_SIZE
+            ),
+            GeneratorError::BrickDensity { density } => {
+                write!(f, "brick density {} is not between 0 and 1", density)
+            }
+            GeneratorError::StartCells { count } => write!(
+                f,
+                "{} start cells requested, expected 1 to {}",
+                count, MAX_PLAYERS
+            ),
+            GeneratorError::NoRoomForStarts { count, free } => write!(
+                f,
+                "{} start cells do not fit in the {} free cells of the map",
+                count, free
+            ),
+        }
+    }
+}
+
+impl std::error::Error for GeneratorError {}
+
+impl FromStr for GeneratorParams {
+    type Err = GeneratorError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut params = GeneratorParams::default();
+
+        for pair in s.split(',').map(str::trim).filter(|pair| !pair.is_empty()) {
+            let (key, value) = pair.split_once('=').unwrap_or((pair, ""));
+            let invalid = || GeneratorError::InvalidValue {
+                key: key.to_string(),
+                value: value.to_string(),
+            };
+
+            match key {
+                "size" => {
+                    let (width, height) = value.split_once('x').ok_or_else(invalid)?;
+                    params.width = width.parse().map_err(|_| invalid())?;
+                    params.height = height.parse().map_err(|_| invalid())?;
+                }
+                "blocks" => {
+                    params.blocks = match value {
+                        "pillars" => BlockPattern::Pillars,
+                        "random" => BlockPattern::Random,
+                        "none" => BlockPattern::None,
+                        _ => return Err(invalid()),
+                    }
+                }
+                "bricks" => params.brick_density = value.parse().map_err(|_| invalid())?,
+                "starts" => params.start_cells = value.parse().map_err(|_| invalid())?,
+                "symmetry" => {
+                    params.symmetry = match value {
+                        "none" => Symmetry::None,
+                        "mirror" => Symmetry::Mirror,
+                        "rotational" => Symmetry::Rotational,
+                        _ => return Err(invalid()),
+                    }
+                }
+                _ => {
+                    return Err(GeneratorError::UnknownKey {
+                        key: key.to_string(),
+                    })
+                }
+            }
+        }
+
+        Ok(params)
+    }
+}
+
+impl GeneratorParams {
+    fn validate(&self) -> Result<(), GeneratorError> {
+        if self.width < MIN_SIZE || self.height < MIN_SIZE {
+            return Err(GeneratorError::TooSmall {
+                width: self.width,
+                height: self.height,
+            });
+        }
+
+        if !(0.0..=1.0).contains(&self.brick_density) {
+            return Err(GeneratorError::BrickDensity {
+                density: self.brick_density,
+            });
+        }
+
+        if self.start_cells == 0 || self.start_cells > MAX_PLAYERS {
+            return Err(GeneratorError::StartCells {
+                count: self.start_cells,
+            });
+        }
+
+        Ok(())
+    }
+
+    /// The cell itself followed by its mirror image, if it has another one.
+    fn images(&self, cell: Cell) -> Vec<Cell> {
+        let image = match self.symmetry {
+            Symmetry::None => cell,
+            Symmetry::Mirror => Cell(self.width - 1 - cell.0, cell.1),
+            Symmetry::Rotational => Cell(self.width - 1 - cell.0, self.height - 1 - cell.1),
+        };
+
+        if image == cell {
+            vec![cell]
+        } else {
+            vec![cell, image]
+        }
+    }
+}
+
+pub fn generate(params: &GeneratorParams, seed: u64) -> Result<MapState, GeneratorError> {
+    params.validate()?;
+
+    let mut rng = ChaCha8Rng::seed_from_u64(seed);
+    let mut grid = Grid::new(params.width, params.height);
+
+    // Every cell paired with its images, each group listed once
+    let groups: Vec<Vec<Cell>> = grid
+        .cells()
+        .map(|(cell, _)| params.images(cell))
+        .filter(|images| {
+            images
+                .iter()
+                .all(|image| (image.1, image.0) >= (images[0].1, images[0].0))
+        })
+        .collect();
+
+    if params.blocks == BlockPattern::Pillars {
+        for group in &groups {
+            let Cell(x, y) = group[0];
+            if x % 2 == 1 && y % 2 == 1 {
+                fill(&mut grid, group, legend::BLOCK);
+            }
+        }
+    }
+
+    let start_cells = spread_start_cells(&grid, params, &mut rng)?;
+    let start_areas: HashSet<Cell> = start_cells
+        .iter()
+        .flat_map(|cell| start_area(&grid, *cell))
+        .collect();
+
+    if params.blocks == BlockPattern::Random {
+        let mut shuffled = groups.clone();
+        shuffled.shuffle(&mut rng);
+
+        let mut budget = (grid.cells().count() as f32 * RANDOM_BLOCK_DENSITY) as usize;
+
+        for group in shuffled {
+            if budget < group.len() {
+                break;
+            }
+
+            if group.iter().any(|cell| start_areas.contains(cell)) {
+                continue;
+            }
+
+            fill(&mut grid, &group, legend::BLOCK);
+
+            if is_connected(&grid) {
+                budget -= group.len();
+            } else {
+                fill(&mut grid, &group, legend::EMPTY);
+            }
+        }
+    }
+
+    for group in &groups {
+        let is_free = group
+            .iter()
+            .all(|cell| grid.get(*cell) == legend::EMPTY && !start_areas.contains(cell));
+
+        if is_free && rng.gen::<f32>() < params.brick_density {
+            fill(&mut grid, group, legend::BRICK);
+        }
+    }
+
+    Ok(MapState {
+        scheme: grid,
+        start_cells,
+        powerups: powerup::default_rules(),
+    })
+}
+
+fn fill(grid: &mut Grid, cells: &[Cell], tile: u8) {
+    for cell in cells {
+        grid.set(*cell, tile);
+    }
+}
+
+/// Start cells as far from each other as possible, from the top left corner
+/// on. Each one brings its mirror image along while there is room for it.
+fn spread_start_cells(
+    grid: &Grid,
+    params: &GeneratorParams,
+    rng: &mut ChaCha8Rng,
+) -> Result<Vec<Cell>, GeneratorError> {
+    let free: Vec<Cell> = grid
+        .cells()
+        .filter(|(_, tile)| *tile == legend::EMPTY)
+        .map(|(cell, _)| cell)
+        .collect();
+
+    if free.len() < params.start_cells {
+        return Err(GeneratorError::NoRoomForStarts {
+            count: params.start_cells,
+            free: free.len(),
+        });
+    }
+
+    let mut start_cells: Vec<Cell> = Vec::new();
+    // Pillars only stand on odd coordinates, the corner is always free
+    let mut next = Cell(0, 0);
+
+    loop {
+        for image in params.images(next) {
+            if start_cells.len() < params.start_cells && !start_cells.contains(&image) {
+                start_cells.push(image);
+            }
+        }
+
+        if start_cells.len() == params.start_cells {
+            return Ok(start_cells);
+        }
+
+        // A cell's own image counts too, or a pair could land side by side
+        let distance = |cell: &Cell| {
+            start_cells
+                .iter()
+                .chain(params.images(*cell).iter().skip(1))
+                .map(|start| start.0.abs_diff(cell.0) as u16 + start.1.abs_diff(cell.1) as u16)
+                .min()
+                .unwrap_or_default()
+        };
+
+        let farthest = free.iter().map(distance).max().unwrap_or_default();
+        let candidates: Vec<Cell> = free
+            .iter()
+            .filter(|cell| distance(cell) == farthest)
+            .copied()
+            .collect();
+
+        next = *candidates
+            .choose(rng)
+            .expect("a free cell is left for every start");
+    }
+}
+
+/// Cells kept clear around a start: everything up to the nearest cell off the
+/// start's row and column, where a player hides from their own first bomb.
+fn start_area(grid: &Grid, start: Cell) -> Vec<Cell> {
+    let mut distances = HashMap::from([(start, 0)]);
+    let mut queue = VecDeque::from([start]);
+    let mut shelter_distance = None;
+
+    while let Some(cell) = queue.pop_front() {
+        let distance = distances[&cell];
+
+        if shelter_distance.is_some_and(|shelter| distance >= shelter) {
+            continue;
+        }
+
+        for (dx, dy) in DIRECTIONS {
+            let Some(next_cell) = cell.neighbour(grid, dx, dy) else {
+                continue;
+            };
+
+            if grid.get(next_cell) == legend::BLOCK || distances.contains_key(&next_cell) {
+                continue;
+            }
+
+            distances.insert(next_cell, distance + 1);
+            queue.push_back(next_cell);
+
+            if next_cell.0 != start.0 && next_cell.1 != start.1 && shelter_distance.is_none() {
+                shelter_distance = Some(distance + 1);
+            }
+        }
+    }
+
+    let shelter_distance = shelter_distance.unwrap_or(u32::MAX);
+
+    distances
+        .into_iter()
+        .filter(|(_, distance)| *distance <= shelter_distance)
+        .map(|(cell, _)| cell)
+        .collect()
+}
+
+fn is_connected(grid: &Grid) -> bool {
+    let mut open = grid
+        .cells()
+        .filter(|(_, tile)| *tile != legend::BLOCK)
+        .map(|(cell, _)| cell);
+
+    let Some(first) = open.next() else {
+        return true;
+    };
+    let open_count = open.count() + 1;
+
+    let mut reached = HashSet::from([first]);
+    let mut queue = VecDeque::from([first]);
+
+    while let Some(cell) = queue.pop_front() {
+        for (dx, dy) in DIRECTIONS {
+            let Some(next_cell) = cell.neighbour(grid, dx, dy) else {
+                continue;
+            };
+
+            if grid.get(next_cell) != legend::BLOCK && reached.insert(next_cell) {
+                queue.push_back(next_cell);
+            }
+        }
+    }
+
+    reached.len() == open_count
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SEEDS: u64 = 5;
+
+    fn every_params() -> Vec<GeneratorParams> {
+        let mut every = Vec::new();
+
+        for (width, height) in [(15, 11), (21, 13), (8, 7)] {
+            for blocks in [
+                BlockPattern::Pillars,
+                BlockPattern::Random,
+                BlockPattern::None,
+            ] {
+                for symmetry in [Symmetry::None, Symmetry::Mirror, Symmetry::Rotational] {
+                    for start_cells in [1, 4, MAX_PLAYERS] {
+                        every.push(GeneratorParams {
+                            width,
+                            height,
+                            blocks,
+                            brick_density: 1.0,
+                            start_cells,
+                            symmetry,
+                        });
+                    }
+                }
+            }
+        }
+
+        every
+    }
+
+    // Cells reached from `from` through the tiles that pass
+    fn reachable(grid: &Grid, from: Cell, passes: impl Fn(u8) -> bool) -> HashSet<Cell> {
+        let mut reached = HashSet::from([from]);
+        let mut queue = VecDeque::from([from]);
+
+        while let Some(cell) = queue.pop_front() {
+            for (dx, dy) in DIRECTIONS {
+                let Some(next_cell) = cell.neighbour(grid, dx, dy) else {
+                    continue;
+                };
+
+                if passes(grid.get(next_cell)) && reached.insert(next_cell) {
+                    queue.push_back(next_cell);
+                }
+            }
+        }
+
+        reached
+    }
+
+    #[test]
+    fn parses_params() {
+        let params: GeneratorParams =
+            "size=21x13, blocks=random,bricks=0.5,starts=4,symmetry=mirror"
+                .parse()
+                .unwrap();
+
+        assert_eq!(
+            params,
+            GeneratorParams {
+                width: 21,
+                height: 13,
+                blocks: BlockPattern::Random,
+                brick_density: 0.5,
+                start_cells: 4,
+                symmetry: Symmetry::Mirror,
+            }
+        );
+        assert_eq!(
+            "".parse::<GeneratorParams>().unwrap(),
+            GeneratorParams::default()
+        );
+    }
+
+    #[test]
+    fn reports_invalid_params() {
+        assert!(matches!(
+            "walls=none".parse::<GeneratorParams>(),
+            Err(GeneratorError::UnknownKey { key }) if key == "walls"
+        ));
+        assert!(matches!(
+            "size=21".parse::<GeneratorParams>(),
+            Err(GeneratorError::InvalidValue { key, value }) if key == "size" && value == "21"
+        ));
+
+        let params = |s: &str| s.parse::<GeneratorParams>().unwrap();
+        assert!(matches!(
+            generate(&params("size=2x9"), 0),
+            Err(GeneratorError::TooSmall {
+                width: 2,
+                height: 9
+            })
+        ));
+        assert!(matches!(
+            generate(&params("bricks=1.5"), 0),
+            Err(GeneratorError::BrickDensity { .. })
+        ));
+        assert!(matches!(
+            generate(&params("starts=0"), 0),
+            Err(GeneratorError::StartCells { count: 0 })
+        ));
+        assert!(matches!(
+            generate(&params("size=3x3,starts=9"), 0),
+            Err(GeneratorError::NoRoomForStarts { count: 9, free: 8 })
+        ));
+    }
+
+    #[test]
+    fn same_seed_same_map() {
+        let params = GeneratorParams {
+            blocks: BlockPattern::Random,
+            ..GeneratorParams::default()
+        };
+
+        let first = generate(&params, 7).unwrap();
+        let second = generate(&params, 7).unwrap();
+        assert_eq!(first.scheme, second.scheme);
+        assert_eq!(first.start_cells, second.start_cells);
+    }
+
+    #[test]
+    fn starts_have_a_clear_way_to_shelter() {
+        for params in every_params() {
+            for seed in 0..SEEDS {
+                let map_state = generate(&params, seed).unwrap();
+                let grid = &map_state.scheme;
+                assert_eq!(map_state.start_cells.len(), params.start_cells);
+
+                for start in &map_state.start_cells {
+                    assert_eq!(grid.get(*start), legend::EMPTY);
+
+                    let sheltered = reachable(grid, *start, |tile| tile == legend::EMPTY)
+                        .iter()
+                        .any(|cell| cell.0 != start.0 && cell.1 != start.1);
+                    assert!(
+                        sheltered,
+                        "{:?} seed {}: no shelter for {:?}",
+                        params, seed, start
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn layouts_follow_the_symmetry() {
+        for params in every_params() {
+            for seed in 0..SEEDS {
+                let grid = generate(&params, seed).unwrap().scheme;
+
+                for (cell, tile) in grid.cells() {
+                    for image in params.images(cell) {
+                        assert_eq!(
+                            grid.get(image),
+                            tile,
+                            "{:?} seed {}: {:?} and {:?} differ",
+                            params,
+                            seed,
+                            cell,
+                            image
+                        );
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn every_open_cell_is_reachable() {
+        for params in every_params() {
+            for seed in 0..SEEDS {
+                let grid = generate(&params, seed).unwrap().scheme;
+
+                let open: HashSet<Cell> = grid
+                    .cells()
+                    .filter(|(_, tile)| *tile != legend::BLOCK)
+                    .map(|(cell, _)| cell)
+                    .collect();
+                let Some(first) = open.iter().next() else {
+                    continue;
+                };
+
+                let reached = reachable(&grid, *first, |tile| tile != legend::BLOCK);
+                assert_eq!(reached, open, "{:?} seed {}: cut off cells", params, seed);
+            }
+        }
+    }
+}
@@ -0,0 +1,278 @@
+use bevy::prelude::*;
+use bevy::render::camera::ScalingMode;
+use bevy_rapier2d::na::ComplexField;
+use serde::{Deserialize, Serialize};
+
+use super::powerup;
+use super::round;
+use crate::abtestbed::config::GameConfig;
+
+pub mod generator;
+pub mod scheme;
+
+pub use generator::generate;
+
+pub const CELL_SIZE: Vec2 = Vec2::new(40.0, 36.0);
+// Free space the camera keeps around the borders
+const CAMERA_MARGIN: Vec2 = CELL_SIZE;
+
+pub struct MapPlugin;
+
+impl Plugin for MapPlugin {
+    fn build(&self, app: &mut App) {
+        // The map every round starts from, a loaded scheme if there is one
+        let initial_map = app
+            .world_mut()
+            .get_resource_or_insert_with(MapState::default)
+            .clone();
+
+        app.insert_resource(InitialMap(initial_map)).add_systems(
+            OnEnter(round::GameState::RoundStarting),
+            (reset_map, fit_camera).chain(),
+        );
+    }
+}
+
+#[derive(
+    Debug, Copy, Clone, Default, Hash, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize,
+)]
+pub struct Cell(pub u8, pub u8);
+
+impl Cell {
+    pub fn from_transform(grid: &Grid, transform: &Transform) -> Self {
+        let start = grid.cell_start_pos();
+        let position = Vec2::new(
+            ComplexField::round((transform.translation.x - start.x) / CELL_SIZE.x),
+            ComplexField::round((-transform.translation.y + start.y) / CELL_SIZE.y),
+        );
+
+        Cell(position.x as u8, position.y as u8)
+    }
+
+    /// Adjacent cell in the given direction, `dy` points north like the inputs do.
+    pub fn neighbour(&self, grid: &Grid, dx: i8, dy: i8) -> Option<Cell> {
+        let x = self.0 as i16 + dx as i16;
+        let y = self.1 as i16 - dy as i16;
+
+        if x < 0 || y < 0 || x >= grid.width as i16 || y >= grid.height as i16 {
+            return None;
+        }
+
+        Some(Cell(x as u8, y as u8))
+    }
+
+    /// Like `neighbour`, but stepping off one edge of the grid comes back in
+    /// on the opposite one.
+    pub fn wrapping_neighbour(&self, grid: &Grid, dx: i8, dy: i8) -> Cell {
+        let x = (self.0 as i16 + dx as i16).rem_euclid(grid.width as i16);
+        let y = (self.1 as i16 - dy as i16).rem_euclid(grid.height as i16);
+
+        Cell(x as u8, y as u8)
+    }
+
+    pub fn center(&self, grid: &Grid) -> Transform {
+        let start = grid.cell_start_pos();
+
+        Transform::from_xyz(
+            start.x + (self.0 as f32 * CELL_SIZE.x),
+            start.y - (self.1 as f32 * CELL_SIZE.y),
+            0.0,
+        )
+    }
+}
+
+/// Tiles of a map of any size, stored row by row from the top left cell.
+/// The arena is centred on the origin.
+#[derive(Debug, Clone, Hash, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Grid {
+    width: u8,
+    height: u8,
+    tiles: Vec<u8>,
+}
+
+impl Grid {
+    pub fn new(width: u8, height: u8) -> Self {
+        Grid {
+            width,
+            height,
+            tiles: vec![legend::EMPTY; width as usize * height as usize],
+        }
+    }
+
+    /// Builds a grid from its rows, which must all be as long as the first one.
+    pub fn from_rows<R: AsRef<[u8]>>(rows: &[R]) -> Self {
+        let width = rows.first().map_or(0, |row| row.as_ref().len());
+        assert!(
+            rows.iter().all(|row| row.as_ref().len() == width),
+            "grid rows differ in length"
+        );
+
+        Grid {
+            width: width as u8,
+            height: rows.len() as u8,
+            tiles: rows
+                .iter()
+                .flat_map(|row| row.as_ref().iter().copied())
+                .collect(),
+        }
+    }
+
+    pub fn width(&self) -> u8 {
+        self.width
+    }
+
+    pub fn height(&self) -> u8 {
+        self.height
+    }
+
+    pub fn contains(&self, cell: Cell) -> bool {
+        cell.0 < self.width && cell.1 < self.height
+    }
+
+    pub fn get(&self, cell: Cell) -> u8 {
+        self.tiles[self.index(cell)]
+    }
+
+    pub fn set(&mut self, cell: Cell, tile: u8) {
+        let index = self.index(cell);
+        self.tiles[index] = tile;
+    }
+
+    /// Every cell with its tile, row by row.
+    pub fn cells(&self) -> impl Iterator<Item = (Cell, u8)> + '_ {
+        self.tiles.iter().enumerate().map(|(index, tile)| {
+            let cell = Cell(
+                (index % self.width as usize) as u8,
+                (index / self.width as usize) as u8,
+            );
+            (cell, *tile)
+        })
+    }
+
+    /// Every cell once, going round the edges and then further in ring by ring.
+    pub fn spiral(&self) -> Vec<Cell> {
+        let mut cells: Vec<(i16, i16)> = Vec::with_capacity(self.tiles.len());
+        let (mut left, mut top) = (0i16, 0i16);
+        let (mut right, mut bottom) = (self.width as i16 - 1, self.height as i16 - 1);
+
+        while left <= right && top <= bottom {
+            cells.extend((left..=right).map(|x| (x, top)));
+            cells.extend((top + 1..=bottom).map(|y| (right, y)));
+            if top < bottom {
+                cells.extend((left..right).rev().map(|x| (x, bottom)));
+            }
+            if left < right {
+                cells.extend((top + 1..bottom).rev().map(|y| (left, y)));
+            }
+
+            left += 1;
+            top += 1;
+            right -= 1;
+            bottom -= 1;
+        }
+
+        cells.into_iter().map(|(x, y)| Cell(x as u8, y as u8)).collect()
+    }
+
+    /// Size of the arena in world units.
+    pub fn size(&self) -> Vec2 {
+        Vec2::new(self.width as f32, self.height as f32) * CELL_SIZE
+    }
+
+    pub fn cell_start_pos(&self) -> Vec2 {
+        let size = self.size();
+        Vec2::new(
+            -(size.x / 2.0) + (CELL_SIZE.x / 2.0),
+            (size.y / 2.0) - (CELL_SIZE.y / 2.0),
+        )
+    }
+
+    fn index(&self, cell: Cell) -> usize {
+        assert!(self.contains(cell), "{:?} is outside of the grid", cell);
+        cell.1 as usize * self.width as usize + cell.0 as usize
+    }
+}
+
+pub mod legend {
+    pub const EMPTY: u8 = 0;
+    pub const BLOCK: u8 = 1;
+    pub const BRICK: u8 = 2;
+}
+
+#[derive(Resource, Clone, Serialize, Deserialize)]
+pub struct MapState {
+    pub scheme: Grid,
+    pub start_cells: Vec<Cell>,
+    pub powerups: [powerup::PowerupRule; powerup::KINDS],
+}
+
+#[derive(Resource)]
+pub struct InitialMap(pub MapState);
+
+pub fn reset_map(mut map_state: ResMut<MapState>, initial_map: Res<InitialMap>) {
+    *map_state = initial_map.0.clone();
+}
+
+fn fit_camera(
+    map_state: Res<MapState>,
+    config: Res<GameConfig>,
+    mut projections: Query<&mut OrthographicProjection>,
+) {
+    for mut projection in &mut projections {
+        fit_projection(&map_state.scheme, &config, &mut projection);
+    }
+}
+
+/// Zooms a 2D camera so the whole arena and its borders are in view.
+pub fn fit_projection(grid: &Grid, config: &GameConfig, projection: &mut OrthographicProjection) {
+    let view = grid.size() + Vec2::splat(2.0 * config.border.thickness) + 2.0 * CAMERA_MARGIN;
+
+    projection.scaling_mode = ScalingMode::AutoMin {
+        min_width: view.x,
+        min_height: view.y,
+    };
+}
+
+impl MapState {
+    pub fn tile(&self, cell: Cell) -> u8 {
+        self.scheme.get(cell)
+    }
+
+    pub fn set_tile(&mut self, cell: Cell, tile: u8) {
+        self.scheme.set(cell, tile);
+    }
+}
+
+impl Default for MapState {
+    fn default() -> Self {
+        MapState {
+            // Room for the whole roster, a start in every corner and along the edges
+            scheme: Grid::from_rows(&[
+                [0, 0, 0, 2, 2, 2, 0, 0, 0, 2, 2, 2, 0, 0, 0],
+                [0, 1, 0, 1, 2, 1, 0, 1, 0, 1, 2, 1, 0, 1, 0],
+                [0, 0, 0, 0, 2, 2, 2, 2, 2, 2, 2, 0, 0, 0, 0],
+                [2, 1, 0, 1, 2, 1, 2, 1, 2, 1, 2, 1, 0, 1, 2],
+                [0, 0, 2, 0, 0, 2, 2, 2, 2, 0, 0, 2, 2, 0, 0],
+                [0, 1, 2, 1, 0, 1, 2, 1, 2, 1, 0, 1, 2, 1, 0],
+                [0, 0, 2, 2, 0, 0, 2, 2, 2, 2, 0, 0, 2, 0, 0],
+                [2, 1, 0, 1, 2, 1, 2, 1, 2, 1, 2, 1, 0, 1, 2],
+                [0, 0, 0, 0, 2, 2, 2, 2, 2, 2, 2, 0, 0, 0, 0],
+                [0, 1, 0, 1, 2, 1, 0, 1, 0, 1, 2, 1, 0, 1, 0],
+                [0, 0, 0, 2, 2, 2, 0, 0, 0, 2, 2, 2, 0, 0, 0],
+            ]),
+            start_cells: vec![
+                Cell(0, 0),
+                Cell(14, 10),
+                Cell(14, 0),
+                Cell(0, 10),
+                Cell(7, 0),
+                Cell(7, 10),
+                Cell(0, 5),
+                Cell(14, 5),
+                Cell(4, 5),
+                Cell(10, 5),
+            ],
+            powerups: powerup::default_rules(),
+        }
+    }
+}
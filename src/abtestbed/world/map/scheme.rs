@@ -0,0 +1,366 @@
+//! Loader for original Atomic Bomberman `.SCH` scheme files.
+//!
+//! A scheme is a plain text file where every meaningful line starts with a
+//! directive and holds comma separated fields. Everything after `;` is a comment.
+//!
+//! - `-V,<version>` and `-N,<name>` are accepted and ignored.
+//! - `-B,<density>` is accepted and ignored, bricks are taken from the rows as is.
+//! - `-R,<row>,<cells>` describes one grid row: `#` is a block, `:` is a brick, `.` is empty.
+//!   Every row is as wide as the first one and the rows numbered from 0 up to the
+//!   last one make up the map, so a scheme may be of any size up to 255 by 255.
+//! - `-S,<player>,<x>,<y>[,<team>]` sets the start cell of a player.
+//! - `-P,<index>,<born with>,<has override>,<override value>,<forbidden>[,<name>]`
+//!   configures one power-up. The override value is read as the drop chance in percent.
+//!   Both disease numbers configure the disease item, the random item is ignored.
+
+use std::fmt;
+use std::fs;
+use std::path::Path;
+
+use crate::abtestbed::world::powerup::PowerupKind;
+use super::{legend, Cell, Grid, MapState};
+
+pub const MAX_PLAYERS: usize = 10;
+pub const POWERUP_NUMBERS: usize = 13;
+
+#[derive(Debug)]
+pub enum SchemeError {
+    Io(std::io::Error),
+    UnknownDirective { line: usize, directive: String },
+    MissingField { line: usize, field: &'static str },
+    InvalidNumber { line: usize, field: &'static str, value: String },
+    RowOutOfRange { line: usize, row: usize },
+    RowLength { line: usize, expected: usize, found: usize },
+    EmptyRow { line: usize },
+    RowTooLong { line: usize, found: usize },
+    UnknownTile { line: usize, tile: char },
+    PlayerOutOfRange { line: usize, player: usize },
+    StartOutOfRange { line: usize, x: usize, y: usize },
+    PowerupOutOfRange { line: usize, index: usize },
+    MissingRow { row: usize },
+}
+
+impl fmt::Display for SchemeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SchemeError::Io(err) => write!(f, "failed to read scheme: {}", err),
+            SchemeError::UnknownDirective { line, directive } => {
+                write!(f, "line {}: unknown directive `{}`", line, directive)
+            }
+            SchemeError::MissingField { line, field } => {
+                write!(f, "line {}: missing field `{}`", line, field)
+            }
+            SchemeError::InvalidNumber { line, field, value } => {
+                write!(f, "line {}: field `{}` is not a number: `{}`", line, field, value)
+            }
+            SchemeError::RowOutOfRange { line, row } => {
+                write!(f, "line {}: row {} is out of range", line, row)
+            }
+            SchemeError::RowLength {
+                line,
+                expected,
+                found,
+            } => write!(
+                f,
+                "line {}: expected {} cells in a row, found {}",
+                line, expected, found
+            ),
+            SchemeError::EmptyRow { line } => write!(f, "line {}: the row has no cells", line),
+            SchemeError::RowTooLong { line, found } => write!(
+                f,
+                "line {}: a row holds at most {} cells, found {}",
+                line,
+                u8::MAX,
+                found
+            ),
+            SchemeError::UnknownTile { line, tile } => {
+                write!(f, "line {}: unknown tile `{}`", line, tile)
+            }
+            SchemeError::PlayerOutOfRange { line, player } => {
+                write!(f, "line {}: player {} is out of range", line, player)
+            }
+            SchemeError::StartOutOfRange { line, x, y } => {
+                write!(f, "line {}: start cell ({}, {}) is out of the map", line, x, y)
+            }
+            SchemeError::PowerupOutOfRange { line, index } => {
+                write!(f, "line {}: power-up {} is out of range", line, index)
+            }
+            SchemeError::MissingRow { row } => write!(f, "row {} is not defined", row),
+        }
+    }
+}
+
+impl std::error::Error for SchemeError {}
+
+impl From<std::io::Error> for SchemeError {
+    fn from(err: std::io::Error) -> Self {
+        SchemeError::Io(err)
+    }
+}
+
+pub fn load(path: impl AsRef<Path>) -> Result<MapState, SchemeError> {
+    // Stock schemes are not always valid UTF-8, their comments are read lossily
+    let bytes = fs::read(path)?;
+    parse(&String::from_utf8_lossy(&bytes))
+}
+
+pub fn parse(source: &str) -> Result<MapState, SchemeError> {
+    let mut map_state = MapState::default();
+    let mut rows: Vec<Option<Vec<u8>>> = Vec::new();
+    let mut width = None;
+    // Start cells are checked once the size of the map is known
+    let mut start_cells: [Option<(usize, usize, usize)>; MAX_PLAYERS] = [None; MAX_PLAYERS];
+
+    for (i, raw_line) in source.lines().enumerate() {
+        let line = i + 1;
+        let content = raw_line.split(';').next().unwrap_or_default().trim();
+        if content.is_empty() {
+            continue;
+        }
+
+        let mut fields = content.split(',').map(str::trim);
+        let directive = fields.next().unwrap_or_default();
+
+        match directive.to_ascii_uppercase().as_str() {
+            "-V" | "-N" | "-B" => {}
+            "-R" => {
+                let row = parse_number(line, "row", fields.next())?;
+                if row >= u8::MAX as usize {
+                    return Err(SchemeError::RowOutOfRange { line, row });
+                }
+
+                let cells = fields.next().ok_or(SchemeError::MissingField {
+                    line,
+                    field: "cells",
+                })?;
+                let found = cells.chars().count();
+                if found == 0 {
+                    return Err(SchemeError::EmptyRow { line });
+                }
+                if found > u8::MAX as usize {
+                    return Err(SchemeError::RowTooLong { line, found });
+                }
+
+                let expected = *width.get_or_insert(found);
+                if found != expected {
+                    return Err(SchemeError::RowLength {
+                        line,
+                        expected,
+                        found,
+                    });
+                }
+
+                let tiles = cells
+                    .chars()
+                    .map(|tile| match tile {
+                        '#' => Ok(legend::BLOCK),
+                        ':' => Ok(legend::BRICK),
+                        '.' => Ok(legend::EMPTY),
+                        _ => Err(SchemeError::UnknownTile { line, tile }),
+                    })
+                    .collect::<Result<Vec<u8>, _>>()?;
+
+                if rows.len() <= row {
+                    rows.resize(row + 1, None);
+                }
+                rows[row] = Some(tiles);
+            }
+            "-S" => {
+                let player = parse_number(line, "player", fields.next())?;
+                if player >= MAX_PLAYERS {
+                    return Err(SchemeError::PlayerOutOfRange { line, player });
+                }
+
+                let x = parse_number(line, "x", fields.next())?;
+                let y = parse_number(line, "y", fields.next())?;
+
+                start_cells[player] = Some((line, x, y));
+            }
+            "-P" => {
+                let index = parse_number(line, "index", fields.next())?;
+                if index >= POWERUP_NUMBERS {
+                    return Err(SchemeError::PowerupOutOfRange { line, index });
+                }
+
+                let born_with = parse_number(line, "born with", fields.next())?;
+                let has_override = parse_number(line, "has override", fields.next())?;
+                let override_value = parse_number(line, "override value", fields.next())?;
+                let forbidden = parse_number(line, "forbidden", fields.next())?;
+
+                let Some(kind) = PowerupKind::from_scheme_index(index) else {
+                    continue;
+                };
+
+                let rule = &mut map_state.powerups[kind.index()];
+                rule.born_with = born_with.min(u8::MAX as usize) as u8;
+                rule.forbidden = forbidden != 0;
+                if has_override != 0 {
+                    rule.probability = override_value.min(100) as f32 / 100.0;
+                }
+            }
+            _ => {
+                return Err(SchemeError::UnknownDirective {
+                    line,
+                    directive: directive.to_string(),
+                })
+            }
+        }
+    }
+
+    let rows = rows
+        .into_iter()
+        .enumerate()
+        .map(|(row, tiles)| tiles.ok_or(SchemeError::MissingRow { row }))
+        .collect::<Result<Vec<_>, _>>()?;
+
+    if rows.is_empty() {
+        return Err(SchemeError::MissingRow { row: 0 });
+    }
+
+    map_state.scheme = Grid::from_rows(&rows);
+
+    if start_cells.iter().any(Option::is_some) {
+        map_state.start_cells.clear();
+
+        for (line, x, y) in start_cells.into_iter().flatten() {
+            let cell = Cell(x.min(u8::MAX as usize) as u8, y.min(u8::MAX as usize) as u8);
+            if x > u8::MAX as usize || y > u8::MAX as usize || !map_state.scheme.contains(cell) {
+                return Err(SchemeError::StartOutOfRange { line, x, y });
+            }
+
+            map_state.start_cells.push(cell);
+        }
+    } else {
+        // The default start cells are kept where the map is big enough, the
+        // top left one fits in any map
+        let grid = &map_state.scheme;
+        map_state.start_cells.retain(|cell| grid.contains(*cell));
+    }
+
+    Ok(map_state)
+}
+
+fn parse_number(
+    line: usize,
+    field: &'static str,
+    value: Option<&str>,
+) -> Result<usize, SchemeError> {
+    let value = value.ok_or(SchemeError::MissingField { line, field })?;
+
+    value.parse().map_err(|_| SchemeError::InvalidNumber {
+        line,
+        field,
+        value: value.to_string(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SCHEME: &str = "\
+; A small arena
+-V,2
+-N,Test
+-B,50
+-R,0,..:..
+-R,1,.#:#.
+-R,2,..:..
+-S,0,0,0
+-S,1,4,2,1
+-P,1,2,1,25,0,Flame
+-P,3,0,0,0,1,Kick
+";
+
+    fn parse_err(source: &str) -> SchemeError {
+        match parse(source) {
+            Ok(_) => panic!("parsed an invalid scheme"),
+            Err(err) => err,
+        }
+    }
+
+    #[test]
+    fn parses_valid_scheme() {
+        let map_state = parse(SCHEME).unwrap();
+        let grid = &map_state.scheme;
+
+        assert_eq!((grid.width(), grid.height()), (5, 3));
+        assert_eq!(grid.get(Cell(0, 0)), legend::EMPTY);
+        assert_eq!(grid.get(Cell(2, 0)), legend::BRICK);
+        assert_eq!(grid.get(Cell(1, 1)), legend::BLOCK);
+        assert_eq!(map_state.start_cells, vec![Cell(0, 0), Cell(4, 2)]);
+
+        let flame = &map_state.powerups[PowerupKind::Flame.index()];
+        assert_eq!(flame.born_with, 2);
+        assert_eq!(flame.probability, 0.25);
+        assert!(!flame.forbidden);
+        assert!(map_state.powerups[PowerupKind::Kick.index()].forbidden);
+    }
+
+    #[test]
+    fn keeps_default_start_cells_that_fit() {
+        let map_state = parse("-R,0,...\n-R,1,...").unwrap();
+        assert_eq!(map_state.start_cells, vec![Cell(0, 0)]);
+    }
+
+    #[test]
+    fn reports_unreadable_file() {
+        assert!(matches!(
+            load("/nonexistent/scheme.sch"),
+            Err(SchemeError::Io(_))
+        ));
+    }
+
+    #[test]
+    fn reports_every_error() {
+        assert!(matches!(
+            parse_err("-R,0,...\n-X,1"),
+            SchemeError::UnknownDirective { line: 2, directive } if directive == "-X"
+        ));
+        assert!(matches!(
+            parse_err("-R,0"),
+            SchemeError::MissingField { line: 1, field: "cells" }
+        ));
+        assert!(matches!(
+            parse_err("-R,zero,..."),
+            SchemeError::InvalidNumber { line: 1, field: "row", value } if value == "zero"
+        ));
+        assert!(matches!(
+            parse_err("-R,255,..."),
+            SchemeError::RowOutOfRange { line: 1, row: 255 }
+        ));
+        assert!(matches!(
+            parse_err("-R,0,...\n-R,1,...."),
+            SchemeError::RowLength { line: 2, expected: 3, found: 4 }
+        ));
+        assert!(matches!(
+            parse_err("-R,0,"),
+            SchemeError::EmptyRow { line: 1 }
+        ));
+        assert!(matches!(
+            parse_err(&format!("-R,0,{}", ".".repeat(256))),
+            SchemeError::RowTooLong { line: 1, found: 256 }
+        ));
+        assert!(matches!(
+            parse_err("-R,0,.x."),
+            SchemeError::UnknownTile { line: 1, tile: 'x' }
+        ));
+        assert!(matches!(
+            parse_err("-R,0,...\n-S,10,0,0"),
+            SchemeError::PlayerOutOfRange { line: 2, player: 10 }
+        ));
+        assert!(matches!(
+            parse_err("-R,0,...\n-S,0,3,0"),
+            SchemeError::StartOutOfRange { line: 2, x: 3, y: 0 }
+        ));
+        assert!(matches!(
+            parse_err("-R,0,...\n-P,13,0,0,0,0"),
+            SchemeError::PowerupOutOfRange { line: 2, index: 13 }
+        ));
+        assert!(matches!(
+            parse_err("-R,0,...\n-R,2,..."),
+            SchemeError::MissingRow { row: 1 }
+        ));
+        assert!(matches!(parse_err("-V,2"), SchemeError::MissingRow { row: 0 }));
+    }
+}
@@ -1,23 +1,39 @@
 use bevy::prelude::*;
 
-mod map;
-mod border;
-mod block;
-mod brick;
-mod player;
-mod bomb;
-mod explosion;
+pub mod map;
+pub mod border;
+pub mod block;
+pub mod brick;
+pub mod player;
+pub mod controls;
+pub mod ai;
+pub mod external;
+pub mod bomb;
+pub mod explosion;
+pub mod powerup;
+pub mod disease;
+pub mod round;
+pub mod sudden_death;
+pub mod collision;
 
 pub struct WorldPlugin;
 
 impl Plugin for WorldPlugin {
     fn build(&self, app: &mut App) {
-        app.add_plugins(map::MapPlugin)
+        app.add_plugins(round::RoundPlugin)
+            .add_plugins(map::MapPlugin)
             .add_plugins(border::BorderPlugin)
             .add_plugins(block::BlockPlugin)
             .add_plugins(brick::BrickPlugin)
             .add_plugins(explosion::ExplosionPlugin)
+            .add_plugins(powerup::PowerupPlugin)
+            .add_plugins(disease::DiseasePlugin)
             .add_plugins(bomb::BombPlugin)
-            .add_plugins(player::PlayerPlugin);
+            .add_plugins(controls::ControlsPlugin)
+            .add_plugins(player::PlayerPlugin)
+            .add_plugins(ai::AiPlugin)
+            .add_plugins(external::ExternalPlugin)
+            .add_plugins(sudden_death::SuddenDeathPlugin)
+            .add_plugins(collision::CollisionPlugin);
     }
 }
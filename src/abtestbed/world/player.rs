@@ -1,18 +1,26 @@
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::fmt;
+use std::str::FromStr;
+
 use bevy::prelude::*;
 use bevy_rapier2d::prelude::*;
+use serde::{Deserialize, Serialize};
 use uuid::Uuid;
 
+use super::ai;
 use super::bomb;
+use super::brick;
+use super::collision;
+use super::controls;
+use super::disease;
 use super::explosion;
 use super::map;
+use super::powerup::PowerupKind;
+use super::round;
+use super::sudden_death;
+use crate::abtestbed::config::{GameConfig, PlayerConfig};
 use crate::abtestbed::setup;
 
-const DEFAULT_SPEED: f32 = 70.0;
-const DEFAULT_FIRE_RANGE: u8 = 2;
-const DEFAULT_BOMB_CAPACITY: u8 = 1;
-
-const SIZE: Vec2 = Vec2::new(27.0, 27.0);
-const MASS: f32 = 100.0;
 const FRICTION: f32 = 0.0;
 const RESTITUTION: f32 = 0.0;
 
@@ -20,242 +28,676 @@ pub struct PlayerPlugin;
 
 impl Plugin for PlayerPlugin {
     fn build(&self, app: &mut App) {
-        app.add_systems(Startup, spawn_players).add_systems(
-            Update,
-            (
-                update_player_input,
-                movement_system,
-                handle_bomb_exploded,
-                track_explosion_players,
-            ),
-        );
+        app.init_resource::<Roster>()
+            .init_resource::<RosterSettings>()
+            .init_resource::<PendingInputs>()
+            .add_systems(Startup, init_roster)
+            .add_systems(
+                OnEnter(round::GameState::RoundStarting),
+                spawn_players.after(brick::spawn_bricks),
+            )
+            .add_systems(Update, read_local_inputs.run_if(round::round_running))
+            .configure_sets(
+                FixedUpdate,
+                InputsTaken.after(update_player_input).before(plant_bombs),
+            )
+            .add_systems(
+                FixedUpdate,
+                (
+                    update_player_input,
+                    plant_bombs,
+                    movement_system,
+                    track_explosion_players.after(explosion::spawn_explosion),
+                )
+                    .chain()
+                    .run_if(round::round_running),
+            )
+            .add_systems(
+                FixedUpdate,
+                give_bombs_back
+                    .after(bomb::explode_bombs)
+                    .after(sudden_death::crush_under_blocks)
+                    .before(round::score_round)
+                    .run_if(round::round_running),
+            );
     }
 }
-struct ControlKeys {
-    move_north: KeyCode,
-    move_south: KeyCode,
-    move_west: KeyCode,
-    move_east: KeyCode,
-    set_bomb: KeyCode,
-}
 
-impl std::default::Default for ControlKeys {
-    fn default() -> Self {
-        ControlKeys {
-            move_north: KeyCode::ArrowUp,
-            move_south: KeyCode::ArrowDown,
-            move_west: KeyCode::ArrowLeft,
-            move_east: KeyCode::ArrowRight,
-            set_bomb: KeyCode::Space,
-        }
-    }
-}
+/// Systems that see the inputs of the tick as every player took them over,
+/// before anything acts on them and clears a press.
+#[derive(SystemSet, Debug, Clone, PartialEq, Eq, Hash)]
+pub struct InputsTaken;
 
-#[derive(Default)]
+#[derive(Debug, Default, Copy, Clone, PartialEq, Serialize, Deserialize)]
 pub struct InputState {
-    horizontal_direction: i8,
-    vertical_direction: i8,
+    pub horizontal_direction: i8,
+    pub vertical_direction: i8,
+    pub plant_bomb: bool,
+    // Sets off the oldest of the player's trigger bombs
+    pub detonate: bool,
 }
 
-#[derive(Copy, Clone)]
+/// The colours of the original game, one per player.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
 pub enum PlayerColor {
     White,
     Black,
+    Red,
+    Blue,
+    Green,
+    Yellow,
+    Cyan,
+    Magenta,
+    Orange,
+    Purple,
 }
 
 impl PlayerColor {
-    pub fn to_bevy_color(&self) -> Color {
+    pub const ALL: [PlayerColor; map::scheme::MAX_PLAYERS] = [
+        PlayerColor::White,
+        PlayerColor::Black,
+        PlayerColor::Red,
+        PlayerColor::Blue,
+        PlayerColor::Green,
+        PlayerColor::Yellow,
+        PlayerColor::Cyan,
+        PlayerColor::Magenta,
+        PlayerColor::Orange,
+        PlayerColor::Purple,
+    ];
+
+    pub fn to_bevy_color(self) -> Color {
         match self {
             PlayerColor::White => Color::srgb(0.85, 0.85, 0.85),
             PlayerColor::Black => Color::srgb(0.35, 0.35, 0.35),
+            PlayerColor::Red => Color::srgb(0.85, 0.2, 0.2),
+            PlayerColor::Blue => Color::srgb(0.25, 0.35, 0.9),
+            PlayerColor::Green => Color::srgb(0.2, 0.7, 0.25),
+            PlayerColor::Yellow => Color::srgb(0.9, 0.85, 0.2),
+            PlayerColor::Cyan => Color::srgb(0.2, 0.8, 0.85),
+            PlayerColor::Magenta => Color::srgb(0.85, 0.25, 0.75),
+            PlayerColor::Orange => Color::srgb(0.95, 0.55, 0.15),
+            PlayerColor::Purple => Color::srgb(0.5, 0.25, 0.75),
         }
     }
 }
 
-#[derive(Component)]
+/// What drives a player: a keyboard layout and a gamepad seat of the
+/// `Bindings`, a bot, an external bot process, or inputs written to
+/// `PendingInputs` by the network or a replay.
+#[derive(Copy, Clone, Serialize, Deserialize)]
+pub enum Controls {
+    Keyboard(usize),
+    Gamepad(usize),
+    Bot(ai::Difficulty),
+    External(usize),
+    Remote,
+}
+
+/// A participant of the match, kept across rounds while the `Player` entity is
+/// spawned anew for every round.
+pub struct PlayerSlot {
+    pub id: Uuid,
+    pub color: PlayerColor,
+    pub controls: Controls,
+}
+
+impl PlayerSlot {
+    pub fn remote(id: Uuid, color: PlayerColor) -> Self {
+        PlayerSlot {
+            id,
+            color,
+            controls: Controls::Remote,
+        }
+    }
+}
+
+#[derive(Resource, Default)]
+pub struct Roster {
+    pub slots: Vec<PlayerSlot>,
+}
+
+impl Roster {
+    /// First colour nobody has taken yet.
+    pub fn free_color(&self) -> Option<PlayerColor> {
+        PlayerColor::ALL
+            .into_iter()
+            .find(|color| self.slots.iter().all(|slot| slot.color != *color))
+    }
+}
+
+/// Kind of a slot as given on the command line, before keyboard layouts and
+/// gamepads are handed out.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum SlotKind {
+    Keyboard,
+    Gamepad,
+    Bot(ai::Difficulty),
+    // A bot process started from the next `--external` command
+    External,
+    // Kept free for a client to join into
+    Remote,
+}
+
+#[derive(Debug)]
+pub struct UnknownSlotKind(String);
+
+impl fmt::Display for UnknownSlotKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "unknown player '{}', expected keyboard, gamepad, remote, external, easy, normal \
+             or hard",
+            self.0
+        )
+    }
+}
+
+impl std::error::Error for UnknownSlotKind {}
+
+impl FromStr for SlotKind {
+    type Err = UnknownSlotKind;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "keyboard" => Ok(SlotKind::Keyboard),
+            "gamepad" => Ok(SlotKind::Gamepad),
+            "remote" => Ok(SlotKind::Remote),
+            "external" => Ok(SlotKind::External),
+            _ => s
+                .parse()
+                .map(SlotKind::Bot)
+                .map_err(|_| UnknownSlotKind(s.to_string())),
+        }
+    }
+}
+
+/// Who takes the slots when the game starts, in colour order.
+#[derive(Resource)]
+pub struct RosterSettings {
+    pub slots: Vec<SlotKind>,
+}
+
+impl std::default::Default for RosterSettings {
+    fn default() -> Self {
+        RosterSettings {
+            slots: vec![SlotKind::Keyboard, SlotKind::Keyboard],
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum RosterError {
+    TooManyPlayers { count: usize },
+    TooManyKeyboards { count: usize, layouts: usize },
+}
+
+impl fmt::Display for RosterError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RosterError::TooManyPlayers { count } => write!(
+                f,
+                "{} players requested, at most {} can play",
+                count,
+                PlayerColor::ALL.len()
+            ),
+            RosterError::TooManyKeyboards { count, layouts } => write!(
+                f,
+                "{} keyboard players requested, the bindings only have {} keyboard layouts",
+                count, layouts
+            ),
+        }
+    }
+}
+
+impl std::error::Error for RosterError {}
+
+impl RosterSettings {
+    pub fn validate(&self, bindings: &controls::Bindings) -> Result<(), RosterError> {
+        if self.slots.len() > PlayerColor::ALL.len() {
+            return Err(RosterError::TooManyPlayers {
+                count: self.slots.len(),
+            });
+        }
+
+        let keyboards = self
+            .slots
+            .iter()
+            .filter(|kind| **kind == SlotKind::Keyboard)
+            .count();
+
+        if keyboards > bindings.keyboard.len() {
+            return Err(RosterError::TooManyKeyboards {
+                count: keyboards,
+                layouts: bindings.keyboard.len(),
+            });
+        }
+
+        Ok(())
+    }
+}
+
+/// Inputs of every player that changed since the last tick, taken over by the
+/// next `update_player_input`. Players without an entry keep their inputs.
+#[derive(Resource, Default)]
+pub struct PendingInputs {
+    pub by_id: HashMap<Uuid, InputState>,
+}
+
+#[derive(Component, Clone, Serialize, Deserialize)]
 pub struct Player {
-    controls: ControlKeys,
+    // Built-in bots are driven by writing their inputs directly
+    controls: Controls,
 
-    id: Uuid,
-    color: PlayerColor,
-    inputs: InputState,
+    pub id: Uuid,
+    pub color: PlayerColor,
+    pub inputs: InputState,
 
     curr_speed: f32,
 
     bomb_capacity: u8,
     fire_range: u8,
     bomb_detonation_period: f32,
+
+    abilities: BTreeSet<PowerupKind>,
+    // Last direction the player moved in, as (horizontal, vertical)
+    facing: (i8, i8),
 }
 
-impl std::default::Default for Player {
-    fn default() -> Self {
+impl Player {
+    pub fn new(slot: &PlayerSlot, config: &GameConfig) -> Self {
         Player {
-            id: Uuid::new_v4(),
-            color: PlayerColor::White,
-            controls: ControlKeys::default(),
+            id: slot.id,
+            color: slot.color,
+            controls: slot.controls,
             inputs: InputState::default(),
-            bomb_capacity: DEFAULT_BOMB_CAPACITY,
-            fire_range: DEFAULT_FIRE_RANGE,
-            curr_speed: DEFAULT_SPEED,
-            bomb_detonation_period: bomb::DEFAULT_DETONATION_PERIOD,
+            bomb_capacity: config.player.bomb_capacity,
+            fire_range: config.player.fire_range,
+            curr_speed: config.player.speed,
+            bomb_detonation_period: config.bomb.detonation_period,
+            abilities: BTreeSet::new(),
+            facing: (0, -1),
+        }
+    }
+
+    /// Hands the player over to `PendingInputs`, as playback and the network drive it.
+    pub fn make_remote(&mut self) {
+        self.controls = Controls::Remote;
+    }
+
+    pub fn collect(&mut self, kind: PowerupKind, config: &PlayerConfig) {
+        match kind {
+            PowerupKind::ExtraBomb => self.bomb_capacity = self.bomb_capacity.saturating_add(1),
+            PowerupKind::Flame => {
+                self.fire_range = (self.fire_range + 1).min(config.max_fire_range)
+            }
+            PowerupKind::Speed => {
+                self.curr_speed = (self.curr_speed + config.speed_step).min(config.max_speed)
+            }
+            PowerupKind::FullFire | PowerupKind::GoldFlame => {
+                self.fire_range = config.max_fire_range
+            }
+            // Curses are handed out by the disease plugin
+            PowerupKind::Disease => {}
+            PowerupKind::Kick
+            | PowerupKind::Punch
+            | PowerupKind::Grab
+            | PowerupKind::Spooge
+            | PowerupKind::Trigger
+            | PowerupKind::Jelly => {
+                self.abilities.insert(kind);
+            }
         }
     }
+
+    pub fn has_ability(&self, kind: PowerupKind) -> bool {
+        self.abilities.contains(&kind)
+    }
+
+    pub fn speed(&self) -> f32 {
+        self.curr_speed
+    }
+
+    pub fn fire_range(&self) -> u8 {
+        self.fire_range
+    }
+
+    pub fn bomb_capacity(&self) -> u8 {
+        self.bomb_capacity
+    }
+
+    pub fn facing(&self) -> (i8, i8) {
+        self.facing
+    }
 }
 
-fn spawn_players(mut commands: Commands) {
-    let collision_groups = CollisionGroups::new(
-        Group::from_bits(setup::collision::policy::PLAYER.0).unwrap(),
-        Group::from_bits(setup::collision::policy::PLAYER.1).unwrap(),
-    );
-
-    commands.spawn((
-        Player::default(),
-        Sprite {
-            color: PlayerColor::White.to_bevy_color(),
-            custom_size: Some(SIZE),
-            ..default()
-        },
-        map::Cell(0, 0).center(),
-        RigidBody::Dynamic,
-        Velocity::zero(),
-        LockedAxes::ROTATION_LOCKED_Z,
-        Collider::cuboid(SIZE.x / 2.0, SIZE.y / 2.0),
-        collision_groups,
-        ColliderMassProperties::Mass(MASS),
-        Friction::new(FRICTION),
-        Restitution::new(RESTITUTION),
-        ExternalForce::default(),
-    ));
-
-    commands.spawn((
-        Player {
-            color: PlayerColor::Black,
-            controls: ControlKeys {
-                move_north: KeyCode::KeyW,
-                move_south: KeyCode::KeyS,
-                move_west: KeyCode::KeyA,
-                move_east: KeyCode::KeyD,
-                set_bomb: KeyCode::KeyV,
+pub fn init_roster(
+    mut roster: ResMut<Roster>,
+    settings: Res<RosterSettings>,
+    bindings: Res<controls::Bindings>,
+    mut rng: ResMut<setup::GameRng>,
+) {
+    let mut keyboard_layouts = 0..bindings.keyboard.len();
+    let mut gamepad_seats = 0..;
+    let mut external_commands = 0..;
+
+    for kind in &settings.slots {
+        let Some(color) = roster.free_color() else {
+            warn!("The roster is full, dropping the remaining players");
+            break;
+        };
+
+        let controls = match kind {
+            SlotKind::Keyboard => match keyboard_layouts.next() {
+                Some(layout) => Controls::Keyboard(layout),
+                None => {
+                    warn!("No keyboard layout is left, skipping a keyboard player");
+                    continue;
+                }
+            },
+            SlotKind::Gamepad => Controls::Gamepad(gamepad_seats.next().unwrap_or_default()),
+            SlotKind::Bot(difficulty) => Controls::Bot(*difficulty),
+            SlotKind::External => {
+                Controls::External(external_commands.next().unwrap_or_default())
+            }
+            SlotKind::Remote => Controls::Remote,
+        };
+
+        roster.slots.push(PlayerSlot {
+            id: rng.next_uuid(),
+            color,
+            controls,
+        });
+    }
+}
+
+fn spawn_players(
+    mut commands: Commands,
+    config: Res<GameConfig>,
+    map_state: Res<map::MapState>,
+    roster: Res<Roster>,
+) {
+    // Maps with fewer start cells than players have some of them share a start
+    let mut starts: Vec<(&PlayerSlot, &map::Cell)> = roster
+        .slots
+        .iter()
+        .zip(map_state.start_cells.iter().cycle())
+        .collect();
+
+    // Bodies are added in the order a restored snapshot adds them
+    starts.sort_by_key(|(slot, _)| slot.id);
+    for (slot, cell) in starts {
+        spawn_player(&mut commands, &config, &map_state, slot, *cell);
+    }
+}
+
+pub fn spawn_player(
+    commands: &mut Commands,
+    config: &GameConfig,
+    map_state: &map::MapState,
+    slot: &PlayerSlot,
+    cell: map::Cell,
+) -> Entity {
+    let mut player = Player::new(slot, config);
+
+    for kind in PowerupKind::ALL {
+        let rule = &map_state.powerups[kind.index()];
+        if rule.forbidden {
+            continue;
+        }
+
+        for _ in 0..rule.born_with {
+            player.collect(kind, &config.player);
+        }
+    }
+
+    let entity = spawn_player_body(commands, config, player, cell.center(&map_state.scheme));
+
+    if let Controls::Bot(difficulty) = slot.controls {
+        commands
+            .entity(entity)
+            .insert(ai::Bot::new(difficulty, slot.id.as_u64_pair().0));
+    }
+
+    entity
+}
+
+/// Spawns `player` at rest with everything it needs to move and collide,
+/// leaving out what drives it.
+pub fn spawn_player_body(
+    commands: &mut Commands,
+    config: &GameConfig,
+    player: Player,
+    transform: Transform,
+) -> Entity {
+    let color = player.color;
+    let size = Vec2::from(config.player.size);
+
+    commands
+        .spawn((
+            round::RoundEntity,
+            player,
+            Sprite {
+                color: color.to_bevy_color(),
+                custom_size: Some(size),
+                ..default()
             },
-            ..default()
-        },
-        Sprite {
-            color: PlayerColor::Black.to_bevy_color(),
-            custom_size: Some(SIZE),
-            ..default()
-        },
-        map::Cell(2, 2).center(),
-        RigidBody::Dynamic,
-        Velocity::zero(),
-        LockedAxes::ROTATION_LOCKED_Z,
-        Collider::cuboid(SIZE.x / 2.0, SIZE.y / 2.0),
-        collision_groups,
-        ColliderMassProperties::Mass(MASS),
-        Friction::new(FRICTION),
-        Restitution::new(RESTITUTION),
-        ExternalForce::default(),
-    ));
+            transform,
+            RigidBody::Dynamic,
+            Velocity::zero(),
+            LockedAxes::ROTATION_LOCKED_Z,
+            Collider::cuboid(size.x / 2.0, size.y / 2.0),
+            CollisionGroups::new(
+                Group::from_bits(setup::collision::policy::PLAYER.0).unwrap(),
+                Group::from_bits(setup::collision::policy::PLAYER.1).unwrap(),
+            ),
+            ColliderMassProperties::Mass(config.player.mass),
+            Friction::new(FRICTION),
+            Restitution::new(RESTITUTION),
+            ExternalForce::default(),
+        ))
+        .id()
 }
 
-fn update_player_input(
+/// Reads the keyboard and gamepads once a frame. A frame may hold several
+/// ticks or none, so presses are kept until a tick takes them.
+fn read_local_inputs(
     kbd_input: Res<ButtonInput<KeyCode>>,
-    mut query: Query<(&mut Player, &Transform)>,
+    bindings: Res<controls::Bindings>,
+    seats: Res<controls::GamepadSeats>,
+    gamepads: Query<&Gamepad>,
+    mut pending_inputs: ResMut<PendingInputs>,
+    query: Query<&Player>,
+) {
+    for player in &query {
+        let inputs = match player.controls {
+            Controls::Keyboard(layout) => match bindings.keyboard.get(layout) {
+                Some(control_set) => control_set.read(&kbd_input, None),
+                None => continue,
+            },
+            // A player whose gamepad is unplugged stands still until it is back
+            Controls::Gamepad(seat) => match seats.gamepad(seat, &gamepads) {
+                Some(gamepad) => bindings.gamepad.read(&kbd_input, Some(gamepad)),
+                None => InputState::default(),
+            },
+            Controls::Bot(_) | Controls::External(_) | Controls::Remote => continue,
+        };
+
+        let pending = pending_inputs.by_id.entry(player.id).or_default();
+        *pending = InputState {
+            plant_bomb: pending.plant_bomb || inputs.plant_bomb,
+            detonate: pending.detonate || inputs.detonate,
+            ..inputs
+        };
+    }
+}
+
+pub fn update_player_input(
+    mut pending_inputs: ResMut<PendingInputs>,
+    mut query: Query<&mut Player>,
+) {
+    for mut player in &mut query {
+        if let Some(inputs) = pending_inputs.by_id.remove(&player.id) {
+            player.inputs = inputs;
+        }
+    }
+
+    pending_inputs.by_id.clear();
+}
+
+pub fn plant_bombs(
+    mut query: Query<(&mut Player, &Transform, Option<&disease::Curse>)>,
     mut events: EventWriter<bomb::BombPlanted>,
     planted_bombs: Res<bomb::PlantedBombs>,
+    map_state: Res<map::MapState>,
+    config: Res<GameConfig>,
 ) {
-    for (mut player, transform) in &mut query {
-        let move_west = kbd_input.pressed(player.controls.move_west);
-        let move_east = kbd_input.pressed(player.controls.move_east);
-        let move_north = kbd_input.pressed(player.controls.move_north);
-        let move_south = kbd_input.pressed(player.controls.move_south);
-
-        player.inputs.horizontal_direction = move_east as i8 - move_west as i8;
-        player.inputs.vertical_direction = move_north as i8 - move_south as i8;
-
-        let player_cell = map::Cell::from_transform(transform);
-
-        if kbd_input.just_pressed(player.controls.set_bomb)
-            && player.bomb_capacity > 0
-            && !planted_bombs.set.contains(&player_cell)
-        {
-            events.send(bomb::BombPlanted {
-                player_id: player.id,
-                player_color: player.color,
-                player_cell: player_cell,
-                player_fire_range: player.fire_range,
-                player_bomb_detonation_period: player.bomb_detonation_period,
-            });
+    for (mut player, transform, curse) in &mut query {
+        let mut inputs = InputState {
+            plant_bomb: std::mem::take(&mut player.inputs.plant_bomb),
+            ..player.inputs
+        };
+        if let Some(curse) = curse {
+            curse.apply(&mut inputs);
+        }
+
+        if !inputs.plant_bomb {
+            continue;
+        }
+
+        let player_cell = map::Cell::from_transform(&map_state.scheme, transform);
+
+        if !planted_bombs.set.contains(&player_cell) {
+            plant_bomb(&mut player, curse, &config, player_cell, &mut events);
+            continue;
+        }
+
+        // With spooge, pressing on a bomb lays the rest of the bombs in a line
+        if !player.has_ability(PowerupKind::Spooge) {
+            continue;
+        }
+
+        let (dx, dy) = player.facing;
+        let mut cell = player_cell;
+
+        while player.bomb_capacity > 0 {
+            let Some(next_cell) = cell.neighbour(&map_state.scheme, dx, dy) else {
+                break;
+            };
 
-            player.bomb_capacity -= 1;
+            cell = next_cell;
+
+            if map_state.tile(cell) != map::legend::EMPTY || planted_bombs.set.contains(&cell) {
+                break;
+            }
+
+            plant_bomb(&mut player, curse, &config, cell, &mut events);
         }
     }
 }
 
-fn movement_system(
+fn plant_bomb(
+    player: &mut Player,
+    curse: Option<&disease::Curse>,
+    config: &GameConfig,
+    cell: map::Cell,
+    events: &mut EventWriter<bomb::BombPlanted>,
+) {
+    if player.bomb_capacity == 0 {
+        return;
+    }
+
+    let mut fire_range = player.fire_range;
+    let mut detonation_period = player.bomb_detonation_period;
+    if let Some(curse) = curse {
+        fire_range = curse.fire_range(fire_range);
+        detonation_period = curse.detonation_period(detonation_period, &config.disease);
+    }
+
+    events.send(bomb::BombPlanted {
+        player_id: player.id,
+        player_color: player.color,
+        player_cell: cell,
+        player_fire_range: fire_range,
+        player_bomb_detonation_period: detonation_period,
+        player_has_trigger: player.has_ability(PowerupKind::Trigger),
+    });
+
+    player.bomb_capacity -= 1;
+}
+
+pub fn movement_system(
+    config: Res<GameConfig>,
     timestep_mode: Res<TimestepMode>,
-    mut query: Query<(&Player, &Velocity, &mut ExternalForce)>,
+    mut query: Query<(&mut Player, &Velocity, &mut ExternalForce, Option<&disease::Curse>)>,
 ) {
-    for (player, velocity, mut ext_force) in &mut query {
+    for (mut player, velocity, mut ext_force, curse) in &mut query {
+        // Remote inputs stay in place between messages, so curses must not
+        // flip them for good
+        let mut inputs = player.inputs;
+        let mut speed = player.curr_speed;
+        if let Some(curse) = curse {
+            curse.apply(&mut inputs);
+            speed = curse.speed(speed, &config.disease);
+        }
+
+        if inputs.horizontal_direction != 0 {
+            player.facing = (inputs.horizontal_direction, 0);
+        } else if inputs.vertical_direction != 0 {
+            player.facing = (0, inputs.vertical_direction);
+        }
+
         let desired_vel = Vec2::new(
-            player.inputs.horizontal_direction as f32 * player.curr_speed,
-            player.inputs.vertical_direction as f32 * player.curr_speed,
+            inputs.horizontal_direction as f32 * speed,
+            inputs.vertical_direction as f32 * speed,
         );
 
         let current_vel = velocity.linvel;
         let vel_delta = desired_vel - current_vel;
 
         let mut time_delta = 0.0;
-        match timestep_mode.as_ref() {
-            TimestepMode::Fixed { dt, substeps: _ } => time_delta = dt.clone(),
-            _ => (),
+        if let TimestepMode::Fixed { dt, substeps: _ } = timestep_mode.as_ref() {
+            time_delta = *dt;
         }
 
         let desired_force = Vec2::new(
             // [Н] = [кг] * [м/с] / [с]
-            MASS * vel_delta.x / time_delta,
-            MASS * vel_delta.y / time_delta,
+            config.player.mass * vel_delta.x / time_delta,
+            config.player.mass * vel_delta.y / time_delta,
         );
 
         ext_force.force = desired_force;
     }
 }
 
-fn handle_bomb_exploded(
-    mut events: EventReader<bomb::BombExploded>,
+/// Hands a bomb back to its owner once it is gone, blown up or crushed.
+fn give_bombs_back(
+    mut exploded_events: EventReader<bomb::BombExploded>,
+    mut crushed_events: EventReader<bomb::BombCrushed>,
     mut query: Query<&mut Player>,
 ) {
-    for event in events.read() {
+    let owners = exploded_events
+        .read()
+        .map(|event| event.player_id)
+        .chain(crushed_events.read().map(|event| event.player_id));
+
+    for owner in owners {
         for mut player in &mut query {
-            if player.id == event.player_id {
-                player.bomb_capacity += 1;
+            if player.id == owner {
+                player.bomb_capacity = player.bomb_capacity.saturating_add(1);
                 break;
             }
         }
     }
 }
 
-fn track_explosion_players(
+pub fn track_explosion_players(
     mut commands: Commands,
-    mut collision_events: EventReader<CollisionEvent>,
-    explosions: Query<(), With<explosion::Explosion>>,
-    players: Query<((), &Player)>,
+    mut hits: EventReader<collision::ExplosionHitPlayer>,
 ) {
-    for collision_event in collision_events.read() {
-        match collision_event {
-            CollisionEvent::Started(e1, e2, _) => {
-                let player_entity;
-
-                if explosions.get(*e1).is_ok() && players.get(*e2).is_ok() {
-                    player_entity = e2;
-                } else if explosions.get(*e2).is_ok() && players.get(*e1).is_ok() {
-                    player_entity = e1;
-                } else {
-                    return;
-                }
+    let burnt: HashSet<Entity> = hits.read().map(|hit| hit.player).collect();
 
-                commands.entity(*player_entity).despawn();
-            }
-            _ => {}
-        }
+    for e in burnt {
+        commands.entity(e).despawn();
     }
 }
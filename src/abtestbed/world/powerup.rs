@@ -0,0 +1,279 @@
+use std::collections::{HashMap, HashSet};
+
+use bevy::prelude::*;
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use super::brick;
+use super::collision;
+use super::disease;
+use super::explosion;
+use super::map;
+use super::player;
+use super::round;
+use crate::abtestbed::config::GameConfig;
+use crate::abtestbed::setup;
+
+pub const KINDS: usize = 12;
+
+pub struct PowerupPlugin;
+
+impl Plugin for PowerupPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_systems(
+            FixedUpdate,
+            (
+                reveal_powerups,
+                collect_powerups,
+                track_explosion_powerups,
+            )
+                .chain()
+                .after(brick::track_explosion_bricks)
+                .after(player::track_explosion_players)
+                .run_if(round::round_running),
+        );
+    }
+}
+
+#[derive(Debug, Copy, Clone, Hash, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+pub enum PowerupKind {
+    ExtraBomb,
+    Flame,
+    Speed,
+    FullFire,
+    Kick,
+    Punch,
+    Grab,
+    Spooge,
+    Trigger,
+    Jelly,
+    GoldFlame,
+    Disease,
+}
+
+impl PowerupKind {
+    pub const ALL: [PowerupKind; KINDS] = [
+        PowerupKind::ExtraBomb,
+        PowerupKind::Flame,
+        PowerupKind::Speed,
+        PowerupKind::FullFire,
+        PowerupKind::Kick,
+        PowerupKind::Punch,
+        PowerupKind::Grab,
+        PowerupKind::Spooge,
+        PowerupKind::Trigger,
+        PowerupKind::Jelly,
+        PowerupKind::GoldFlame,
+        PowerupKind::Disease,
+    ];
+
+    pub fn index(self) -> usize {
+        self as usize
+    }
+
+    /// Maps a power-up number of the original `.SCH` files. Full fire has no
+    /// number of its own there, the random item is not supported.
+    pub fn from_scheme_index(index: usize) -> Option<Self> {
+        match index {
+            0 => Some(PowerupKind::ExtraBomb),
+            1 => Some(PowerupKind::Flame),
+            2 | 11 => Some(PowerupKind::Disease),
+            3 => Some(PowerupKind::Kick),
+            4 => Some(PowerupKind::Speed),
+            5 => Some(PowerupKind::Punch),
+            6 => Some(PowerupKind::Grab),
+            7 => Some(PowerupKind::Spooge),
+            8 => Some(PowerupKind::GoldFlame),
+            9 => Some(PowerupKind::Trigger),
+            10 => Some(PowerupKind::Jelly),
+            _ => None,
+        }
+    }
+
+    pub fn to_bevy_color(self) -> Color {
+        match self {
+            PowerupKind::ExtraBomb => Color::srgb(0.1, 0.1, 0.1),
+            PowerupKind::Flame => Color::srgb(0.9, 0.4, 0.1),
+            PowerupKind::Speed => Color::srgb(0.3, 0.7, 0.9),
+            PowerupKind::FullFire => Color::srgb(0.9, 0.1, 0.1),
+            PowerupKind::Kick => Color::srgb(0.6, 0.4, 0.2),
+            PowerupKind::Punch => Color::srgb(0.9, 0.6, 0.6),
+            PowerupKind::Grab => Color::srgb(0.6, 0.6, 0.9),
+            PowerupKind::Spooge => Color::srgb(0.5, 0.9, 0.5),
+            PowerupKind::Trigger => Color::srgb(0.9, 0.9, 0.2),
+            PowerupKind::Jelly => Color::srgb(0.7, 0.2, 0.7),
+            PowerupKind::GoldFlame => Color::srgb(1.0, 0.8, 0.0),
+            PowerupKind::Disease => Color::srgb(0.4, 0.5, 0.1),
+        }
+    }
+
+    // Chance in percent that a destroyed brick reveals this item
+    fn default_chance(self) -> u8 {
+        match self {
+            PowerupKind::ExtraBomb | PowerupKind::Flame => 10,
+            PowerupKind::Speed => 8,
+            PowerupKind::Kick => 4,
+            PowerupKind::Disease => 3,
+            PowerupKind::Punch | PowerupKind::Grab => 2,
+            PowerupKind::FullFire
+            | PowerupKind::Spooge
+            | PowerupKind::Trigger
+            | PowerupKind::Jelly
+            | PowerupKind::GoldFlame => 1,
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PowerupRule {
+    pub born_with: u8,
+    pub probability: f32,
+    pub forbidden: bool,
+}
+
+pub fn default_rules() -> [PowerupRule; KINDS] {
+    PowerupKind::ALL.map(|kind| PowerupRule {
+        born_with: 0,
+        probability: kind.default_chance() as f32 / 100.0,
+        forbidden: false,
+    })
+}
+
+#[derive(Component, Clone, Serialize, Deserialize)]
+pub struct Powerup {
+    pub kind: PowerupKind,
+    pub cell: map::Cell,
+    pub revealed_at: u64,
+}
+
+pub fn reveal_powerups(
+    mut commands: Commands,
+    mut brick_destroyed_events: EventReader<brick::BrickDestroyed>,
+    config: Res<GameConfig>,
+    map_state: Res<map::MapState>,
+    tick: Res<setup::SimTick>,
+    mut rng: ResMut<setup::GameRng>,
+) {
+    for bd_event in brick_destroyed_events.read() {
+        let roll: f32 = rng.0.gen();
+        let mut threshold = 0.0;
+
+        let revealed = PowerupKind::ALL.into_iter().find(|kind| {
+            let rule = &map_state.powerups[kind.index()];
+            if rule.forbidden {
+                return false;
+            }
+
+            threshold += rule.probability;
+            roll < threshold
+        });
+
+        let Some(kind) = revealed else {
+            continue;
+        };
+
+        let powerup = Powerup {
+            kind,
+            cell: bd_event.cell,
+            revealed_at: tick.0,
+        };
+
+        spawn_powerup(&mut commands, &config, &map_state.scheme, powerup);
+    }
+}
+
+pub fn spawn_powerup(
+    commands: &mut Commands,
+    config: &GameConfig,
+    grid: &map::Grid,
+    powerup: Powerup,
+) -> Entity {
+    let color = powerup.kind.to_bevy_color();
+    let transform = powerup.cell.center(grid);
+
+    commands
+        .spawn((
+            round::RoundEntity,
+            powerup,
+            Sprite {
+                color,
+                custom_size: Some(config.powerup.size.into()),
+                ..default()
+            },
+            transform,
+        ))
+        .id()
+}
+
+pub fn collect_powerups(
+    mut commands: Commands,
+    config: Res<GameConfig>,
+    mut touches: EventReader<collision::PlayerTouchedPowerup>,
+    mut players: Query<&mut player::Player>,
+    powerups: Query<&Powerup>,
+    mut diseases: EventWriter<disease::DiseaseCaught>,
+) {
+    // Items go in cell order and to the lowest player id on the cell, so the
+    // outcome does not hang on the order entities are stored in
+    let mut collectors: HashMap<Entity, (Uuid, Entity)> = HashMap::new();
+    for touch in touches.read() {
+        let Ok(player) = players.get(touch.player) else {
+            continue;
+        };
+
+        let collector = (player.id, touch.player);
+        collectors
+            .entry(touch.powerup)
+            .and_modify(|lowest| *lowest = (*lowest).min(collector))
+            .or_insert(collector);
+    }
+
+    let mut collected: Vec<(Entity, &Powerup, Entity)> = collectors
+        .into_iter()
+        .filter_map(|(powerup_entity, (_, entity))| {
+            Some((powerup_entity, powerups.get(powerup_entity).ok()?, entity))
+        })
+        .collect();
+    collected.sort_by_key(|(_, powerup, _)| (powerup.cell.1, powerup.cell.0, powerup.kind));
+
+    for (powerup_entity, powerup, entity) in collected {
+        let Ok(mut player) = players.get_mut(entity) else {
+            continue;
+        };
+
+        if powerup.kind == PowerupKind::Disease {
+            diseases.send(disease::DiseaseCaught { player: entity });
+        }
+
+        player.collect(powerup.kind, &config.player);
+        commands.entity(powerup_entity).despawn();
+    }
+}
+
+// Only fresh explosions destroy items, so the blast that revealed an item
+// from a brick does not burn it right away
+pub fn track_explosion_powerups(
+    mut commands: Commands,
+    mut hits: EventReader<collision::ExplosionHitPowerup>,
+    explosions: Query<&explosion::Explosion>,
+    powerups: Query<&Powerup>,
+    tick: Res<setup::SimTick>,
+) {
+    let mut burnt = HashSet::new();
+
+    for hit in hits.read() {
+        let Ok(explosion) = explosions.get(hit.explosion) else {
+            continue;
+        };
+        let Ok(powerup) = powerups.get(hit.powerup) else {
+            continue;
+        };
+
+        // Items revealed by this very blast come out of the flames unharmed
+        let burns = explosion.lit_at() == tick.0 && powerup.revealed_at != tick.0;
+        if burns && burnt.insert(hit.powerup) {
+            commands.entity(hit.powerup).despawn();
+        }
+    }
+}
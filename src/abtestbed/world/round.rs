@@ -0,0 +1,200 @@
+use std::collections::BTreeMap;
+
+use bevy::prelude::*;
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use super::map;
+use super::player;
+use super::sudden_death;
+use crate::abtestbed::config::GameConfig;
+use crate::abtestbed::setup::SimTick;
+
+const MIN_PLAYERS: usize = 2;
+pub const DEFAULT_WINS_TO_WIN: u8 = 3;
+
+pub struct RoundPlugin;
+
+impl Plugin for RoundPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_state::<GameState>()
+            .init_resource::<MatchSettings>()
+            .init_resource::<MatchScore>()
+            .init_resource::<PhaseEnd>()
+            .add_systems(OnEnter(GameState::Lobby), despawn_world)
+            .add_systems(OnExit(GameState::Lobby), reset_score)
+            .add_systems(
+                OnEnter(GameState::RoundStarting),
+                // The last arena goes before the new one is spawned
+                (despawn_world.before(map::reset_map), start_round),
+            )
+            .add_systems(OnEnter(GameState::RoundOver), end_round)
+            .add_systems(
+                Update,
+                (
+                    start_match.run_if(in_state(GameState::Lobby)),
+                    restart_match.run_if(in_state(GameState::MatchOver)),
+                ),
+            )
+            .add_systems(
+                FixedUpdate,
+                (
+                    finish_round_countdown.run_if(in_state(GameState::RoundStarting)),
+                    watch_last_player_standing.run_if(in_state(GameState::InRound)),
+                    score_round.run_if(in_state(GameState::RoundOver)),
+                )
+                    .chain()
+                    .after(sudden_death::crush_under_blocks),
+            );
+    }
+}
+
+#[derive(States, Debug, Copy, Clone, Default, Hash, PartialEq, Eq, Serialize, Deserialize)]
+pub enum GameState {
+    #[default]
+    Lobby,
+    // Rebinding the controls, entered from the lobby
+    ControlsScreen,
+    RoundStarting,
+    InRound,
+    RoundOver,
+    MatchOver,
+}
+
+#[derive(Resource)]
+pub struct MatchSettings {
+    pub wins_to_win: u8,
+    // Skips waiting for the start key, for runs without anybody at the keyboard
+    pub auto_start: bool,
+}
+
+impl std::default::Default for MatchSettings {
+    fn default() -> Self {
+        MatchSettings {
+            wins_to_win: DEFAULT_WINS_TO_WIN,
+            auto_start: false,
+        }
+    }
+}
+
+#[derive(Resource, Default, Clone, Serialize, Deserialize)]
+pub struct MatchScore {
+    pub round: u32,
+    pub wins: BTreeMap<Uuid, u8>,
+    pub champion: Option<Uuid>,
+}
+
+/// Marks everything that belongs to the arena of the current round.
+#[derive(Component)]
+pub struct RoundEntity;
+
+/// Tick the countdown before a round or the pause after it ends at.
+#[derive(Resource, Default, Copy, Clone, Serialize, Deserialize)]
+pub struct PhaseEnd(u64);
+
+/// Gameplay only runs while a round is being played out.
+pub fn round_running(state: Res<State<GameState>>) -> bool {
+    matches!(state.get(), GameState::InRound | GameState::RoundOver)
+}
+
+fn despawn_world(mut commands: Commands, query: Query<Entity, With<RoundEntity>>) {
+    for e in &query {
+        commands.entity(e).despawn_recursive();
+    }
+}
+
+fn reset_score(mut score: ResMut<MatchScore>) {
+    *score = MatchScore::default();
+}
+
+fn start_round(
+    config: Res<GameConfig>,
+    tick: Res<SimTick>,
+    mut score: ResMut<MatchScore>,
+    mut phase_end: ResMut<PhaseEnd>,
+) {
+    score.round += 1;
+    phase_end.0 = tick.0 + config.ticks(config.round.start_delay);
+
+    info!("Round {} is starting", score.round);
+}
+
+fn end_round(config: Res<GameConfig>, tick: Res<SimTick>, mut phase_end: ResMut<PhaseEnd>) {
+    phase_end.0 = tick.0 + config.ticks(config.round.over_delay);
+}
+
+fn start_match(
+    kbd_input: Res<ButtonInput<KeyCode>>,
+    settings: Res<MatchSettings>,
+    roster: Res<player::Roster>,
+    mut next_state: ResMut<NextState<GameState>>,
+) {
+    if roster.slots.len() < MIN_PLAYERS {
+        return;
+    }
+
+    if settings.auto_start || kbd_input.just_pressed(KeyCode::Enter) {
+        next_state.set(GameState::RoundStarting);
+    }
+}
+
+fn finish_round_countdown(
+    tick: Res<SimTick>,
+    phase_end: Res<PhaseEnd>,
+    mut next_state: ResMut<NextState<GameState>>,
+) {
+    if tick.0 >= phase_end.0 {
+        next_state.set(GameState::InRound);
+    }
+}
+
+fn watch_last_player_standing(
+    players: Query<(), With<player::Player>>,
+    mut next_state: ResMut<NextState<GameState>>,
+) {
+    if players.iter().count() <= 1 {
+        next_state.set(GameState::RoundOver);
+    }
+}
+
+pub fn score_round(
+    tick: Res<SimTick>,
+    phase_end: Res<PhaseEnd>,
+    settings: Res<MatchSettings>,
+    mut score: ResMut<MatchScore>,
+    players: Query<&player::Player>,
+    mut next_state: ResMut<NextState<GameState>>,
+) {
+    if tick.0 < phase_end.0 {
+        return;
+    }
+
+    let survivors: Vec<&player::Player> = players.iter().collect();
+    let [winner] = survivors.as_slice() else {
+        info!("Round {} is a draw", score.round);
+        next_state.set(GameState::RoundStarting);
+        return;
+    };
+
+    let wins = score.wins.entry(winner.id).or_default();
+    *wins += 1;
+    let wins = *wins;
+
+    info!("Round {} goes to {:?} ({} wins)", score.round, winner.color, wins);
+
+    if wins >= settings.wins_to_win {
+        score.champion = Some(winner.id);
+        next_state.set(GameState::MatchOver);
+    } else {
+        next_state.set(GameState::RoundStarting);
+    }
+}
+
+fn restart_match(
+    kbd_input: Res<ButtonInput<KeyCode>>,
+    mut next_state: ResMut<NextState<GameState>>,
+) {
+    if kbd_input.just_pressed(KeyCode::Enter) {
+        next_state.set(GameState::Lobby);
+    }
+}
@@ -0,0 +1,168 @@
+//! Once a round has gone on for too long, blocks drop in a spiral from the
+//! edges of the arena inward and crush whatever is in the cells they fill.
+
+use bevy::prelude::*;
+use serde::{Deserialize, Serialize};
+
+use super::block;
+use super::bomb;
+use super::collision;
+use super::disease;
+use super::map;
+use super::powerup;
+use super::round;
+use crate::abtestbed::config::GameConfig;
+
+pub struct SuddenDeathPlugin;
+
+impl Plugin for SuddenDeathPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<SuddenDeath>()
+            .add_event::<BlockDropped>()
+            .add_systems(
+                OnEnter(round::GameState::RoundStarting),
+                reset_sudden_death.after(map::reset_map),
+            )
+            .add_systems(
+                FixedUpdate,
+                (
+                    drop_blocks
+                        .after(bomb::track_player_gone)
+                        .after(powerup::track_explosion_powerups)
+                        .after(disease::blink_cursed_players)
+                        .run_if(in_state(round::GameState::InRound)),
+                    crush_under_blocks
+                        .after(drop_blocks)
+                        .run_if(round::round_running),
+                ),
+            );
+    }
+}
+
+#[derive(Resource, Default, Clone, Serialize, Deserialize)]
+pub struct SuddenDeath {
+    // Ticks of play left before it starts, unset when the arena never shrinks
+    countdown: Option<u64>,
+    // Ticks between two blocks and ticks left before the next one
+    drop_period: u64,
+    next_drop_in: u64,
+    // Cells still to be filled, the next one last
+    pending: Vec<map::Cell>,
+    started: bool,
+}
+
+/// A block filled the cell, whatever was in it is routed to
+/// `crush_under_blocks` by the collision dispatcher.
+#[derive(Event)]
+pub struct BlockDropped {
+    pub cell: map::Cell,
+}
+
+impl SuddenDeath {
+    pub fn started(&self) -> bool {
+        self.started
+    }
+
+    /// Ticks of play left before sudden death, if it is still to come.
+    pub fn remaining(&self) -> Option<u64> {
+        self.countdown.filter(|_| !self.started)
+    }
+}
+
+fn reset_sudden_death(
+    mut sudden_death: ResMut<SuddenDeath>,
+    config: Res<GameConfig>,
+    map_state: Res<map::MapState>,
+) {
+    let after = config.round.sudden_death_after;
+    let drop_period = config.ticks(config.round.block_drop_period).max(1);
+
+    let mut pending = map_state.scheme.spiral();
+    pending.reverse();
+
+    *sudden_death = SuddenDeath {
+        countdown: (after > 0.0).then(|| config.ticks(after).max(1)),
+        drop_period,
+        next_drop_in: drop_period,
+        pending,
+        started: false,
+    };
+}
+
+pub fn drop_blocks(
+    mut commands: Commands,
+    mut sudden_death: ResMut<SuddenDeath>,
+    mut map_state: ResMut<map::MapState>,
+    mut dropped_events: EventWriter<BlockDropped>,
+    config: Res<GameConfig>,
+) {
+    let sudden_death = &mut *sudden_death;
+
+    let Some(countdown) = sudden_death.countdown.as_mut() else {
+        return;
+    };
+
+    if !sudden_death.started {
+        *countdown -= 1;
+        if *countdown > 0 {
+            return;
+        }
+
+        info!("Sudden death!");
+        sudden_death.started = true;
+    }
+
+    sudden_death.next_drop_in -= 1;
+    if sudden_death.next_drop_in > 0 {
+        return;
+    }
+    sudden_death.next_drop_in = sudden_death.drop_period;
+
+    // Cells that are blocks already do not take a turn
+    let next = std::iter::from_fn(|| sudden_death.pending.pop())
+        .find(|cell| map_state.tile(*cell) != map::legend::BLOCK);
+
+    let Some(cell) = next else {
+        return;
+    };
+
+    map_state.set_tile(cell, map::legend::BLOCK);
+    block::spawn_block(&mut commands, &config, &map_state.scheme, cell);
+    dropped_events.send(BlockDropped { cell });
+}
+
+/// Crushes what a dropped block landed on. A crushed bomb does not go off,
+/// its owner gets it back.
+#[allow(clippy::too_many_arguments)]
+pub fn crush_under_blocks(
+    mut commands: Commands,
+    mut player_hits: EventReader<collision::BlockHitPlayer>,
+    mut bomb_hits: EventReader<collision::BlockHitBomb>,
+    mut brick_hits: EventReader<collision::BlockHitBrick>,
+    mut powerup_hits: EventReader<collision::BlockHitPowerup>,
+    bombs: Query<&bomb::Bomb>,
+    mut planted_bombs: ResMut<bomb::PlantedBombs>,
+    mut crushed_events: EventWriter<bomb::BombCrushed>,
+) {
+    let crushed = player_hits
+        .read()
+        .map(|hit| hit.player)
+        .chain(brick_hits.read().map(|hit| hit.brick))
+        .chain(powerup_hits.read().map(|hit| hit.powerup));
+
+    for entity in crushed {
+        commands.entity(entity).despawn();
+    }
+
+    for hit in bomb_hits.read() {
+        let Ok(bomb) = bombs.get(hit.bomb) else {
+            continue;
+        };
+
+        planted_bombs.set.remove(&bomb.cell);
+        commands.entity(hit.bomb).despawn();
+        crushed_events.send(bomb::BombCrushed {
+            player_id: bomb.player_id,
+        });
+    }
+}
@@ -0,0 +1,3 @@
+mod abtestbed;
+
+pub use abtestbed::{gym, main};
@@ -1,5 +1,3 @@
-mod abtestbed;
-
 fn main() {
     abtestbed::main();
 }